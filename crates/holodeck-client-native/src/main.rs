@@ -6,10 +6,8 @@ pub(crate) mod deps {
     pub(crate) use holodeck_viewer;
 
     pub(crate) use holodeck_core::deps::{
-        bincode,
         futures,
         tokio,
-        tokio_tungstenite,
     };
     pub(crate) use log;
     pub(crate) use structopt;
@@ -37,8 +35,50 @@ pub(crate) struct Args {
     /// the host port on which to connect
     #[structopt(short, long, default_value = "7000")]
     pub(crate) port: u16,
-    /* #[structopt(long, default_value = "WebSocket")]
-     * pub(crate) transport: crate::deps::holodeck_net::protocol::Transport, */
+
+    /// connect with wss:// rather than ws://
+    #[structopt(long)]
+    pub(crate) tls: bool,
+
+    /// a PEM file of certificates to trust on top of the usual roots, for self-signed servers
+    #[structopt(long, requires = "tls")]
+    pub(crate) ca: Option<std::path::PathBuf>,
+
+    /// the bearer token to present to the server, which decides what the client may do
+    #[structopt(long, env = "HOLODECK_TOKEN", hide_env_values = true)]
+    pub(crate) token: Option<String>,
+
+    /// the name to go by, shown to the simulation and other operators
+    #[structopt(long)]
+    pub(crate) name: Option<String>,
+
+    /// the message format to ask the server for: bincode, msgpack, cbor or json
+    #[structopt(long, default_value = "bincode")]
+    pub(crate) codec: crate::deps::holodeck_net::message::MessageCodec,
+
+    /// how entity positions should be encoded: f32, bfloat16, fixed16 or fixed24
+    #[structopt(long)]
+    pub(crate) position_encoding: Option<crate::deps::holodeck_net::message::PositionEncoding>,
+
+    /// how the server should compress frames: deflate or zstd, defaults to the best supported
+    #[structopt(long)]
+    pub(crate) compression: Option<crate::deps::holodeck_net::message::Compression>,
+
+    /// ask the server not to compress frames
+    #[structopt(long)]
+    pub(crate) no_compression: bool,
+
+    /// how to connect: websocket, tcp or unix
+    #[structopt(long, default_value = "websocket")]
+    pub(crate) transport: crate::deps::holodeck_net::protocol::Transport,
+
+    /// the server's socket file for the unix transport
+    #[structopt(long)]
+    pub(crate) socket: Option<std::path::PathBuf>,
+
+    /// ask for states over UDP, for fast moving simulations on a LAN
+    #[structopt(long)]
+    pub(crate) udp: bool,
 }
 
 
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     mem,
     sync::{
         Arc,
@@ -11,11 +12,21 @@ use std::{
 
 use crate::deps::{
     futures::SinkExt,
-    holodeck_core::messages::ToWebSocketMessage,
+    holodeck_core::{
+        Error,
+        Result,
+    },
     holodeck_net::message::{
-        SimulationState,
-        SpawnRequest,
+        Codec,
+        Compression,
+        Features,
+        Hello,
+        Message,
+        MessageCodec,
+        PositionCodec,
+        PositionEncoding,
         WebSocketMessage,
+        Welcome,
     },
 };
 
@@ -31,16 +42,23 @@ use crate::deps::{
 
 use crate::deps::tokio::{
     io::AsyncReadExt,
-    net::TcpStream,
     stream::StreamExt,
+    sync::Notify,
 };
 
 use crate::deps::{
     holodeck_macros::holodeck,
-    holodeck_net::protocol::Transport,
-    tokio_tungstenite::{
-        connect_async,
-        WebSocketStream,
+    holodeck_net::{
+        protocol::Transport,
+        tls::{
+            self,
+            TlsConnector,
+        },
+        transport::{
+            self,
+            Frames,
+        },
+        udp::SnapshotReceiver,
     },
     tracing::{
         info_span,
@@ -51,26 +69,28 @@ use crate::deps::{
 
 #[derive(Clone)]
 pub struct BackendChannelWrapper {
-    tx: Arc<Mutex<Vec<SpawnRequest>>>,
-    rx: Arc<Mutex<Option<Box<SimulationState>>>>,
+    tx:     Arc<Mutex<Vec<Message>>>,
+    rx:     Arc<Mutex<VecDeque<Message>>>,
+    /// woken whenever the viewer queues something to send
+    queued: Arc<Notify>,
 }
 
 
 impl BackendChannel for BackendChannelWrapper {
-    type Rx = Box<SimulationState>;
-    type Tx = SpawnRequest;
+    type Rx = Message;
+    type Tx = Message;
 
     fn send(
         &self,
         value: Self::Tx,
     ) {
-        let mut tx = self.tx.lock().expect("could not lock tx");
-        tx.push(value);
+        self.tx.lock().expect("could not lock tx").push(value);
+        self.queued.notify();
     }
 
     fn recv(&self) -> Option<Self::Rx> {
         let mut rx = self.rx.lock().expect("could not lock rx");
-        rx.take()
+        rx.pop_front()
     }
 }
 
@@ -97,11 +117,29 @@ fn init_logging(level: Level) {
 }
 
 
+/// What the client asks the server for when connecting.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    pub codec:             MessageCodec,
+    pub position_encoding: PositionEncoding,
+    pub compression:       Option<Compression>,
+    /// the certificates trusted for `wss://` urls
+    pub tls:               TlsConnector,
+    /// sent as a bearer token
+    pub token:             Option<String>,
+    /// the name to go by
+    pub name:              Option<String>,
+    /// the host to ask for states over UDP from, `None` to get them over the connection
+    pub udp:               Option<String>,
+}
+
+
 pub struct SimulationWebSocketClient(JoinHandle<()>);
 
 impl SimulationWebSocketClient {
     pub fn spawn<S>(
         url: S,
+        options: ConnectOptions,
         frontend: BackendChannelWrapper,
     ) -> Self
     where
@@ -116,7 +154,7 @@ impl SimulationWebSocketClient {
                 .build()
                 .unwrap();
 
-            let fut = Self::run_task(url, frontend).instrument(info_span!("net-worker"));
+            let fut = Self::run_task(url, options, frontend).instrument(info_span!("net-worker"));
 
             rt.block_on(fut);
         });
@@ -126,36 +164,131 @@ impl SimulationWebSocketClient {
 
     async fn run_task(
         endpoint: String,
+        options: ConnectOptions,
         frontend: BackendChannelWrapper,
     ) {
-        use crate::deps::tokio_tungstenite::tungstenite::Error;
-
         crate::deps::tokio::spawn(Self::notify_running());
-        let mut socket = Self::must_connect(&endpoint).await;
+        // websockets answer pings by themselves, the framed transports leave it to us
+        let framed = Transport::from_url(&endpoint) != Some(Transport::WebSocket);
+        let (mut socket, mut codec, welcome) = Self::must_connect(&endpoint, &options).await;
+        let mut udp = Self::receive_udp(&welcome, &options).await;
+        let (mut positions, mut compression) = Self::welcomed(welcome, &frontend);
+        // the tick of the newest state or delta handed to the viewer
+        let mut tick = None;
 
         loop {
-            match socket.next().await {
-                Some(Ok(message)) => Self::handle_message(message, &frontend).await,
-                Some(Err(Error::ConnectionClosed))
-                | Some(Err(Error::Protocol(_)))
-                | Some(Err(Error::Io(_)))
-                | None => {
-                    socket = Self::must_connect(&endpoint).await;
+            let received = crate::deps::tokio::select! {
+                received = socket.next() => received,
+                snapshot = Self::next_snapshot(&mut udp, codec) => Some(Ok(snapshot)),
+                // commands go out right away, not only once the server next sends something
+                _ = frontend.queued.notified() => {
+                    Self::forward(&mut socket, codec, &frontend).await;
+                    continue;
+                }
+            };
+            match received {
+                Some(Ok(WebSocketMessage::Ping(payload))) if framed => {
+                    let _result = socket.send(WebSocketMessage::Pong(payload)).await;
+                }
+                Some(Ok(message)) => {
+                    Self::handle_message(message, codec, &positions, compression, &mut tick, &frontend).await
+                }
+                lost => {
+                    // whatever went wrong, the server is not getting anything more out of this
+                    // connection
+                    if let Some(Err(err)) = lost {
+                        warn!("connection lost, reconnecting: {}", err);
+                    }
+                    let (reconnected, accepted, welcome) = Self::must_connect(&endpoint, &options).await;
+                    socket = reconnected;
+                    codec = accepted;
+                    udp = Self::receive_udp(&welcome, &options).await;
+                    let (negotiated, compressed) = Self::welcomed(welcome, &frontend);
+                    positions = negotiated;
+                    compression = compressed;
+                    tick = None;
                 }
-                unhandled => panic!("{:?}", unhandled),
             };
 
-            // forward messages received from the backend
-            // to the connection
-            let mut rx = frontend.tx.lock().expect("data potato");
+            Self::forward(&mut socket, codec, &frontend).await;
+        }
+    }
 
-            let messages = mem::take(&mut *rx);
-            'forward: for data in messages {
-                if let Ok(ws_msg) = data.to_message() {
-                    let _result = socket.send(ws_msg).await;
-                } else {
-                    break 'forward;
+    /// Send the messages the viewer queued to the connection. The queue is not kept locked while
+    /// sending, the viewer never waits on the connection.
+    async fn forward(
+        socket: &mut Frames,
+        codec: MessageCodec,
+        frontend: &BackendChannelWrapper,
+    ) {
+        let messages = mem::take(&mut *frontend.tx.lock().expect("could not lock tx"));
+        'forward: for data in messages {
+            if let Ok(frame) = codec.encode(&data) {
+                let _result = socket.send(frame.into()).await;
+            } else {
+                break 'forward;
+            }
+        }
+    }
+
+    /// Hand the server's welcome to the viewer so it can lay out the world, anything queued from
+    /// a previous connection is stale. Returns how the server will encode frames.
+    fn welcomed(
+        welcome: Welcome,
+        frontend: &BackendChannelWrapper,
+    ) -> (PositionCodec, Option<Compression>) {
+        let negotiated = (welcome.position_codec(), welcome.compression);
+        let mut tx = frontend.rx.lock().expect("could not lock message queue");
+        tx.clear();
+        tx.push_back(Message::Welcome(welcome));
+        negotiated
+    }
+
+    /// Register for the states the server sends over UDP, if it welcomed us with a session. The
+    /// states come over the connection otherwise.
+    async fn receive_udp(
+        welcome: &Welcome,
+        options: &ConnectOptions,
+    ) -> Option<SnapshotReceiver> {
+        let host = options.udp.as_deref()?;
+        let session = match welcome.udp {
+            Some(session) => session,
+            None => {
+                warn!("the server does not send states over udp, getting them over the connection");
+                return None;
+            }
+        };
+
+        match SnapshotReceiver::connect(host, session).await {
+            Ok(receiver) => Some(receiver),
+            Err(err) => {
+                warn!("could not receive states over udp from {}: {}", host, err);
+                None
+            }
+        }
+    }
+
+    /// The next state the server sent over UDP as the frame it would have been sent as over the
+    /// connection, never ready without a UDP session.
+    async fn next_snapshot(
+        udp: &mut Option<SnapshotReceiver>,
+        codec: MessageCodec,
+    ) -> WebSocketMessage {
+        let receiver = match udp {
+            Some(receiver) => receiver,
+            None => return crate::deps::futures::future::pending().await,
+        };
+
+        loop {
+            match receiver.recv().await {
+                Ok(snapshot) if codec.is_text() => {
+                    match String::from_utf8(snapshot) {
+                        Ok(text) => return WebSocketMessage::Text(text),
+                        Err(err) => error!("bad udp frame: {}", err),
+                    }
                 }
+                Ok(snapshot) => return WebSocketMessage::Binary(snapshot),
+                Err(err) => warn!("could not receive a state over udp: {}", err),
             }
         }
     }
@@ -169,65 +302,225 @@ impl SimulationWebSocketClient {
         }
     }
 
-    async fn must_connect(url: &str) -> WebSocketStream<TcpStream> {
+    async fn must_connect(
+        url: &str,
+        options: &ConnectOptions,
+    ) -> (Frames, MessageCodec, Welcome) {
+        let requested = options.codec;
+
         'connect: loop {
-            match connect_async(url).await {
-                Ok((socket, _)) => {
-                    info!("established connection to {}", url);
-                    return socket;
+            let connected = transport::connect(url, requested, options.token.as_deref(), &options.tls).await;
+            let err = match connected {
+                Ok((mut socket, codec)) => {
+                    if codec != requested {
+                        warn!("server does not speak {:?}, using {:?}", requested, codec);
+                    }
+
+                    info!("established connection to {}; codec={:?}", url, codec);
+                    match Self::handshake(&mut socket, codec, options).await {
+                        Ok(welcome) => {
+                            info!(
+                                "handshake complete: client={}; features={:?}; positions={:?}; \
+                                 compression={:?}; udp={}",
+                                welcome.client,
+                                welcome.features,
+                                welcome.position_encoding,
+                                welcome.compression,
+                                welcome.udp.is_some()
+                            );
+                            return (socket, codec, welcome);
+                        }
+                        Err(err @ Error::Handshake { .. }) => {
+                            // retrying would only run into the same mismatch again
+                            error!("incompatible server at {}: {}", url, err);
+                            std::process::exit(1);
+                        }
+                        Err(err) => err,
+                    }
                 }
-                Err(err) => {
-                    log::warn!("connection failed, retrying in 3s: err={}", err);
-                    crate::deps::tokio::time::delay_for(Duration::from_secs(3)).await;
+                Err(err @ Error::BadValue { .. }) => {
+                    error!("cannot connect to {}: {}", url, err);
+                    std::process::exit(1);
                 }
-            }
+                Err(err) => err,
+            };
+
+            log::warn!("connection failed, retrying in 3s: err={}", err);
+            crate::deps::tokio::time::delay_for(Duration::from_secs(3)).await;
+        }
+    }
+
+    /// Introduce ourselves to the server and wait for it to welcome us.
+    async fn handshake(
+        socket: &mut Frames,
+        codec: MessageCodec,
+        options: &ConnectOptions,
+    ) -> Result<Welcome> {
+        let mut requested = Features::supported();
+        if options.udp.is_some() {
+            requested |= Features::UDP;
+        }
+        let hello = Hello::new(requested)
+            .with_position_encoding(options.position_encoding)
+            .with_compression(options.compression)
+            .with_name(options.name.as_ref());
+        socket.send(codec.encode(&Message::Hello(hello))?.into()).await?;
+
+        loop {
+            let frame = match socket.next().await {
+                Some(Ok(WebSocketMessage::Binary(b))) => b,
+                Some(Ok(WebSocketMessage::Text(text))) => text.into_bytes(),
+                Some(Ok(WebSocketMessage::Ping(_))) | Some(Ok(WebSocketMessage::Pong(_))) => continue,
+                Some(Ok(other)) => {
+                    return Err(Error::Handshake {
+                        reason: format!("expected a welcome, got: {:?}", other).into(),
+                    })
+                }
+                Some(Err(err)) => return Err(err.into()),
+                None => {
+                    return Err(Error::Handshake {
+                        reason: "connection closed during handshake".into(),
+                    })
+                }
+            };
+
+            return match codec.decode::<Message>(&frame)? {
+                Message::Welcome(welcome) => {
+                    welcome.accept(requested)?;
+                    Ok(welcome)
+                }
+                Message::Reject(reject) => Err(reject.into()),
+                other => {
+                    Err(Error::Handshake {
+                        reason: format!("expected a welcome, got: {:?}", other).into(),
+                    })
+                }
+            };
         }
     }
 
     async fn handle_message(
         message: WebSocketMessage,
+        codec: MessageCodec,
+        positions: &PositionCodec,
+        compression: Option<Compression>,
+        tick: &mut Option<u64>,
         frontend: &BackendChannelWrapper,
     ) {
-        match message {
-            WebSocketMessage::Binary(b) => {
-                let message_size = b.len();
-                if let Some(state) =
-                    crate::deps::bincode::deserialize_from::<_, Box<SimulationState>>(&b[..]).ok()
-                {
-                    // send state to the backend
-                    debug!(
-                        "received simulation update message: tick={:?}; bytes={}",
-                        state.tick, message_size
-                    );
-                    let mut tx = frontend.rx.lock().expect("could not lock message queue");
-                    tx.replace(state);
-                } else {
-                    error!("bad message: len={:?}", b.len());
+        let b = match message {
+            WebSocketMessage::Binary(b) if compression.is_some() => {
+                match Compression::unpack(&b) {
+                    Ok(unpacked) => {
+                        debug!("unpacked frame: bytes={}; unpacked={}", b.len(), unpacked.len());
+                        unpacked.into_owned()
+                    }
+                    Err(err) => {
+                        error!("bad frame: len={:?}; error={}", b.len(), err);
+                        return;
+                    }
                 }
             }
-            WebSocketMessage::Text(_) | WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_) => {
-                error!("unexpected websocket message type");
-            }
+            WebSocketMessage::Binary(b) => b,
+            WebSocketMessage::Text(text) => text.into_bytes(),
+            // the server's heartbeats, tungstenite answers the pings by itself
+            WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_) => return,
             WebSocketMessage::Close(_) => {
                 warn!("connection closed!");
+                return;
             }
         };
+
+        let message_size = b.len();
+        match positions.scope(|| codec.decode::<Message>(&b[..])) {
+            Ok(Message::State(state)) => {
+                // states over UDP and over the connection overtake each other, an older one
+                // would undo what a newer one did
+                if let Some(newest) = tick.filter(|&newest| state.tick <= newest) {
+                    debug!("dropping state: tick={}; newest={}", state.tick, newest);
+                    return;
+                }
+                // send state to the backend
+                debug!(
+                    "received simulation update message: tick={:?}; bytes={}",
+                    state.tick, message_size
+                );
+                *tick = Some(state.tick);
+                let mut tx = frontend.rx.lock().expect("could not lock message queue");
+                // a full state supersedes any updates the viewer has not gotten to yet
+                tx.retain(|queued| !matches!(queued, Message::State(_) | Message::Delta(_)));
+                tx.push_back(Message::State(state));
+            }
+            Ok(Message::Delta(delta)) => {
+                debug!(
+                    "received simulation delta message: tick={:?}; base_tick={:?}; bytes={}",
+                    delta.tick, delta.base_tick, message_size
+                );
+                *tick = Some(delta.tick);
+                let mut tx = frontend.rx.lock().expect("could not lock message queue");
+                tx.push_back(Message::Delta(delta));
+            }
+            Ok(Message::CommandResult(result)) => {
+                debug!("received command result: {:?}", result);
+                let mut tx = frontend.rx.lock().expect("could not lock message queue");
+                tx.push_back(Message::CommandResult(result));
+            }
+            Ok(other) => warn!("unexpected message: {:?}", other),
+            Err(err) => error!("bad message: len={:?}; error={}", message_size, err),
+        }
     }
 }
 
 
 
 pub(crate) fn run(args: &crate::Args) {
-    let url = format!("ws://{}:{}", args.host, args.port);
+    let url = match args.transport {
+        Transport::WebSocket if args.tls => format!("wss://{}:{}", args.host, args.port),
+        Transport::WebSocket => format!("ws://{}:{}", args.host, args.port),
+        Transport::Tcp => format!("tcp://{}:{}", args.host, args.port),
+        Transport::Unix => {
+            let path = args.socket.clone().unwrap_or_else(transport::default_socket_path);
+            format!("unix://{}", path.display())
+        }
+    };
 
     init_logging(args.log);
 
+    let tls = match tls::connector(args.ca.as_deref()) {
+        Ok(tls) => tls,
+        Err(err) => {
+            error!("could not set up TLS: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     let backend_channel = BackendChannelWrapper {
-        tx: Arc::new(Mutex::new(vec![])),
-        rx: Arc::new(Mutex::new(None)),
+        tx:     Arc::new(Mutex::new(vec![])),
+        rx:     Arc::new(Mutex::new(VecDeque::new())),
+        queued: Arc::new(Notify::new()),
     };
 
-    let _handle = SimulationWebSocketClient::spawn(url, backend_channel.clone());
+    let options = ConnectOptions {
+        codec: args.codec,
+        position_encoding: args.position_encoding.unwrap_or_default(),
+        compression: if args.no_compression {
+            None
+        } else {
+            Some(args.compression.unwrap_or_else(Compression::preferred))
+        },
+        tls,
+        token: args.token.clone(),
+        name: args.name.clone(),
+        udp: if args.udp {
+            // the server's udp port is on the same host, the loopback for a socket file
+            match args.transport {
+                Transport::Unix => Some("localhost".to_string()),
+                _ => Some(args.host.clone()),
+            }
+        } else {
+            None
+        },
+    };
+    let _handle = SimulationWebSocketClient::spawn(url, options, backend_channel.clone());
 
     // start server
     info!("viewer up and running");
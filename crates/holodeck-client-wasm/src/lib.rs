@@ -1,10 +1,7 @@
 pub(crate) mod deps {
     pub(crate) use cfg_if;
     pub(crate) use console_error_panic_hook;
-    pub(crate) use holodeck_core::{
-        self,
-        deps::bincode,
-    };
+    pub(crate) use holodeck_core;
     pub(crate) use holodeck_viewer;
     pub(crate) use js_sys;
     pub(crate) use wasm_bindgen;
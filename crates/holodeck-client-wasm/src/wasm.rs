@@ -3,14 +3,20 @@ use std::{
         Cell,
         RefCell,
     },
+    collections::VecDeque,
     rc::Rc,
 };
 
 use crate::deps::{
-    bincode,
     holodeck_core::messages::{
-        SimulationState,
-        SpawnRequest,
+        Codec,
+        Compression,
+        Features,
+        Frame,
+        Hello,
+        Message,
+        MessageCodec,
+        PositionCodec,
     },
     holodeck_viewer::app::BackendChannel,
     js_sys,
@@ -21,6 +27,7 @@ use crate::deps::{
     web_sys::{
         ErrorEvent,
         MessageEvent,
+        WebSocket,
     },
 };
 
@@ -47,74 +54,157 @@ extern "C" {
 
 
 struct BackendChannelWrapper {
-    tx: Rc<RefCell<Vec<SpawnRequest>>>,
-    rx: Rc<Cell<Option<Box<SimulationState>>>>,
+    ws:       WebSocket,
+    codec:    Rc<Cell<MessageCodec>>,
+    welcomed: Rc<Cell<Option<PositionCodec>>>,
+    rx:       Rc<RefCell<VecDeque<Message>>>,
 }
 
 
 impl BackendChannel for BackendChannelWrapper {
-    type Rx = Box<SimulationState>;
-    type Tx = SpawnRequest;
+    type Rx = Message;
+    type Tx = Message;
 
+    /// Commands and the like are sent right away, the browser buffers them for us.
     fn send(
         &self,
         value: Self::Tx,
     ) {
-        self.tx.borrow_mut().push(value)
+        if self.welcomed.get().is_none() {
+            console_log!(
+                "dropping message, the server has not welcomed us yet: {:?}",
+                value
+            );
+            return;
+        }
+
+        let frame = match self.codec.get().encode(&value) {
+            Ok(frame) => frame,
+            Err(err) => {
+                console_log!("ERROR: could not serialize message: {:?}", err);
+                return;
+            }
+        };
+
+        let sent = match frame {
+            Frame::Binary(bytes) => self.ws.send_with_u8_array(&bytes[..]),
+            Frame::Text(text) => self.ws.send_with_str(&text),
+        };
+        if let Err(err) = sent {
+            console_log!("ERROR: could not send message: {:?}", err);
+        }
     }
 
     fn recv(&self) -> Option<Self::Rx> {
-        self.rx.take()
+        self.rx.borrow_mut().pop_front()
     }
 }
 
 
 fn start_websocket(
-    url: String
-) -> Result<Box<dyn BackendChannel<Tx = SpawnRequest, Rx = Box<SimulationState>>>, JsValue> {
-    // Connect to an echo server
-    let ws = crate::deps::web_sys::WebSocket::new(&url)?;
-
-    let backend_channel = BackendChannelWrapper {
-        tx: Rc::new(RefCell::new(vec![])),
-        rx: Rc::new(Cell::new(None)),
-    };
-    let frontend_tx = backend_channel.rx.clone();
+    url: String,
+    requested: MessageCodec,
+) -> Result<Box<dyn BackendChannel<Tx = Message, Rx = Message>>, JsValue> {
+    // Connect to an echo server, asking for the codec as the subprotocol
+    let ws = WebSocket::new_with_str(&url, requested.protocol())?;
 
     // For small binary messages, like CBOR, Arraybuffer is more efficient than Blob handling
     ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
     // create callback
     let counter = Rc::new(Cell::new(0usize));
+    // set once the server has welcomed us, nothing but the handshake is expected before that.
+    // holds the codec the server encodes entity positions with on this connection
+    let welcomed = Rc::new(Cell::new(None::<PositionCodec>));
+    // how the server compresses binary frames, they are not prefixed at all when `None`
+    let compression = Rc::new(Cell::new(None::<Compression>));
+    let requested = Features::supported();
+    // the codec the server agreed to, known once the socket is open
+    let codec = Rc::new(Cell::new(MessageCodec::default()));
+    let message_codec = codec.clone();
+
+    let backend_channel = BackendChannelWrapper {
+        ws:       ws.clone(),
+        codec:    codec.clone(),
+        welcomed: welcomed.clone(),
+        rx:       Rc::new(RefCell::new(VecDeque::new())),
+    };
+    let frontend_tx = backend_channel.rx.clone();
 
+    let cloned_ws = ws.clone();
     let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
         // Handle difference Text/Binary,...
-        if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+        let buf = if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
             console_log!("message event, received arraybuffer: {:?}", abuf);
-            let array = js_sys::Uint8Array::new(&abuf);
-            let len = array.byte_length() as usize;
-            let buf = array.to_vec();
-            counter.set(counter.get() + 1);
-            console_log!("Received message; bytes={}", len);
-
-            let current_msg = frontend_tx.take();
-            let msg = if let Some(_) = current_msg.as_ref() {
-                // skip message deserializing and enqueuing message if the current message has not been
-                // retrieved.
-                current_msg
+            let bytes = js_sys::Uint8Array::new(&abuf).to_vec();
+            if compression.get().is_none() {
+                bytes
             } else {
-                let sim_state = bincode::deserialize_from::<_, Box<SimulationState>>(&buf[..])
-                    .map_err(|err| console_log!("ERROR: {:?}", err))
-                    .ok();
-
-                sim_state
-            };
-
-            frontend_tx.set(msg);
-        // here you can for example use Serde Deserialize decode the message
-        // for demo purposes we switch back to Blob-type and send off another binary message
+                match Compression::unpack(&bytes) {
+                    Ok(unpacked) => unpacked.into_owned(),
+                    Err(err) => {
+                        console_log!("ERROR: bad frame: len={}; error={}", bytes.len(), err);
+                        return;
+                    }
+                }
+            }
+        } else if let Some(text) = e.data().as_string() {
+            text.into_bytes()
         } else {
             console_log!("message event, received Unknown: {:?}", e.data());
+            return;
+        };
+
+        counter.set(counter.get() + 1);
+        console_log!("Received message; bytes={}", buf.len());
+
+        let codec = message_codec.get();
+        let decoded = match welcomed.get() {
+            Some(positions) => positions.scope(|| codec.decode::<Message>(&buf[..])),
+            None => codec.decode::<Message>(&buf[..]),
+        };
+
+        match decoded {
+            Ok(Message::State(state)) if welcomed.get().is_some() => {
+                // a full state supersedes any updates the viewer has not gotten to yet
+                let mut queue = frontend_tx.borrow_mut();
+                queue.retain(|queued| !matches!(queued, Message::State(_) | Message::Delta(_)));
+                queue.push_back(Message::State(state));
+            }
+            Ok(Message::Delta(delta)) if welcomed.get().is_some() => {
+                frontend_tx.borrow_mut().push_back(Message::Delta(delta));
+            }
+            Ok(Message::CommandResult(result)) if welcomed.get().is_some() => {
+                frontend_tx.borrow_mut().push_back(Message::CommandResult(result));
+            }
+            Ok(Message::Welcome(welcome)) => {
+                match welcome.accept(requested) {
+                    Ok(features) => {
+                        let positions = welcome.position_codec();
+                        console_log!(
+                            "handshake complete; client={}; features={:?}; positions={:?}",
+                            welcome.client,
+                            features,
+                            positions.encoding
+                        );
+                        welcomed.set(Some(positions));
+                        compression.set(welcome.compression);
+                        // the viewer lays out the world from the description
+                        let mut queue = frontend_tx.borrow_mut();
+                        queue.clear();
+                        queue.push_back(Message::Welcome(welcome));
+                    }
+                    Err(err) => {
+                        console_log!("ERROR: incompatible server: {}", err);
+                        let _ = cloned_ws.close();
+                    }
+                }
+            }
+            Ok(Message::Reject(reject)) => {
+                console_log!("ERROR: server rejected the connection: {}", reject.reason);
+            }
+            Ok(other) => console_log!("unexpected message: {:?}", other),
+            Err(err) => console_log!("ERROR: {:?}", err),
         }
     }) as Box<dyn FnMut(MessageEvent)>);
     // set message event handler on WebSocket
@@ -128,18 +218,25 @@ fn start_websocket(
     ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
     onerror_callback.forget();
 
-    //    let cloned_ws = ws.clone();
+    let cloned_ws = ws.clone();
     let onopen_callback = Closure::wrap(Box::new(move |_| {
-        console_log!("socket opened");
-        // match cloned_ws.send_with_str("ping") {
-        //     Ok(_) => console_log!("message successfully sent"),
-        //     Err(err) => console_log!("error sending message: {:?}", err),
-        // }
-        // // send off binary message
-        // match cloned_ws.send_with_u8_array(&vec![0, 1, 2, 3]) {
-        //     Ok(_) => console_log!("binary message successfully sent"),
-        //     Err(err) => console_log!("error sending message: {:?}", err),
-        // }
+        // servers which predate codec negotiation do not pick a subprotocol and speak bincode
+        let accepted = MessageCodec::from_protocol(cloned_ws.protocol()).unwrap_or_default();
+        console_log!("socket opened; codec={:?}", accepted);
+        codec.set(accepted);
+
+        let hello = accepted
+            .encode(&Message::Hello(Hello::new(requested)))
+            .unwrap_or_else(|err| panic!("could not serialize hello: {:?}", err));
+
+        let sent = match hello {
+            Frame::Binary(bytes) => cloned_ws.send_with_u8_array(&bytes[..]),
+            Frame::Text(text) => cloned_ws.send_with_str(&text),
+        };
+        match sent {
+            Ok(_) => console_log!("hello sent"),
+            Err(err) => console_log!("error sending hello: {:?}", err),
+        }
     }) as Box<dyn FnMut(JsValue)>);
     ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
     onopen_callback.forget();
@@ -147,15 +244,29 @@ fn start_websocket(
     Ok(Box::new(backend_channel))
 }
 
+/// `codec` optionally names the message codec to ask the server for (bincode, msgpack, cbor or
+/// json), bincode when left out. `token` is the bearer token to present to the server, browsers
+/// cannot set headers on a websocket so it goes in the query string.
 #[wasm_bindgen]
-pub fn run(url: JsValue) -> Result<(), JsValue> {
+pub fn run(
+    url: JsValue,
+    codec: JsValue,
+    token: JsValue,
+) -> Result<(), JsValue> {
     crate::deps::console_error_panic_hook::set_once();
-    let url = url
+    let mut url = url
         .as_string()
         .map(|url| format!("ws://{}:5999", url))
         .unwrap_or("ws://localhost:5999".to_string());
+    if let Some(token) = token.as_string() {
+        url = format!("{}/?token={}", url, js_sys::encode_uri_component(&token));
+    }
+    let codec = codec
+        .as_string()
+        .and_then(|codec| codec.parse().ok())
+        .unwrap_or_default();
 
-    let backend_channel = start_websocket(url).unwrap();
+    let backend_channel = start_websocket(url, codec).unwrap();
     holodeck_viewer::app::PlayerGameClient::run(Some(backend_channel));
     Ok(())
 }
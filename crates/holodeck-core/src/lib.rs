@@ -3,13 +3,17 @@ use std::borrow::Cow;
 pub mod deps {
     pub use anyhow;
     pub use bincode;
+    pub use flate2;
     #[cfg(not(target_arch = "wasm32"))]
     pub use futures;
     #[cfg(not(target_arch = "wasm32"))]
     pub use futures_util;
     #[cfg(feature = "bfloat16")]
     pub use half;
+    pub use rmp_serde;
     pub use serde;
+    pub use serde_cbor;
+    pub use serde_json;
     pub use thiserror;
     #[cfg(not(target_arch = "wasm32"))]
     pub use tokio;
@@ -17,6 +21,8 @@ pub mod deps {
     pub use tokio_tungstenite;
     #[cfg(not(target_arch = "wasm32"))]
     pub use tokio_tungstenite::tungstenite;
+    #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+    pub use zstd;
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -33,7 +39,7 @@ pub enum Error {
 
     #[error("an internal error occurred, \"{message}\": error={err}")]
     Internal {
-        err:     Box<dyn std::error::Error>,
+        err:     Box<dyn std::error::Error + Send + Sync>,
         message: Cow<'static, str>,
     },
     #[error("could not create an instance of `{to}` from the value `{value}` with type `{from}`")]
@@ -48,6 +54,21 @@ pub enum Error {
 
     #[error("bincode serialization error: {err}")]
     BincodeSerialize { err: crate::deps::bincode::Error },
+
+    #[error("{codec} serialization error: {message}")]
+    Codec {
+        codec:   &'static str,
+        message: Cow<'static, str>,
+    },
+
+    #[error("the protocol handshake failed: {reason}")]
+    Handshake { reason: Cow<'static, str> },
+
+    #[error("the client was disconnected: {reason}")]
+    Disconnected { reason: Cow<'static, str> },
+
+    #[error("a TLS operation failed: {reason}")]
+    Tls { reason: Cow<'static, str> },
 }
 
 
@@ -86,104 +107,444 @@ impl From<crate::deps::tungstenite::Error> for Error {
 }
 
 
-pub mod messages {
-    use crate::deps::serde;
-    #[cfg(not(target_arch = "wasm32"))]
-    pub use crate::deps::tungstenite::Message as WebSocketMessage;
+pub mod messages;
 
-    #[cfg(not(target_arch = "wasm32"))]
-    pub trait ToWebSocketMessage {
-        type Error: std::error::Error;
-        fn to_message(&self) -> std::result::Result<WebSocketMessage, Self::Error>;
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{
+        Bounds,
+        Codec,
+        Command,
+        CommandKind,
+        CommandResult,
+        Compression,
+        Entity,
+        Features,
+        Frame,
+        Hello,
+        IdRange,
+        Interest,
+        Message,
+        MessageCodec,
+        PositionCodec,
+        PositionEncoding,
+        Reject,
+        Reply,
+        SimulationState,
+        SpawnRequest,
+        Subscription,
+        TagSet,
+        Welcome,
+        WorldDescription,
+        MAX_NAME_LEN,
+        PROTOCOL_VERSION,
+    };
+    use rand::{
+        rngs::StdRng,
+        Rng,
+        SeedableRng,
+    };
+
+    /// frames tried per codec and position encoding
+    const ROUNDS: usize = 2_000;
+
+    const POSITION_ENCODINGS: [PositionEncoding; 4] = [
+        PositionEncoding::F32,
+        PositionEncoding::BFloat16,
+        PositionEncoding::Fixed16,
+        PositionEncoding::Fixed24,
+    ];
+
+
+    fn random_bytes(
+        rng: &mut StdRng,
+        max_len: usize,
+    ) -> Vec<u8> {
+        let len = rng.gen_range(0, max_len + 1);
+        (0..len).map(|_| rng.gen()).collect()
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    /// Blanket implementation for all local types that implement serialize
-    /// to shove them into a websocket message.
-    impl<T> ToWebSocketMessage for T
-    where
-        T: serde::Serialize,
-    {
-        type Error = crate::Error;
-
-        fn to_message(&self) -> crate::Result<WebSocketMessage> {
-            let message = bincode::serialize::<T>(self).map(|bytes| WebSocketMessage::Binary(bytes))?;
-            Ok(message)
+
+    fn sample_state() -> Message {
+        let mut state = SimulationState::default();
+        state.tick = 42;
+        state.entities = (0..16)
+            .map(|id| {
+                let mut entity = Entity::new(id, 1, id as f32, -(id as f32), 0.5);
+                entity.set_yaw_pitch_roll(0.1, 0.2, 0.3);
+                entity
+            })
+            .collect();
+        state.entity_count = state.entities.len() as u64;
+        Message::State(state)
+    }
+
+
+    #[test]
+    fn decoding_random_frames_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0x0401_dec4);
+        let bounds = Bounds::square(1000.0);
+
+        for codec in MessageCodec::ALL.iter() {
+            for encoding in POSITION_ENCODINGS.iter() {
+                let positions = PositionCodec::new(*encoding, bounds);
+                for _ in 0..ROUNDS {
+                    let frame = random_bytes(&mut rng, 512);
+                    let _ = positions.scope(|| codec.decode::<Message>(&frame));
+                }
+            }
         }
     }
 
 
-    #[derive(Copy, Clone, Debug, PartialEq)]
-    pub enum Message {
-        Test,
+    #[test]
+    fn decoding_corrupted_frames_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0x0c02_2a9d);
+        let bounds = Bounds::square(1000.0);
+        let message = sample_state();
+
+        for codec in MessageCodec::ALL.iter() {
+            for encoding in POSITION_ENCODINGS.iter() {
+                if !Features::supported().contains(encoding.requires()) {
+                    continue;
+                }
+                let positions = PositionCodec::new(*encoding, bounds);
+                let frame = positions.scope(|| codec.encode(&message)).unwrap();
+                let valid = frame.as_bytes();
+
+                for _ in 0..ROUNDS {
+                    let mut corrupted = valid.to_vec();
+                    match rng.gen_range(0, 3) {
+                        0 => corrupted.truncate(rng.gen_range(0, valid.len())),
+                        1 => {
+                            for _ in 0..rng.gen_range(1, 8) {
+                                let at = rng.gen_range(0, corrupted.len());
+                                corrupted[at] = rng.gen();
+                            }
+                        }
+                        _ => {
+                            let at = rng.gen_range(0, corrupted.len());
+                            let garbage = random_bytes(&mut rng, 32);
+                            corrupted.splice(at..at, garbage);
+                        }
+                    }
+                    let _ = positions.scope(|| codec.decode::<Message>(&corrupted));
+                }
+            }
+        }
     }
 
 
-    #[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
-    pub struct SimulationState {
-        pub tick:         u64,
-        pub entity_count: u64,
-        pub entities:     Vec<Entity>,
+    #[test]
+    fn messages_round_trip_through_every_codec() {
+        let messages = vec![
+            Message::Hello(Hello::new(Features::supported()).with_name(Some("ops"))),
+            Message::Reject(Reject::new("no room")),
+            sample_state(),
+            Message::Command(Command {
+                id:   3,
+                kind: CommandKind::Spawn(SpawnRequest::at(1.0, -2.0, 0.5)),
+            }),
+            Message::CommandResult(CommandResult::ok(3, Reply::Spawned { id: 17 })),
+            Message::CommandResult(CommandResult::err(4, "the simulation is full")),
+            Message::Interest(Some(Interest::new([1.5, -2.5, 0.0], 250.0))),
+            Message::Interest(None),
+            Message::Subscription(Some(Subscription {
+                tags: TagSet::Only(vec![1, 7]),
+                ids:  Some(vec![IdRange { first: 10, last: 20 }]),
+            })),
+        ];
+
+        for codec in MessageCodec::ALL.iter() {
+            for message in messages.iter() {
+                let frame = codec.encode(message).unwrap();
+                assert_eq!(matches!(frame, Frame::Text(_)), codec.is_text(), "{:?}", codec);
+
+                let decoded = codec.decode::<Message>(frame.as_bytes()).unwrap();
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", message), "{:?}", codec);
+            }
+        }
     }
 
 
-    #[derive(Copy, Clone, Debug, serde::Serialize, serde::Deserialize)]
-    pub struct SpawnRequest {
-        pub x: f32,
-        pub y: f32,
-        pub z: f32,
+    #[test]
+    fn subscriptions_with_overlong_lists_are_refused() {
+        let longest = Subscription::MAX_LIST_LEN;
+        let tags = |len: usize| (0..len as u16).collect::<Vec<_>>();
+        let ids = |len: usize| {
+            (0..len as u64)
+                .map(|id| IdRange { first: id, last: id })
+                .collect()
+        };
+        let subscriptions = |len: usize| {
+            vec![
+                Subscription {
+                    tags: TagSet::Only(tags(len)),
+                    ids:  None,
+                },
+                Subscription {
+                    tags: TagSet::Except(tags(len)),
+                    ids:  None,
+                },
+                Subscription {
+                    tags: TagSet::default(),
+                    ids:  Some(ids(len)),
+                },
+            ]
+        };
+
+        for codec in MessageCodec::ALL.iter() {
+            for subscription in subscriptions(longest) {
+                let message = Message::Subscription(Some(subscription));
+                let frame = codec.encode(&message).unwrap();
+                let decoded = codec.decode::<Message>(frame.as_bytes()).unwrap();
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", message), "{:?}", codec);
+            }
+            for subscription in subscriptions(longest + 1) {
+                let frame = codec.encode(&Message::Subscription(Some(subscription))).unwrap();
+                assert!(codec.decode::<Message>(frame.as_bytes()).is_err(), "{:?}", codec);
+            }
+        }
     }
 
 
-    #[derive(Copy, Clone, Debug, serde::Serialize, serde::Deserialize)]
-    pub struct Entity {
-        pub id:  u64,
-        pub tag: u16,
-        #[cfg_attr(
-            all(feature = "bfloat16", feature = "serde_f32_as_bfloat16"),
-            serde(with = "as_bfloat16")
-        )]
-        pub x:   f32,
-        #[cfg_attr(
-            all(feature = "bfloat16", feature = "serde_f32_as_bfloat16"),
-            serde(with = "as_bfloat16")
-        )]
-        pub y:   f32,
-        #[cfg_attr(
-            all(feature = "bfloat16", feature = "serde_f32_as_bfloat16"),
-            serde(with = "as_bfloat16")
-        )]
-        pub z:   f32,
+    #[test]
+    fn codecs_are_picked_by_subprotocol_and_name() {
+        for codec in MessageCodec::ALL.iter().copied() {
+            assert_eq!(MessageCodec::from_protocol(codec.protocol()), Some(codec));
+        }
+        assert_eq!(
+            MessageCodec::select("chat, holodeck.cbor, holodeck.json"),
+            Some(MessageCodec::Cbor)
+        );
+        assert_eq!(MessageCodec::select("chat"), None);
+
+        assert_eq!(
+            "MsgPack".parse::<MessageCodec>().unwrap(),
+            MessageCodec::MessagePack
+        );
+        assert_eq!("json".parse::<MessageCodec>().unwrap(), MessageCodec::Json);
+        assert!("yaml".parse::<MessageCodec>().is_err());
+        assert_eq!(MessageCodec::default(), MessageCodec::Bincode);
     }
 
-    #[cfg(feature = "bfloat16")]
-    mod as_bfloat16 {
-        use crate::deps::{
-            half::bf16,
-            serde::{
-                Deserialize,
-                Deserializer,
-                Serializer,
-            },
-        };
 
-        pub fn serialize<S>(
-            value: &f32,
-            serializer: S,
-        ) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
+    /// Encode an entity at `position` and decode it again, with the positions in `encoding`.
+    fn round_trip(
+        codec: MessageCodec,
+        positions: PositionCodec,
+        position: [f32; 3],
+    ) -> [f32; 3] {
+        let [x, y, z] = position;
+        let message = Message::State(SimulationState {
+            entities: vec![Entity::new(7, 1, x, y, z)],
+            ..SimulationState::default()
+        });
+        let frame = positions.scope(|| codec.encode(&message)).unwrap();
+        match positions
+            .scope(|| codec.decode::<Message>(frame.as_bytes()))
+            .unwrap()
         {
-            let v = bf16::from_f32(*value);
-            serializer.serialize_u16(v.to_bits())
+            Message::State(state) => state.entities[0].position(),
+            other => panic!("decoded {:?}", other),
         }
+    }
 
-        pub fn deserialize<'de, D>(deserializer: D) -> Result<f32, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            let value: bf16 = bf16::deserialize(deserializer)?;
-            Ok(value.to_f32())
+
+    #[test]
+    fn positions_round_trip() {
+        let bounds = Bounds {
+            min: [-500.0, -500.0, 0.0],
+            max: [500.0, 500.0, 100.0],
+        };
+        // along each axis, the furthest a coordinate may be off after a round trip
+        let tolerances = |encoding| {
+            match encoding {
+                PositionEncoding::F32 => 0.0,
+                PositionEncoding::BFloat16 => 2.0,
+                PositionEncoding::Fixed16 => 1000.0 / 0xFFFE as f32,
+                PositionEncoding::Fixed24 => 1000.0 / 0xFF_FFFE as f32,
+            }
+        };
+        let inside = [
+            [0.0, 0.0, 0.0],
+            [-500.0, 500.0, 100.0],
+            [123.456, -0.001, 0.75],
+            [499.9, -499.9, 99.9],
+        ];
+
+        for codec in MessageCodec::ALL.iter().copied() {
+            for encoding in POSITION_ENCODINGS.iter().copied() {
+                if !Features::supported().contains(encoding.requires()) {
+                    continue;
+                }
+                let positions = PositionCodec::new(encoding, bounds);
+                for position in inside.iter().copied() {
+                    let decoded = round_trip(codec, positions, position);
+                    for axis in 0..3 {
+                        assert!(
+                            (decoded[axis] - position[axis]).abs() <= tolerances(encoding),
+                            "{:?} {:?}: {:?} came back as {:?}",
+                            codec,
+                            encoding,
+                            position,
+                            decoded
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn fixed_point_positions_out_of_bounds_are_exact() {
+        let flat = Bounds::square(1000.0);
+        let outside = [
+            [501.0, 0.0, 0.0],
+            [-1e6, 1e6, 0.0],
+            [0.0, -500.5, 0.0],
+            [f32::MAX, f32::MIN, 0.0],
+        ];
+        // the bounds have no height, which leaves nothing to quantize heights over
+        let heights = [[0.0, 0.0, 0.75], [0.0, 0.0, -3.0], [12.0, 34.0, 1e-3]];
+
+        for codec in MessageCodec::ALL.iter().copied() {
+            for encoding in [PositionEncoding::Fixed16, PositionEncoding::Fixed24]
+                .iter()
+                .copied()
+            {
+                let positions = PositionCodec::new(encoding, flat);
+                for position in outside.iter().copied() {
+                    let decoded = round_trip(codec, positions, position);
+                    assert_eq!(decoded, position, "{:?} {:?}", codec, encoding);
+                }
+                for position in heights.iter().copied() {
+                    let decoded = round_trip(codec, positions, position);
+                    assert_eq!(decoded[2], position[2], "{:?} {:?}", codec, encoding);
+                }
+            }
         }
     }
+
+
+    #[test]
+    fn unpacking_random_frames_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0x02a9_4e55);
+
+        for _ in 0..ROUNDS * 4 {
+            let mut frame = random_bytes(&mut rng, 512);
+            // mostly frames claiming to be compressed, which actually exercises the decoders
+            if let Some(tag) = frame.first_mut() {
+                *tag = rng.gen_range(0, 4);
+            }
+            let _ = Compression::unpack(&frame);
+        }
+    }
+
+
+    #[test]
+    fn frames_are_only_compressed_when_it_pays_off() {
+        let mut rng = StdRng::seed_from_u64(0x9ac4_ed00);
+        let repetitive = Frame::Binary(b"holodeck ".repeat(512));
+        let noise = Frame::Binary(random_bytes(&mut rng, 4096));
+        let supported = [Compression::Deflate, Compression::Zstd];
+
+        for compression in supported.iter().copied().filter(|c| c.is_supported()) {
+            let packed = compression.pack(repetitive.clone(), 1024).unwrap();
+            assert_ne!(packed.as_bytes()[0], 0, "{:?}", compression);
+            assert!(packed.len() < repetitive.len());
+            assert_eq!(
+                Compression::unpack(packed.as_bytes()).unwrap(),
+                repetitive.as_bytes()
+            );
+
+            // too short to bother, and not any shorter compressed
+            for frame in [Frame::Binary(b"short".to_vec()), noise.clone()].iter() {
+                let packed = compression.pack(frame.clone(), 1024).unwrap();
+                assert_eq!(packed.as_bytes()[0], 0, "{:?}", compression);
+                assert_eq!(Compression::unpack(packed.as_bytes()).unwrap(), frame.as_bytes());
+            }
+
+            let text = Frame::Text("{}".to_string());
+            assert_eq!(compression.pack(text.clone(), 0).unwrap(), text);
+        }
+        assert_eq!("ZSTD".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+
+
+    #[test]
+    fn features_are_negotiated_down_to_what_both_peers_support() {
+        let server = Features::DELTAS | Features::COMPRESSION;
+        let hello = Hello::new(Features::DELTAS | Features::BFLOAT16 | Features::COMPRESSION);
+        assert_eq!(hello.negotiate(server).unwrap(), server);
+        assert_eq!(hello.negotiate(Features::DELTAS).unwrap(), Features::DELTAS);
+
+        // nothing to compress with
+        let uncompressed = Hello {
+            compression: None,
+            ..hello.clone()
+        };
+        assert_eq!(uncompressed.negotiate(server).unwrap(), Features::DELTAS);
+
+        let outdated = Hello {
+            version: PROTOCOL_VERSION - 1,
+            ..hello.clone()
+        };
+        assert!(outdated.negotiate(server).is_err());
+
+        let features = hello.negotiate(server).unwrap();
+        let welcome = Welcome {
+            version: PROTOCOL_VERSION,
+            client: 1,
+            features,
+            position_encoding: hello.position_encoding(features),
+            compression: hello.compression(features),
+            world: WorldDescription::new(Bounds::square(100.0), 10),
+            udp: None,
+        };
+        assert_eq!(welcome.accept(hello.features).unwrap(), features);
+        // a client never accepts more than it asked for
+        assert!(welcome.accept(Features::DELTAS).is_err());
+        let unasked = Welcome {
+            features: Features::DELTAS,
+            compression: Some(Compression::Deflate),
+            ..welcome.clone()
+        };
+        assert!(unasked.accept(hello.features).is_err());
+        assert_eq!(
+            server.without(Features::COMPRESSION | Features::UDP),
+            Features::DELTAS
+        );
+    }
+
+
+    #[test]
+    fn unpacking_a_frame_past_the_size_limit_is_refused() {
+        let huge = vec![0u8; Compression::MAX_DECOMPRESSED_SIZE as usize + 1];
+        let packed = Compression::Deflate.compress(&huge).unwrap();
+
+        let mut frame = vec![1u8];
+        frame.extend_from_slice(&packed);
+        assert!(Compression::unpack(&frame).is_err());
+    }
+
+
+    #[test]
+    fn display_names_are_cleaned_up() {
+        let named = |name: &str| {
+            Hello::new(Features::supported())
+                .with_name(Some(name))
+                .display_name()
+        };
+
+        assert_eq!(named("  ops\u{1b}[31m lead\n"), Some("ops[31m lead".to_string()));
+        assert_eq!(named(" \t\r\n"), None);
+
+        let long = named(&"é".repeat(MAX_NAME_LEN)).unwrap();
+        assert!(long.len() <= MAX_NAME_LEN);
+        assert_eq!(long.chars().count(), MAX_NAME_LEN / 2);
+    }
 }
@@ -0,0 +1,306 @@
+use crate::deps::{
+    serde::{
+        de::DeserializeOwned,
+        Serialize,
+    },
+    serde_cbor,
+    serde_json,
+};
+
+
+/// A serialized message, ready to be sent as a websocket frame.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+
+impl Frame {
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::Binary(bytes) => bytes.len(),
+            Frame::Text(text) => text.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Frame::Binary(bytes) => bytes.as_slice(),
+            Frame::Text(text) => text.as_bytes(),
+        }
+    }
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<Frame> for super::WebSocketMessage {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Binary(bytes) => super::WebSocketMessage::Binary(bytes),
+            Frame::Text(text) => super::WebSocketMessage::Text(text),
+        }
+    }
+}
+
+
+pub trait Codec {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize;
+
+    /// Decode the payload of a frame, the bytes of a text frame for text based codecs.
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned;
+}
+
+
+fn codec_error<E: std::fmt::Display>(codec: &'static str) -> impl FnOnce(E) -> crate::Error {
+    move |err| {
+        crate::Error::Codec {
+            codec,
+            message: err.to_string().into(),
+        }
+    }
+}
+
+
+/// The default, compact but only convenient to use from rust.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Bincode;
+
+
+impl Codec for Bincode {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize,
+    {
+        Ok(Frame::Binary(crate::deps::bincode::serialize(value)?))
+    }
+
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(crate::deps::bincode::deserialize(bytes)?)
+    }
+}
+
+
+/// MessagePack with structs as maps keyed by field name.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MessagePack;
+
+
+impl Codec for MessagePack {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize,
+    {
+        crate::deps::rmp_serde::to_vec_named(value)
+            .map(Frame::Binary)
+            .map_err(codec_error("msgpack"))
+    }
+
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        crate::deps::rmp_serde::from_slice(bytes).map_err(codec_error("msgpack"))
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Cbor;
+
+
+impl Codec for Cbor {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize,
+    {
+        serde_cbor::to_vec(value)
+            .map(Frame::Binary)
+            .map_err(codec_error("cbor"))
+    }
+
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        serde_cbor::from_slice(bytes).map_err(codec_error("cbor"))
+    }
+}
+
+
+/// JSON sent as text frames.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Json;
+
+
+impl Codec for Json {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize,
+    {
+        serde_json::to_string(value)
+            .map(Frame::Text)
+            .map_err(codec_error("json"))
+    }
+
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_slice(bytes).map_err(codec_error("json"))
+    }
+}
+
+
+/// One of the codecs above, chosen at runtime.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageCodec {
+    Bincode,
+    MessagePack,
+    Cbor,
+    Json,
+}
+
+
+impl MessageCodec {
+    /// Every codec, in no particular order.
+    pub const ALL: [MessageCodec; 4] = [
+        MessageCodec::Bincode,
+        MessageCodec::MessagePack,
+        MessageCodec::Cbor,
+        MessageCodec::Json,
+    ];
+
+    /// The websocket subprotocol which selects the codec.
+    pub fn protocol(self) -> &'static str {
+        match self {
+            MessageCodec::Bincode => "holodeck.bincode",
+            MessageCodec::MessagePack => "holodeck.msgpack",
+            MessageCodec::Cbor => "holodeck.cbor",
+            MessageCodec::Json => "holodeck.json",
+        }
+    }
+
+    /// Whether messages are encoded as text frames rather than binary ones.
+    pub fn is_text(self) -> bool {
+        matches!(self, MessageCodec::Json)
+    }
+
+    pub fn from_protocol<S: AsRef<str>>(protocol: S) -> Option<Self> {
+        let protocol = protocol.as_ref().trim();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.protocol() == protocol)
+    }
+
+    /// Pick the first of the comma separated subprotocols offered by a client which
+    /// names a codec.
+    pub fn select<S: AsRef<str>>(offered: S) -> Option<Self> {
+        offered.as_ref().split(',').filter_map(Self::from_protocol).next()
+    }
+}
+
+
+impl Default for MessageCodec {
+    /// bincode, which is what every frame was encoded with before codecs could be picked
+    fn default() -> Self {
+        MessageCodec::Bincode
+    }
+}
+
+
+impl std::str::FromStr for MessageCodec {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bincode" => Ok(Self::Bincode),
+            "msgpack" => Ok(Self::MessagePack),
+            "messagepack" => Ok(Self::MessagePack),
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => {
+                Err(crate::Error::BadValue {
+                    from:  "str".into(),
+                    to:    "MessageCodec".into(),
+                    value: s.to_string().into(),
+                })
+            }
+        }
+    }
+}
+
+
+impl Codec for MessageCodec {
+    fn encode<T>(
+        &self,
+        value: &T,
+    ) -> crate::Result<Frame>
+    where
+        T: Serialize,
+    {
+        match self {
+            MessageCodec::Bincode => Bincode.encode(value),
+            MessageCodec::MessagePack => MessagePack.encode(value),
+            MessageCodec::Cbor => Cbor.encode(value),
+            MessageCodec::Json => Json.encode(value),
+        }
+    }
+
+    fn decode<T>(
+        &self,
+        bytes: &[u8],
+    ) -> crate::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            MessageCodec::Bincode => Bincode.decode(bytes),
+            MessageCodec::MessagePack => MessagePack.decode(bytes),
+            MessageCodec::Cbor => Cbor.decode(bytes),
+            MessageCodec::Json => Json.decode(bytes),
+        }
+    }
+}
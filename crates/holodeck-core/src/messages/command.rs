@@ -0,0 +1,181 @@
+use crate::{
+    deps::serde,
+    messages::Kind,
+};
+
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SpawnRequest {
+    pub x:    f32,
+    pub y:    f32,
+    pub z:    f32,
+    /// `None` leaves the tag up to the simulation
+    pub tag:  Option<u16>,
+    pub kind: Option<Kind>,
+}
+
+
+impl SpawnRequest {
+    pub fn at(
+        x: f32,
+        y: f32,
+        z: f32,
+    ) -> Self {
+        SpawnRequest {
+            x,
+            y,
+            z,
+            tag: None,
+            kind: None,
+        }
+    }
+}
+
+
+/// Identifies a [`Command`] and its [`CommandResult`], chosen by the client and only unique
+/// per connection.
+pub type CommandId = u64;
+
+
+/// A request from a client to the simulation, the simulation answers every command with a
+/// [`CommandResult`] carrying the same id.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Command {
+    pub id:   CommandId,
+    pub kind: CommandKind,
+}
+
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum CommandKind {
+    Spawn(SpawnRequest),
+    Despawn {
+        id: u64,
+    },
+    /// put the entity at the position, in simulation coordinates
+    Move {
+        id:       u64,
+        position: [f32; 3],
+    },
+    Pause,
+    Resume,
+    /// advance a paused simulation by a number of ticks
+    Step {
+        ticks: u32,
+    },
+    SetTickRate {
+        hz: f64,
+    },
+    /// anything else, what the payload means is up to the simulation
+    Custom {
+        name:    String,
+        payload: Vec<u8>,
+    },
+}
+
+
+impl CommandKind {
+    /// The least a client must be allowed to send this command. Changing the entities is up
+    /// to operators, running the simulation itself is up to admins.
+    pub fn required_role(&self) -> Role {
+        match self {
+            CommandKind::Spawn(_)
+            | CommandKind::Despawn { .. }
+            | CommandKind::Move { .. }
+            | CommandKind::Custom { .. } => Role::Operator,
+            CommandKind::Pause
+            | CommandKind::Resume
+            | CommandKind::Step { .. }
+            | CommandKind::SetTickRate { .. } => Role::Admin,
+        }
+    }
+}
+
+
+/// What a client is allowed to do, each role may do everything the roles before it may.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Role {
+    /// watches the simulation, no commands
+    Viewer,
+    /// may spawn, move and despawn entities
+    Operator,
+    /// may also pause, step and reconfigure the simulation
+    Admin,
+}
+
+
+impl Role {
+    pub fn allows(
+        self,
+        kind: &CommandKind,
+    ) -> bool {
+        self >= kind.required_role()
+    }
+}
+
+
+impl std::str::FromStr for Role {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => {
+                Err(crate::Error::BadValue {
+                    from:  "str".into(),
+                    to:    "Role".into(),
+                    value: s.to_string().into(),
+                })
+            }
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CommandResult {
+    pub id:      CommandId,
+    pub outcome: std::result::Result<Reply, CommandError>,
+}
+
+
+impl CommandResult {
+    pub fn ok(
+        id: CommandId,
+        reply: Reply,
+    ) -> Self {
+        CommandResult {
+            id,
+            outcome: Ok(reply),
+        }
+    }
+
+    pub fn err<S: Into<String>>(
+        id: CommandId,
+        reason: S,
+    ) -> Self {
+        CommandResult {
+            id,
+            outcome: Err(CommandError {
+                reason: reason.into(),
+            }),
+        }
+    }
+}
+
+
+/// What a simulation answers a successful command with.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Reply {
+    Done,
+    Spawned { id: u64 },
+    Custom { payload: Vec<u8> },
+}
+
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CommandError {
+    pub reason: String,
+}
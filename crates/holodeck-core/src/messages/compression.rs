@@ -0,0 +1,203 @@
+use std::{
+    borrow::Cow,
+    io::{
+        Read,
+        Write,
+    },
+};
+
+#[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+use crate::deps::zstd;
+use crate::{
+    deps::flate2,
+    messages::Frame,
+};
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Compression {
+    Deflate,
+    /// not available in the browser
+    Zstd,
+}
+
+
+impl Compression {
+    /// Frames which decompress to more than this are refused rather than exhausting the
+    /// receiver's memory.
+    pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+    const UNCOMPRESSED: u8 = 0;
+
+    /// The algorithm this build would rather use.
+    pub fn preferred() -> Compression {
+        if Compression::Zstd.is_supported() {
+            Compression::Zstd
+        } else {
+            Compression::Deflate
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::Deflate => true,
+            Compression::Zstd => cfg!(all(feature = "zstd", not(target_arch = "wasm32"))),
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Compression::Deflate => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn error<E: std::fmt::Display>(self) -> impl FnOnce(E) -> crate::Error {
+        move |err| {
+            crate::Error::Codec {
+                codec:   self.name(),
+                message: err.to_string().into(),
+            }
+        }
+    }
+
+    pub fn compress(
+        self,
+        bytes: &[u8],
+    ) -> crate::Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(bytes).map_err(self.error())?;
+                encoder.finish().map_err(self.error())
+            }
+            #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+            Compression::Zstd => zstd::stream::encode_all(bytes, 3).map_err(self.error()),
+            #[cfg(not(all(feature = "zstd", not(target_arch = "wasm32"))))]
+            Compression::Zstd => Err(self.error()("built without support for zstd")),
+        }
+    }
+
+    pub fn decompress(
+        self,
+        bytes: &[u8],
+    ) -> crate::Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => self.read_limited(flate2::read::DeflateDecoder::new(bytes)),
+            #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+            Compression::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(bytes).map_err(self.error())?;
+                self.read_limited(decoder)
+            }
+            #[cfg(not(all(feature = "zstd", not(target_arch = "wasm32"))))]
+            Compression::Zstd => Err(self.error()("built without support for zstd")),
+        }
+    }
+
+    fn read_limited<R: Read>(
+        self,
+        decoder: R,
+    ) -> crate::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        decoder
+            .take(Self::MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(self.error())?;
+
+        if decompressed.len() as u64 > Self::MAX_DECOMPRESSED_SIZE {
+            return Err(self.error()(format!(
+                "frame decompresses to more than {} bytes",
+                Self::MAX_DECOMPRESSED_SIZE
+            )));
+        }
+        Ok(decompressed)
+    }
+
+    /// Prefix a binary frame with how it is compressed, compressing it if it is at least
+    /// `threshold` bytes long and compression actually makes it smaller.
+    pub fn pack(
+        self,
+        frame: Frame,
+        threshold: usize,
+    ) -> crate::Result<Frame> {
+        let bytes = match frame {
+            Frame::Binary(bytes) => bytes,
+            text @ Frame::Text(_) => return Ok(text),
+        };
+
+        if bytes.len() >= threshold {
+            let compressed = self.compress(&bytes)?;
+            if compressed.len() < bytes.len() {
+                let mut packed = Vec::with_capacity(compressed.len() + 1);
+                packed.push(self.tag());
+                packed.extend_from_slice(&compressed);
+                return Ok(Frame::Binary(packed));
+            }
+        }
+
+        let mut packed = Vec::with_capacity(bytes.len() + 1);
+        packed.push(Self::UNCOMPRESSED);
+        packed.extend_from_slice(&bytes);
+        Ok(Frame::Binary(packed))
+    }
+
+    /// The payload of a binary frame made by [`Compression::pack`].
+    pub fn unpack(bytes: &[u8]) -> crate::Result<Cow<'_, [u8]>> {
+        let (tag, payload) = match bytes.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(crate::Error::Codec {
+                    codec:   "compression",
+                    message: "empty frame".into(),
+                })
+            }
+        };
+
+        if *tag == Self::UNCOMPRESSED {
+            return Ok(Cow::Borrowed(payload));
+        }
+
+        match Compression::from_tag(*tag) {
+            Some(compression) => compression.decompress(payload).map(Cow::Owned),
+            None => {
+                Err(crate::Error::Codec {
+                    codec:   "compression",
+                    message: format!("unknown compression: {}", tag).into(),
+                })
+            }
+        }
+    }
+}
+
+
+impl std::str::FromStr for Compression {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "deflate" => Ok(Self::Deflate),
+            "zstd" => Ok(Self::Zstd),
+            _ => {
+                Err(crate::Error::BadValue {
+                    from:  "str".into(),
+                    to:    "Compression".into(),
+                    value: s.to_string().into(),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{
+    deps::serde,
+    messages::{
+        Command,
+        CommandError,
+        CommandResult,
+        Message,
+        Reply,
+        Role,
+    },
+};
+
+
+/// Identifies a client connection for as long as the server runs, the client's id as far
+/// as the simulation is concerned. Ids are handed out in the order clients connect and never
+/// reused, a client which reconnects gets a new one. What carries over is the name it asks
+/// for, see [`ServerEvent::Connected`].
+pub type ConnectionId = u64;
+
+
+/// Something a client sent, along with who sent it.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Envelope<T> {
+    pub client: ConnectionId,
+    pub body:   T,
+}
+
+
+impl Envelope<Command> {
+    /// Send the `outcome` of the command back to the client which sent it, under the id the
+    /// client chose.
+    pub fn reply(
+        &self,
+        outcome: std::result::Result<Reply, CommandError>,
+    ) -> Dispatch {
+        let result = CommandResult {
+            id: self.body.id,
+            outcome,
+        };
+        Dispatch::to(self.client, Message::CommandResult(result))
+    }
+}
+
+
+/// What the simulation sends the server, messages for every client or for some only.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Dispatch {
+    Broadcast(Message),
+    /// sent to the listed clients, any which are gone are skipped
+    To {
+        clients: Vec<ConnectionId>,
+        message: Message,
+    },
+    /// sent to every client but the listed ones
+    Except {
+        clients: Vec<ConnectionId>,
+        message: Message,
+    },
+}
+
+
+impl Dispatch {
+    pub fn to(
+        client: ConnectionId,
+        message: Message,
+    ) -> Self {
+        Dispatch::To {
+            clients: vec![client],
+            message,
+        }
+    }
+}
+
+
+impl From<Message> for Dispatch {
+    fn from(message: Message) -> Self {
+        Dispatch::Broadcast(message)
+    }
+}
+
+
+/// What the server passes on to the simulation, client commands along with clients coming
+/// and going.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ServerEvent {
+    /// a client completed the handshake
+    Connected {
+        connection: ConnectionId,
+        peer:       String,
+        /// what the client's token allows it to do
+        role:       Role,
+        /// what the client asked to be called, see
+        /// [`Hello::display_name`](super::Hello::display_name)
+        name:       Option<String>,
+    },
+    /// a client hung up, timed out or was dropped by the server, it is never heard from
+    /// again
+    Disconnected {
+        connection: ConnectionId,
+        reason:     String,
+    },
+    /// answered with a [`Message::CommandResult`] carrying the command's id, see
+    /// [`Envelope::reply`]
+    Command(Envelope<Command>),
+}
@@ -0,0 +1,336 @@
+use crate::{
+    deps::serde,
+    messages::{
+        Compression,
+        ConnectionId,
+        PositionCodec,
+        PositionEncoding,
+        WorldDescription,
+        PROTOCOL_VERSION,
+    },
+};
+
+
+/// The optional protocol features a peer supports, exchanged as a bit set during the
+/// handshake.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Features(u32);
+
+
+impl Features {
+    /// Entity positions may be sent as bfloat16, see [`PositionEncoding::BFloat16`]
+    pub const BFLOAT16: Features = Features(1 << 0);
+    /// Binary frames may be compressed
+    pub const COMPRESSION: Features = Features(1 << 2);
+    /// Simulation updates may be sent as deltas against a previous state
+    pub const DELTAS: Features = Features(1 << 1);
+    pub const NONE: Features = Features(0);
+    /// Simulation states may be sent as UDP datagrams, see [`UdpSession`]. It is not one of
+    /// the [`Features::supported`] ones, only clients which can receive datagrams ask for it.
+    pub const UDP: Features = Features(1 << 3);
+
+    /// The features this build of holodeck supports.
+    pub fn supported() -> Features {
+        let mut features = Features::DELTAS | Features::COMPRESSION;
+        if cfg!(feature = "bfloat16") {
+            features |= Features::BFLOAT16;
+        }
+        features
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(
+        self,
+        other: Features,
+    ) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// These features with `other` turned off.
+    pub fn without(
+        self,
+        other: Features,
+    ) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(
+        self,
+        rhs: Self,
+    ) -> Self::Output {
+        Features(self.0 | rhs.0)
+    }
+}
+
+
+impl std::ops::BitOrAssign for Features {
+    fn bitor_assign(
+        &mut self,
+        rhs: Self,
+    ) {
+        self.0 |= rhs.0
+    }
+}
+
+
+impl std::ops::BitAnd for Features {
+    type Output = Features;
+
+    fn bitand(
+        self,
+        rhs: Self,
+    ) -> Self::Output {
+        Features(self.0 & rhs.0)
+    }
+}
+
+
+/// The longest display name a client may go by, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+
+/// The first message sent by a client after the connection is established.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Hello {
+    pub version:           u16,
+    pub features:          Features,
+    /// how the client would like entity positions to be encoded
+    pub position_encoding: PositionEncoding,
+    /// how the client would like frames to be compressed, if at all
+    pub compression:       Option<Compression>,
+    /// what the client would like to be called by the simulation and other operators
+    pub name:              Option<String>,
+}
+
+
+impl Hello {
+    pub fn new(features: Features) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            features,
+            position_encoding: PositionEncoding::default(),
+            compression: Some(Compression::preferred()),
+            name: None,
+        }
+    }
+
+    pub fn with_name<S: Into<String>>(
+        mut self,
+        name: Option<S>,
+    ) -> Self {
+        self.name = name.map(Into::into);
+        self
+    }
+
+    /// The client's name fit for logs and other clients' screens, without control
+    /// characters and cut short at [`MAX_NAME_LEN`]. `None` if nothing is left of it.
+    pub fn display_name(&self) -> Option<String> {
+        let mut name = String::with_capacity(MAX_NAME_LEN);
+        let cleaned = self.name.as_deref()?.trim().chars().filter(|c| !c.is_control());
+        for c in cleaned {
+            if name.len() + c.len_utf8() > MAX_NAME_LEN {
+                break;
+            }
+            name.push(c);
+        }
+        Some(name).filter(|name| !name.is_empty())
+    }
+
+    pub fn with_compression(
+        mut self,
+        compression: Option<Compression>,
+    ) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The compression to use on a connection with the negotiated `features`.
+    pub fn compression(
+        &self,
+        features: Features,
+    ) -> Option<Compression> {
+        self.compression
+            .filter(|_| features.contains(Features::COMPRESSION))
+    }
+
+    pub fn with_position_encoding(
+        mut self,
+        position_encoding: PositionEncoding,
+    ) -> Self {
+        self.position_encoding = position_encoding;
+        self
+    }
+
+    /// The position encoding to use on a connection with the negotiated `features`, the
+    /// client's choice if both peers can encode it and otherwise plain `f32`.
+    pub fn position_encoding(
+        &self,
+        features: Features,
+    ) -> PositionEncoding {
+        if features.contains(self.position_encoding.requires()) {
+            self.position_encoding
+        } else {
+            PositionEncoding::F32
+        }
+    }
+
+    /// Server side of the handshake, decide which of the client's features will be used on
+    /// this connection or why the client cannot be served at all.
+    pub fn negotiate(
+        &self,
+        supported: Features,
+    ) -> std::result::Result<Features, Reject> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(Reject::new(format!(
+                "protocol version mismatch: server={} client={}",
+                PROTOCOL_VERSION, self.version
+            )));
+        }
+
+        let features = self.features & supported;
+        match self.compression {
+            Some(compression) if compression.is_supported() => Ok(features),
+            // nothing to compress with
+            _ => Ok(features.without(Features::COMPRESSION)),
+        }
+    }
+}
+
+
+/// The server's answer to an acceptable [`Hello`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Welcome {
+    pub version:           u16,
+    /// the id the server and the simulation know this client by
+    pub client:            ConnectionId,
+    /// the features enabled for this connection
+    pub features:          Features,
+    /// how entity positions are encoded on this connection
+    pub position_encoding: PositionEncoding,
+    /// how frames from the server are compressed, `None` if they are not prefixed at all
+    pub compression:       Option<Compression>,
+    pub world:             WorldDescription,
+    /// where to ask for simulation states over UDP, when [`Features::UDP`] is enabled
+    pub udp:               Option<UdpSession>,
+}
+
+
+/// The server's UDP port and the token a client sends there to have the full states it is
+/// sent over UDP rather than over the connection, partial states stay on the connection. The
+/// token is only taken from the address the client connected from, and the states go to the
+/// first address there to present it for as long as the client stays connected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UdpSession {
+    pub port:  u16,
+    pub token: u64,
+}
+
+
+impl Welcome {
+    /// Client side of the handshake, verify the server agreed to something this build can
+    /// actually decode and return the features enabled for the connection.
+    pub fn accept(
+        &self,
+        requested: Features,
+    ) -> crate::Result<Features> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(crate::Error::Handshake {
+                reason: format!(
+                    "protocol version mismatch: server={} client={}",
+                    self.version, PROTOCOL_VERSION
+                )
+                .into(),
+            });
+        }
+
+        if !requested.contains(self.features) {
+            return Err(crate::Error::Handshake {
+                reason: format!(
+                    "server enabled unsupported features: requested={:#x} enabled={:#x}",
+                    requested.bits(),
+                    self.features.bits()
+                )
+                .into(),
+            });
+        }
+
+        match self.compression {
+            Some(compression)
+                if !self.features.contains(Features::COMPRESSION) || !compression.is_supported() =>
+            {
+                return Err(crate::Error::Handshake {
+                    reason: format!("server chose unsupported compression: {:?}", compression).into(),
+                });
+            }
+            _ => {}
+        }
+
+        if self.udp.is_some() != self.features.contains(Features::UDP) {
+            return Err(crate::Error::Handshake {
+                reason: format!(
+                    "server enabled UDP={} with session={:?}",
+                    self.features.contains(Features::UDP),
+                    self.udp
+                )
+                .into(),
+            });
+        }
+
+        if !self.features.contains(self.position_encoding.requires()) {
+            return Err(crate::Error::Handshake {
+                reason: format!(
+                    "server chose the {:?} position encoding without negotiating it",
+                    self.position_encoding
+                )
+                .into(),
+            });
+        }
+
+        Ok(self.features)
+    }
+
+    /// The codec to decode entity positions sent on this connection with.
+    pub fn position_codec(&self) -> PositionCodec {
+        PositionCodec::new(self.position_encoding, self.world.bounds)
+    }
+}
+
+
+/// Sent instead of a [`Welcome`] when the server cannot serve the client, the server closes
+/// the connection right after.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Reject {
+    pub version: u16,
+    pub reason:  String,
+}
+
+
+impl Reject {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Reject {
+            version: PROTOCOL_VERSION,
+            reason:  reason.into(),
+        }
+    }
+}
+
+
+impl From<Reject> for crate::Error {
+    fn from(reject: Reject) -> Self {
+        crate::Error::Handshake {
+            reason: format!("rejected by server (v{}): {}", reject.version, reject.reason).into(),
+        }
+    }
+}
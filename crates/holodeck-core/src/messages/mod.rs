@@ -0,0 +1,227 @@
+use crate::deps::serde;
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::deps::tungstenite::Message as WebSocketMessage;
+
+
+mod command;
+mod envelope;
+mod handshake;
+mod subscription;
+mod world;
+
+
+/// The serialization formats frames can be sent in. The format is negotiated before the
+/// [`Hello`], as the websocket subprotocol (the `Sec-WebSocket-Protocol` header), a
+/// connection without a subprotocol uses bincode.
+pub mod codec;
+
+/// Binary frames from the server may be compressed once a connection negotiated
+/// [`Features::COMPRESSION`]. Every binary frame the server sends after the [`Welcome`] then
+/// starts with a byte saying how the rest of it is compressed, frames sent by clients and
+/// text frames are never compressed.
+pub mod compression;
+
+/// Entity positions are encoded according to a [`PositionCodec`] chosen per connection. The
+/// codec is not part of the serialized message, so (de)serializing has to happen within
+/// [`PositionCodec::scope`], anything outside of a scope uses plain `f32`.
+pub mod position;
+
+
+pub use self::{
+    codec::{
+        Codec,
+        Frame,
+        MessageCodec,
+    },
+    command::{
+        Command,
+        CommandError,
+        CommandId,
+        CommandKind,
+        CommandResult,
+        Reply,
+        Role,
+        SpawnRequest,
+    },
+    compression::Compression,
+    envelope::{
+        ConnectionId,
+        Dispatch,
+        Envelope,
+        ServerEvent,
+    },
+    handshake::{
+        Features,
+        Hello,
+        Reject,
+        UdpSession,
+        Welcome,
+        MAX_NAME_LEN,
+    },
+    position::{
+        PositionCodec,
+        PositionEncoding,
+    },
+    subscription::{
+        IdRange,
+        Interest,
+        Subscription,
+        TagSet,
+    },
+    world::{
+        Bounds,
+        TagColor,
+        UpAxis,
+        WorldDescription,
+        Zone,
+    },
+};
+
+
+/// The version of the wire protocol spoken by this build. Bump this whenever a change to
+/// [`Message`], or anything it carries, would make an older peer mis-decode a frame.
+pub const PROTOCOL_VERSION: u16 = 14;
+
+
+/// Every frame exchanged between a viewer and a server is one of these.
+///
+/// Frames are encoded with the [`MessageCodec`] picked as the connection's websocket
+/// subprotocol. A connection starts with the client sending [`Message::Hello`], the server
+/// answers with either [`Message::Welcome`] or [`Message::Reject`] (and then closes the
+/// connection). Only after the welcome do the regular simulation updates and client
+/// [`Command`]s flow. Clients may send a [`Message::Interest`] at any time after that to
+/// narrow the entities they are sent down to the ones around them, and a
+/// [`Message::Subscription`] to narrow them down to the ones they care about. `None` widens
+/// either back to the whole world.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Message {
+    Hello(Hello),
+    Welcome(Welcome),
+    Reject(Reject),
+    State(SimulationState),
+    Delta(SimulationDelta),
+    Command(Command),
+    CommandResult(CommandResult),
+    Interest(Option<Interest>),
+    Subscription(Option<Subscription>),
+}
+
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SimulationState {
+    pub tick:          u64,
+    pub entity_count:  u64,
+    pub entities:      Vec<Entity>,
+    /// ids of entities despawned since the previous state
+    pub removed:       Vec<u64>,
+    /// `entities` lists every live entity, anything the receiver knows about that is not
+    /// in it has been despawned. Otherwise the state only carries the entities that changed
+    /// and despawns are only communicated through `removed`.
+    pub authoritative: bool,
+}
+
+
+/// The changes between two simulation states, only sent on connections which negotiated
+/// [`Features::DELTAS`]. Applying a delta to anything but the state at `base_tick` is an
+/// error, a receiver that lost track should wait for the next full [`SimulationState`].
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SimulationDelta {
+    pub tick:         u64,
+    pub base_tick:    u64,
+    pub entity_count: u64,
+    /// entities which did not exist in the base state
+    pub created:      Vec<Entity>,
+    /// entities which moved further than the server's threshold, or otherwise changed
+    pub moved:        Vec<Entity>,
+    /// ids of entities which no longer exist
+    pub removed:      Vec<u64>,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Entity {
+    pub id:           u64,
+    pub tag:          u16,
+    /// encoded with the [`PositionEncoding`] of the enclosing [`PositionCodec::scope`]
+    #[serde(with = "position::x")]
+    pub x:            f32,
+    #[serde(with = "position::y")]
+    pub y:            f32,
+    #[serde(with = "position::z")]
+    pub z:            f32,
+    /// orientation as a unit quaternion `[i, j, k, w]` in simulation coordinates, `None` is
+    /// the identity
+    pub rotation:     Option<[f32; 4]>,
+    /// size along each simulation axis, `None` is the viewer's default size for the `kind`
+    pub scale:        Option<[f32; 3]>,
+    /// the shape the entity is rendered as, `None` is a [`Kind::Block`]
+    pub kind:         Option<Kind>,
+    /// velocity in simulation units per tick, receivers extrapolate the position between
+    /// states with it when present
+    pub velocity:     Option<[f32; 3]>,
+    /// change in velocity per tick, only meaningful alongside a `velocity`
+    pub acceleration: Option<[f32; 3]>,
+}
+
+
+impl Entity {
+    /// An entity at the position with the default orientation, size and shape.
+    pub fn new(
+        id: u64,
+        tag: u16,
+        x: f32,
+        y: f32,
+        z: f32,
+    ) -> Self {
+        Entity {
+            id,
+            tag,
+            x,
+            y,
+            z,
+            rotation: None,
+            scale: None,
+            kind: None,
+            velocity: None,
+            acceleration: None,
+        }
+    }
+
+    /// Set the rotation from yaw (about z), pitch (about y) and roll (about x) in radians.
+    pub fn set_yaw_pitch_roll(
+        &mut self,
+        yaw: f32,
+        pitch: f32,
+        roll: f32,
+    ) {
+        let (sy, cy) = (yaw * 0.5).sin_cos();
+        let (sp, cp) = (pitch * 0.5).sin_cos();
+        let (sr, cr) = (roll * 0.5).sin_cos();
+
+        self.rotation = Some([
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+            cr * cp * cy + sr * sp * sy,
+        ]);
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind.unwrap_or(Kind::Block)
+    }
+
+    pub fn position(&self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+
+/// The shapes a viewer knows how to render an entity as.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Kind {
+    Block,
+    Player,
+    Plane,
+    Sphere,
+    Quad,
+}
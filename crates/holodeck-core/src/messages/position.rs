@@ -0,0 +1,363 @@
+use std::{
+    cell::Cell,
+    fmt,
+};
+
+use crate::{
+    deps::serde::{
+        de::{
+            self,
+            SeqAccess,
+            Visitor,
+        },
+        Deserialize,
+        Deserializer,
+        Serialize,
+        Serializer,
+    },
+    messages::{
+        Bounds,
+        Features,
+    },
+};
+
+
+/// How the coordinates of an entity's position are put on the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum PositionEncoding {
+    /// 4 bytes per coordinate, lossless
+    F32,
+    /// 2 bytes per coordinate, 8 bits of mantissa so the precision drops with the
+    /// distance from the origin
+    BFloat16,
+    /// 2 bytes per coordinate, quantized evenly over the world bounds. Coordinates outside
+    /// of them take another 4 bytes for the exact value, and axes the bounds are flat on
+    /// are sent as `f32`
+    Fixed16,
+    /// 3 bytes per coordinate, otherwise like [`PositionEncoding::Fixed16`]
+    Fixed24,
+}
+
+
+impl PositionEncoding {
+    /// The features both peers need for the encoding to be used.
+    pub fn requires(self) -> Features {
+        match self {
+            PositionEncoding::BFloat16 => Features::BFLOAT16,
+            PositionEncoding::F32 | PositionEncoding::Fixed16 | PositionEncoding::Fixed24 => Features::NONE,
+        }
+    }
+}
+
+
+impl Default for PositionEncoding {
+    /// bfloat16 for builds with the `serde_f32_as_bfloat16` cargo feature, f32 otherwise
+    fn default() -> Self {
+        if cfg!(all(feature = "bfloat16", feature = "serde_f32_as_bfloat16")) {
+            PositionEncoding::BFloat16
+        } else {
+            PositionEncoding::F32
+        }
+    }
+}
+
+
+impl std::str::FromStr for PositionEncoding {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(Self::F32),
+            "bfloat16" => Ok(Self::BFloat16),
+            "bf16" => Ok(Self::BFloat16),
+            "fixed16" => Ok(Self::Fixed16),
+            "fixed24" => Ok(Self::Fixed24),
+            _ => {
+                Err(crate::Error::BadValue {
+                    from:  "str".into(),
+                    to:    "PositionEncoding".into(),
+                    value: s.to_string().into(),
+                })
+            }
+        }
+    }
+}
+
+
+/// A position encoding along with the world bounds the fixed point encodings are
+/// relative to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PositionCodec {
+    pub encoding: PositionEncoding,
+    pub bounds:   Bounds,
+}
+
+
+thread_local! {
+    static CODEC: Cell<Option<PositionCodec>> = Cell::new(None);
+}
+
+
+impl PositionCodec {
+    /// the largest fixed point values, they say the exact `f32` follows rather than
+    /// being positions themselves
+    const FIXED16_MAX: u32 = 0xFFFF;
+    const FIXED24_MAX: u32 = 0xFF_FFFF;
+
+    pub fn new(
+        encoding: PositionEncoding,
+        bounds: Bounds,
+    ) -> Self {
+        PositionCodec { encoding, bounds }
+    }
+
+    /// Run `f`, (de)serializing any entity positions within it with this codec.
+    pub fn scope<F, R>(
+        &self,
+        f: F,
+    ) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Restore(Option<PositionCodec>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CODEC.with(|codec| codec.set(self.0));
+            }
+        }
+
+        let _restore = Restore(CODEC.with(|codec| codec.replace(Some(*self))));
+        f()
+    }
+
+    fn current() -> Option<PositionCodec> {
+        CODEC.with(|codec| codec.get())
+    }
+
+    /// Whether coordinates along `axis` are sent as fixed point at all, bounds which are
+    /// flat along it leave nothing to quantize over.
+    fn quantizes(
+        &self,
+        axis: usize,
+    ) -> bool {
+        self.range(axis).1 > 0.0
+    }
+
+    /// The fixed point value below `max` for `value`, `None` when it is out of bounds.
+    fn quantize(
+        &self,
+        axis: usize,
+        value: f32,
+        max: u32,
+    ) -> Option<u32> {
+        let (min, range) = self.range(axis);
+        let unit = (value - min) / range;
+        if !(0.0..=1.0).contains(&unit) {
+            return None;
+        }
+        Some((unit as f64 * (max - 1) as f64).round() as u32)
+    }
+
+    fn dequantize(
+        &self,
+        axis: usize,
+        value: u32,
+        max: u32,
+    ) -> f32 {
+        let (min, range) = self.range(axis);
+        min + (value as f64 / (max - 1) as f64 * range as f64) as f32
+    }
+
+    fn range(
+        &self,
+        axis: usize,
+    ) -> (f32, f32) {
+        let min = self.bounds.min[axis];
+        (min, self.bounds.max[axis] - min)
+    }
+}
+
+
+fn serialize<S>(
+    axis: usize,
+    value: f32,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let codec = match PositionCodec::current() {
+        Some(codec) => codec,
+        None => return serializer.serialize_f32(value),
+    };
+
+    match codec.encoding {
+        PositionEncoding::F32 => serializer.serialize_f32(value),
+        #[cfg(feature = "bfloat16")]
+        PositionEncoding::BFloat16 => {
+            serializer.serialize_u16(crate::deps::half::bf16::from_f32(value).to_bits())
+        }
+        #[cfg(not(feature = "bfloat16"))]
+        PositionEncoding::BFloat16 => {
+            Err(<S::Error as crate::deps::serde::ser::Error>::custom(
+                "built without support for bfloat16 positions",
+            ))
+        }
+        PositionEncoding::Fixed16 | PositionEncoding::Fixed24 if !codec.quantizes(axis) => {
+            serializer.serialize_f32(value)
+        }
+        PositionEncoding::Fixed16 => {
+            let max = PositionCodec::FIXED16_MAX;
+            match codec.quantize(axis, value, max) {
+                Some(fixed) => (fixed as u16,).serialize(serializer),
+                None => (max as u16, value).serialize(serializer),
+            }
+        }
+        PositionEncoding::Fixed24 => {
+            let max = PositionCodec::FIXED24_MAX;
+            match codec.quantize(axis, value, max) {
+                Some(fixed) => {
+                    let bytes = fixed.to_le_bytes();
+                    (bytes[0], bytes[1], bytes[2]).serialize(serializer)
+                }
+                None => (0xFFu8, 0xFFu8, 0xFFu8, value).serialize(serializer),
+            }
+        }
+    }
+}
+
+
+fn deserialize<'de, D>(
+    axis: usize,
+    deserializer: D,
+) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let codec = match PositionCodec::current() {
+        Some(codec) => codec,
+        None => return f32::deserialize(deserializer),
+    };
+
+    match codec.encoding {
+        PositionEncoding::F32 => f32::deserialize(deserializer),
+        #[cfg(feature = "bfloat16")]
+        PositionEncoding::BFloat16 => {
+            let bits = u16::deserialize(deserializer)?;
+            Ok(crate::deps::half::bf16::from_bits(bits).to_f32())
+        }
+        #[cfg(not(feature = "bfloat16"))]
+        PositionEncoding::BFloat16 => {
+            Err(<D::Error as crate::deps::serde::de::Error>::custom(
+                "built without support for bfloat16 positions",
+            ))
+        }
+        PositionEncoding::Fixed16 | PositionEncoding::Fixed24 if !codec.quantizes(axis) => {
+            f32::deserialize(deserializer)
+        }
+        PositionEncoding::Fixed16 => deserializer.deserialize_tuple(2, Fixed { codec, axis }),
+        PositionEncoding::Fixed24 => deserializer.deserialize_tuple(4, Fixed { codec, axis }),
+    }
+}
+
+
+/// Reads a fixed point coordinate, followed by the exact one when it is out of bounds.
+struct Fixed {
+    codec: PositionCodec,
+    axis:  usize,
+}
+
+
+impl<'de> Visitor<'de> for Fixed {
+    type Value = f32;
+
+    fn expecting(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "a {:?} coordinate", self.codec.encoding)
+    }
+
+    fn visit_seq<A>(
+        self,
+        mut seq: A,
+    ) -> Result<f32, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let (value, max, len) = match self.codec.encoding {
+            PositionEncoding::Fixed16 => {
+                (
+                    element::<u16, _>(&mut seq, 0)? as u32,
+                    PositionCodec::FIXED16_MAX,
+                    1,
+                )
+            }
+            _ => {
+                let bytes = [
+                    element(&mut seq, 0)?,
+                    element(&mut seq, 1)?,
+                    element(&mut seq, 2)?,
+                ];
+                (
+                    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]),
+                    PositionCodec::FIXED24_MAX,
+                    3,
+                )
+            }
+        };
+
+        if value == max {
+            element(&mut seq, len)
+        } else {
+            Ok(self.codec.dequantize(self.axis, value, max))
+        }
+    }
+}
+
+
+fn element<'de, T, A>(
+    seq: &mut A,
+    index: usize,
+) -> Result<T, A::Error>
+where
+    T: Deserialize<'de>,
+    A: SeqAccess<'de>,
+{
+    seq.next_element()?
+        .ok_or_else(|| de::Error::invalid_length(index, &"a fixed point coordinate"))
+}
+
+
+macro_rules! axis {
+    ($name:ident, $axis:expr) => {
+        pub mod $name {
+            use crate::deps::serde::{
+                Deserializer,
+                Serializer,
+            };
+
+            pub fn serialize<S>(
+                value: &f32,
+                serializer: S,
+            ) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                super::serialize($axis, *value, serializer)
+            }
+
+            pub fn deserialize<'de, D>(deserializer: D) -> Result<f32, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                super::deserialize($axis, deserializer)
+            }
+        }
+    };
+}
+
+axis!(x, 0);
+axis!(y, 1);
+axis!(z, 2);
@@ -0,0 +1,210 @@
+use crate::{
+    deps::serde,
+    messages::Entity,
+};
+
+
+/// The part of the world a viewer is looking at, a sphere in simulation coordinates. The
+/// server only sends the viewer the entities inside it.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Interest {
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+
+impl Interest {
+    pub fn new(
+        center: [f32; 3],
+        radius: f32,
+    ) -> Self {
+        Interest { center, radius }
+    }
+
+    /// The squared distance from the center to `position`.
+    pub fn distance_sq(
+        &self,
+        position: [f32; 3],
+    ) -> f32 {
+        let [dx, dy, dz] = [
+            position[0] - self.center[0],
+            position[1] - self.center[1],
+            position[2] - self.center[2],
+        ];
+        dx * dx + dy * dy + dz * dz
+    }
+}
+
+
+/// The entities a client wants to be sent, whatever part of the world it looks at. Entities
+/// carry no properties beyond their tag and id yet, which are all there is to pick them by.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Subscription {
+    pub tags: TagSet,
+    /// entities with an id in one of the ranges, `None` for any id
+    #[serde(default, deserialize_with = "bounded::option")]
+    pub ids:  Option<Vec<IdRange>>,
+}
+
+
+impl Subscription {
+    /// The most tags or id ranges a subscription may list. Every entity is checked against
+    /// them on every tick, so longer lists are refused when decoding.
+    pub const MAX_LIST_LEN: usize = 256;
+
+    pub fn matches(
+        &self,
+        entity: &Entity,
+    ) -> bool {
+        let ids = match &self.ids {
+            Some(ranges) => ranges.iter().any(|range| range.contains(entity.id)),
+            None => true,
+        };
+        self.tags.contains(entity.tag) && ids
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TagSet {
+    /// only entities with one of these tags
+    Only(#[serde(deserialize_with = "bounded::vec")] Vec<u16>),
+    /// entities with any tag but these
+    Except(#[serde(deserialize_with = "bounded::vec")] Vec<u16>),
+}
+
+
+impl TagSet {
+    pub fn contains(
+        &self,
+        tag: u16,
+    ) -> bool {
+        match self {
+            TagSet::Only(tags) => tags.contains(&tag),
+            TagSet::Except(tags) => !tags.contains(&tag),
+        }
+    }
+}
+
+
+impl Default for TagSet {
+    /// every tag
+    fn default() -> Self {
+        TagSet::Except(Vec::new())
+    }
+}
+
+
+/// The ids from `first` to `last`, inclusive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IdRange {
+    pub first: u64,
+    pub last:  u64,
+}
+
+
+impl IdRange {
+    pub fn contains(
+        &self,
+        id: u64,
+    ) -> bool {
+        self.first <= id && id <= self.last
+    }
+}
+
+
+/// Reads the lists of a [`Subscription`], giving up as soon as one turns out to be longer than
+/// [`Subscription::MAX_LIST_LEN`].
+mod bounded {
+    use std::{
+        fmt,
+        marker::PhantomData,
+    };
+
+    use crate::{
+        deps::serde::{
+            de::{
+                self,
+                SeqAccess,
+                Visitor,
+            },
+            Deserialize,
+            Deserializer,
+        },
+        messages::Subscription,
+    };
+
+    struct Bounded<T>(PhantomData<T>);
+
+
+    impl<'de, T> Visitor<'de> for Bounded<T>
+    where
+        T: Deserialize<'de>,
+    {
+        type Value = Vec<T>;
+
+        fn expecting(
+            &self,
+            f: &mut fmt::Formatter,
+        ) -> fmt::Result {
+            write!(f, "a list of at most {} elements", Subscription::MAX_LIST_LEN)
+        }
+
+        fn visit_seq<A>(
+            self,
+            mut seq: A,
+        ) -> Result<Vec<T>, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let max = Subscription::MAX_LIST_LEN;
+            let hint = seq.size_hint().unwrap_or(0);
+            if hint > max {
+                return Err(de::Error::invalid_length(hint, &self));
+            }
+
+            let mut values = Vec::with_capacity(hint);
+            while let Some(value) = seq.next_element()? {
+                if values.len() == max {
+                    return Err(de::Error::invalid_length(max + 1, &self));
+                }
+                values.push(value);
+            }
+            Ok(values)
+        }
+    }
+
+
+    struct List<T>(Vec<T>);
+
+
+    impl<'de, T> Deserialize<'de> for List<T>
+    where
+        T: Deserialize<'de>,
+    {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_seq(Bounded(PhantomData)).map(List)
+        }
+    }
+
+
+    pub fn vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        List::deserialize(deserializer).map(|List(values)| values)
+    }
+
+
+    pub fn option<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Option::<List<T>>::deserialize(deserializer).map(|list| list.map(|List(values)| values))
+    }
+}
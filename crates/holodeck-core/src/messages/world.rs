@@ -0,0 +1,148 @@
+use crate::deps::serde;
+
+
+/// What the server knows about the world it is simulating, viewers lay out their scene from
+/// it.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorldDescription {
+    pub bounds:        Bounds,
+    pub max_entities:  u64,
+    /// the simulation axis which points up
+    pub up_axis:       UpAxis,
+    /// added to every simulation position before it is placed in the viewer's scene
+    pub origin:        [f32; 3],
+    /// the height of the ground along the up axis, in simulation coordinates
+    pub ground_height: f32,
+    pub zones:         Vec<Zone>,
+    /// colors for entity tags, tags without one get a color from the viewer's palette
+    pub tag_colors:    Vec<TagColor>,
+}
+
+
+impl WorldDescription {
+    /// A z up world centered on the origin with no zones or tag colors.
+    pub fn new(
+        bounds: Bounds,
+        max_entities: u64,
+    ) -> Self {
+        WorldDescription {
+            bounds,
+            max_entities,
+            up_axis: UpAxis::Z,
+            origin: [0.0; 3],
+            ground_height: 0.0,
+            zones: Vec::new(),
+            tag_colors: Vec::new(),
+        }
+    }
+
+    /// Cover the ground plane within the bounds with square zones of the given size.
+    pub fn with_zone_grid(
+        mut self,
+        size: f32,
+    ) -> Self {
+        let (a, b) = self.up_axis.ground_axes();
+        let (min, max) = (self.bounds.min, self.bounds.max);
+
+        self.zones.clear();
+        if size <= 0.0 {
+            return self;
+        }
+
+        let mut u = min[a];
+        while u < max[a] {
+            let mut v = min[b];
+            while v < max[b] {
+                let mut zone = Bounds {
+                    min: [self.ground_height; 3],
+                    max: [self.ground_height; 3],
+                };
+                zone.min[a] = u;
+                zone.max[a] = (u + size).min(max[a]);
+                zone.min[b] = v;
+                zone.max[b] = (v + size).min(max[b]);
+                self.zones.push(Zone {
+                    bounds: zone,
+                    color:  None,
+                });
+                v += size;
+            }
+            u += size;
+        }
+
+        self
+    }
+
+    pub fn with_tag_color(
+        mut self,
+        tag: u16,
+        color: [u8; 4],
+    ) -> Self {
+        self.tag_colors.retain(|c| c.tag != tag);
+        self.tag_colors.push(TagColor { tag, color });
+        self
+    }
+}
+
+
+/// Which axis of the simulation's coordinate system points up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+
+impl UpAxis {
+    /// The indices of the two axes spanning the ground plane.
+    pub fn ground_axes(self) -> (usize, usize) {
+        match self {
+            UpAxis::Y => (0, 2),
+            UpAxis::Z => (0, 1),
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            UpAxis::Y => 1,
+            UpAxis::Z => 2,
+        }
+    }
+}
+
+
+/// An area of the world highlighted by the viewer.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Zone {
+    pub bounds: Bounds,
+    /// rgba, `None` picks the next color of the viewer's palette
+    pub color:  Option<[u8; 4]>,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TagColor {
+    pub tag:   u16,
+    /// rgba
+    pub color: [u8; 4],
+}
+
+
+/// An axis aligned box in simulation coordinates.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+
+impl Bounds {
+    /// A `size` x `size` square on the x/y plane centered on the origin.
+    pub fn square(size: f32) -> Self {
+        let half = size / 2.0;
+        Bounds {
+            min: [-half, -half, 0.0],
+            max: [half, half, 0.0],
+        }
+    }
+}
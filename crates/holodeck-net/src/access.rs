@@ -0,0 +1,313 @@
+//! Who may connect and what they may do. Clients present a bearer token, either in an
+//! `Authorization: Bearer <token>` header or as a `token` query parameter since browsers cannot
+//! set headers on a websocket, and the token decides the client's [`Role`]. Browsers also send
+//! the `Origin` of the page opening the socket, which is checked against an allowlist so other
+//! sites cannot connect on a visitor's behalf.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+};
+
+use crate::{
+    deps::tokio_tungstenite::tungstenite::{
+        handshake::server::{
+            ErrorResponse,
+            Request,
+        },
+        http::{
+            header::{
+                AUTHORIZATION,
+                ORIGIN,
+            },
+            Response,
+            StatusCode,
+        },
+    },
+    message::Role,
+};
+
+
+#[derive(Clone, PartialEq)]
+pub struct Access {
+    /// the bearer tokens clients may present and the role each grants
+    pub tokens:          HashMap<String, Role>,
+    /// the role of clients which present no token, `None` turns them away
+    pub anonymous:       Option<Role>,
+    /// the origins browsers may connect from, any when empty. Clients which send no `Origin`,
+    /// anything but a browser, are not checked.
+    pub allowed_origins: Vec<String>,
+}
+
+
+impl Default for Access {
+    /// Anyone may connect and do anything.
+    fn default() -> Self {
+        Access {
+            tokens:          HashMap::new(),
+            anonymous:       Some(Role::Admin),
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+
+impl Access {
+    /// Grant `role` to clients presenting `token`. Clients without a token are still let in
+    /// unless [`Access::with_anonymous`] says otherwise.
+    pub fn with_token<S: Into<String>>(
+        mut self,
+        token: S,
+        role: Role,
+    ) -> Self {
+        self.tokens.insert(token.into(), role);
+        self
+    }
+
+    pub fn with_anonymous(
+        mut self,
+        role: Option<Role>,
+    ) -> Self {
+        self.anonymous = role;
+        self
+    }
+
+    pub fn with_allowed_origin<S: Into<String>>(
+        mut self,
+        origin: S,
+    ) -> Self {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+
+    /// The role of the client making the websocket upgrade `request`, or the response turning
+    /// it away.
+    pub fn authorize(
+        &self,
+        request: &Request,
+    ) -> std::result::Result<Role, Box<ErrorResponse>> {
+        if let Some(origin) = request.headers().get(ORIGIN) {
+            let allowed = self.allowed_origins.is_empty()
+                || origin
+                    .to_str()
+                    .map(|origin| self.allowed_origins.iter().any(|allowed| allowed == origin))
+                    .unwrap_or(false);
+            if !allowed {
+                return Err(refuse(StatusCode::FORBIDDEN, "origin not allowed"));
+            }
+        }
+
+        match bearer_token(request) {
+            Some(token) => {
+                self.role_of(&token)
+                    .ok_or_else(|| refuse(StatusCode::UNAUTHORIZED, "unknown token"))
+            }
+            None => {
+                self.anonymous
+                    .ok_or_else(|| refuse(StatusCode::UNAUTHORIZED, "a token is required"))
+            }
+        }
+    }
+
+    /// The role `token` grants. Every known token is compared in full, so how long this takes
+    /// says nothing about how close `token` came to one of them.
+    fn role_of(
+        &self,
+        token: &str,
+    ) -> Option<Role> {
+        self.tokens.iter().fold(None, |found, (known, role)| {
+            if constant_time_eq(known.as_bytes(), token.as_bytes()) {
+                Some(*role)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+
+/// The config is logged, the tokens are left out.
+impl fmt::Debug for Access {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let mut roles = self.tokens.values().collect::<Vec<_>>();
+        roles.sort();
+        f.debug_struct("Access")
+            .field("tokens", &roles)
+            .field("anonymous", &self.anonymous)
+            .field("allowed_origins", &self.allowed_origins)
+            .finish()
+    }
+}
+
+
+/// The token from the `Authorization` header, falling back to the `token` query parameter.
+fn bearer_token(request: &Request) -> Option<Cow<'_, str>> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match header {
+        Some(token) => Some(Cow::Borrowed(token)),
+        None => {
+            let token = request
+                .uri()
+                .query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))?;
+            // a token which does not decode is left as is, it is not going to be known either
+            Some(percent_decode(token).map_or(Cow::Borrowed(token), Cow::Owned))
+        }
+    }
+}
+
+
+/// Undo the percent encoding of a query value, `+` is a space like browsers encode forms.
+/// `None` when it is not valid UTF-8 or holds a `%` which is not followed by two hex digits.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.bytes();
+    while let Some(byte) = rest.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [rest.next()?, rest.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+
+/// Whether `a` and `b` are equal, in a time that only depends on their lengths.
+fn constant_time_eq(
+    a: &[u8],
+    b: &[u8],
+) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+
+fn refuse(
+    status: StatusCode,
+    reason: &str,
+) -> Box<ErrorResponse> {
+    let mut response = Response::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    Box::new(response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Request {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+
+    fn access() -> Access {
+        Access::default()
+            .with_token("watch", Role::Viewer)
+            .with_token("drive", Role::Operator)
+            .with_anonymous(None)
+            .with_allowed_origin("https://holodeck.example")
+    }
+
+
+    #[test]
+    fn tokens_grant_their_role() {
+        let access = access();
+        let header = request("ws://localhost/", &[("Authorization", "Bearer drive")]);
+        assert_eq!(access.authorize(&header).ok(), Some(Role::Operator));
+
+        let query = request(
+            "ws://localhost/?codec=json&token=watch",
+            &[("Origin", "https://holodeck.example")],
+        );
+        assert_eq!(access.authorize(&query).ok(), Some(Role::Viewer));
+    }
+
+
+    #[test]
+    fn query_tokens_are_percent_decoded() {
+        let token = "a+b/c=d%e f";
+        let access = Access::default()
+            .with_token(token, Role::Operator)
+            .with_anonymous(None);
+        let role = |uri: &str| access.authorize(&request(uri, &[])).ok();
+
+        assert_eq!(
+            role("ws://localhost/?token=a%2Bb%2Fc%3Dd%25e%20f"),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            role("ws://localhost/?codec=json&token=a%2bb%2fc%3dd%25e+f"),
+            Some(Role::Operator)
+        );
+        // a `+` is a space, anything else would be guessing
+        assert_eq!(role("ws://localhost/?token=a+b%2Fc%3Dd%25e%20f"), None);
+        assert_eq!(role("ws://localhost/?token=a%2Bb%2Fc%3Dd%e%20f"), None);
+
+        let header = request("ws://localhost/", &[("Authorization", "Bearer a+b/c=d%e f")]);
+        assert_eq!(access.authorize(&header).ok(), Some(Role::Operator));
+    }
+
+
+    #[test]
+    fn tokens_are_compared_in_full() {
+        assert!(constant_time_eq(b"drive", b"drive"));
+        assert!(!constant_time_eq(b"drive", b"drivE"));
+        assert!(!constant_time_eq(b"drive", b"driver"));
+        assert!(!constant_time_eq(b"", b"d"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+
+    #[test]
+    fn unknown_tokens_and_origins_are_refused() {
+        let access = access();
+        let status = |request: Request| access.authorize(&request).map_err(|response| response.status());
+
+        assert_eq!(
+            status(request("ws://localhost/", &[])),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(request("ws://localhost/?token=guess", &[])),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(request(
+                "ws://localhost/?token=drive",
+                &[("Origin", "https://evil.example")]
+            )),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+
+    #[test]
+    fn roles_allow_the_commands_below_them() {
+        use crate::message::CommandKind;
+
+        assert!(!Role::Viewer.allows(&CommandKind::Despawn { id: 1 }));
+        assert!(Role::Operator.allows(&CommandKind::Despawn { id: 1 }));
+        assert!(!Role::Operator.allows(&CommandKind::Pause));
+        assert!(Role::Admin.allows(&CommandKind::Pause));
+    }
+}
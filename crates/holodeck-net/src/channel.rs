@@ -1,5 +1,38 @@
+use std::{
+    collections::{
+        hash_map::RandomState,
+        HashMap,
+        VecDeque,
+    },
+    hash::{
+        BuildHasher,
+        Hasher,
+    },
+    io,
+    mem,
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
 use crate::{
+    delta::{
+        self,
+        DeltaEncoder,
+    },
     deps::{
+        bincode,
         futures::{
             stream::{
                 SplitSink,
@@ -8,83 +41,1234 @@ use crate::{
             SinkExt,
         },
         futures_util::StreamExt,
-        holodeck_core::Result,
+        holodeck_core::{
+            Error,
+            Result,
+        },
         log::{
-            error,
+            debug,
             info,
+            warn,
         },
         tokio,
-        tokio::{
-            net::TcpStream,
-            sync::mpsc::Sender,
+        tokio::sync::{
+            mpsc::Sender,
+            Mutex as AsyncMutex,
+            Notify,
         },
-        tokio_tungstenite::{
-            tungstenite::Message,
-            WebSocketStream,
+        tokio_tungstenite::tungstenite::{
+            protocol::{
+                frame::coding::CloseCode,
+                CloseFrame,
+            },
+            Message,
         },
     },
+    interest::{
+        InterestFilter,
+        SpatialIndex,
+    },
+    lod::UpdateSchedule,
     message::{
+        Codec,
+        Command,
+        Compression,
+        ConnectionId,
+        Entity,
+        Features,
+        Frame,
+        Interest,
+        Message as HolodeckMessage,
+        MessageCodec,
+        PositionCodec,
+        Role,
+        SimulationDelta,
         SimulationState,
-        SpawnRequest,
-        ToWebSocketMessage,
+        Subscription,
+        Welcome,
+    },
+    metrics::{
+        ClientMetrics,
+        Metrics,
+    },
+    server::{
+        Accepted,
+        Config,
+        Delivery,
     },
+    transport::Frames,
+    udp::UdpSender,
 };
 
 
+/// Something that happened on a client connection, along with the connection it happened on so
+/// a command's result can find its way back.
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    Command {
+        connection: ConnectionId,
+        command:    Command,
+    },
+    /// both halves of the connection are torn down, nothing more is sent or received
+    Closed {
+        connection: ConnectionId,
+        reason:     String,
+    },
+}
+
+
+/// What was sent to a client, before and after compression.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub frames:             u64,
+    pub uncompressed_bytes: u64,
+    pub sent_bytes:         u64,
+    /// frames dropped because the client had not caught up with them yet
+    pub dropped:            u64,
+}
+
+
+impl FrameStats {
+    fn record(
+        &mut self,
+        uncompressed: usize,
+        sent: usize,
+    ) {
+        self.frames += 1;
+        self.uncompressed_bytes += uncompressed as u64;
+        self.sent_bytes += sent as u64;
+    }
+
+    /// sent / uncompressed bytes
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            1.0
+        } else {
+            self.sent_bytes as f64 / self.uncompressed_bytes as f64
+        }
+    }
+}
+
+
 pub struct SimulationChannel {
-    sink:       SplitSink<WebSocketStream<TcpStream>, Message>,
-    fwd_handle: tokio::task::JoinHandle<()>,
+    connection:  ConnectionId,
+    sink:        SplitSink<Frames, Message>,
+    codec:       MessageCodec,
+    deltas:      Option<DeltaEncoder>,
+    positions:   PositionCodec,
+    compression: Option<Compression>,
+    /// frames smaller than this are sent uncompressed
+    threshold:   usize,
+    stats:       FrameStats,
+    /// shared with the client's [`Mailbox`], which does the dropping
+    dropped:     Arc<AtomicU64>,
+    /// sends states over UDP instead once the client registers
+    udp:         Option<UdpSender>,
+    /// shared with the task receiving from the client
+    reported:    Arc<Mutex<Reported>>,
+    filter:      InterestFilter,
+    /// how often entities far from the client's camera are updated
+    schedule:    UpdateSchedule,
+    /// the server's, the client is forgotten by them once the channel is dropped
+    metrics:     Arc<Metrics>,
+    recorded:    Arc<ClientMetrics>,
 }
 
 
 impl SimulationChannel {
+    /// Log the client's frame stats every this many frames.
+    const STATS_INTERVAL: u64 = 300;
+
     pub fn new(
-        client_stream: WebSocketStream<TcpStream>,
-        fwd: Sender<SpawnRequest>,
+        connection: ConnectionId,
+        sink: SplitSink<Frames, Message>,
+        codec: MessageCodec,
+        welcome: &Welcome,
+        udp: Option<UdpSender>,
+        config: &Config,
+        metrics: &Arc<Metrics>,
     ) -> SimulationChannel {
-        let (sink, incoming) = client_stream.split();
+        let features = welcome.features;
+        let dropped = Arc::new(AtomicU64::new(0));
+        let recorded = metrics.connected(connection, dropped.clone());
+        let filter = InterestFilter::new(
+            config.up_axis,
+            config.interest_cell_size,
+            config.interest_hysteresis,
+        );
+        let deltas = if features.contains(Features::DELTAS) {
+            Some(DeltaEncoder::new(
+                config.keyframe_interval,
+                config.delta_threshold,
+            ))
+        } else {
+            None
+        };
+
         SimulationChannel {
-            fwd_handle: tokio::task::spawn(simulation_message_forwarding(incoming, fwd)),
+            connection,
+            codec,
+            deltas,
+            positions: welcome.position_codec(),
+            compression: welcome.compression,
+            threshold: config.compression_threshold,
+            stats: FrameStats::default(),
+            dropped,
             sink,
+            udp,
+            reported: Arc::new(Mutex::new(Reported::default())),
+            filter,
+            schedule: UpdateSchedule::new(&config.update_rates),
+            metrics: metrics.clone(),
+            recorded,
+        }
+    }
+
+    pub fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    /// Send a [`HolodeckMessage::State`] to the client, as a delta against the last state the
+    /// client was sent if the client supports it. States are narrowed down to the client's
+    /// [`Interest`] and [`Subscription`] when it reported them, deltas only carry the changes to
+    /// entities far from the client's camera every so often. Full states of the whole world and
+    /// deltas are encoded once for every client with the same settings which is sent the same
+    /// thing. Clients getting their states over UDP get full states that way as soon as they
+    /// register, partial states and anything before they register go over the connection.
+    pub async fn send_state(
+        &mut self,
+        broadcast: &Broadcast,
+    ) -> Result<()> {
+        let filtered = self.filtered(broadcast).map(HolodeckMessage::State);
+        let message = filtered.as_ref().unwrap_or(&broadcast.message);
+        self.schedule.advance();
+        let (schedule, interest) = (&self.schedule, self.filter.interest());
+        let delta = match (message, self.deltas.as_mut(), interest) {
+            (HolodeckMessage::State(state), Some(encoder), Some(interest)) => {
+                let due = |entity: &Entity| schedule.is_due(entity, &interest);
+                encoder.encode_with(state, due)
+            }
+            (HolodeckMessage::State(state), Some(encoder), None) => encoder.encode(state),
+            _ => None,
+        };
+
+        let settings = self.settings();
+        let encoded = match &delta {
+            Some(delta) => {
+                let encode = || self.encode(&HolodeckMessage::Delta(delta.clone()));
+                broadcast
+                    .encoded(settings, Some(broadcast.fingerprint(delta)), encode)
+                    .await
+            }
+            None if filtered.is_some() => self.encode(message).map(Arc::new),
+            None => {
+                broadcast
+                    .encoded(settings, None, || self.encode(&broadcast.message))
+                    .await
+            }
+        };
+
+        let encoded = match encoded {
+            Some(encoded) => encoded,
+            None => return Ok(()),
+        };
+        // a partial state lost on the way takes its despawns with it for good, only a full state
+        // makes up for the ones lost before it
+        let full = delta.is_none() && matches!(message, HolodeckMessage::State(state) if state.authoritative);
+        match self.udp.as_ref().and_then(UdpSender::peer).filter(|_| full) {
+            Some(peer) => {
+                self.send_datagrams(peer, &encoded).await;
+                Ok(())
+            }
+            None => self.send_encoded(encoded).await,
         }
     }
 
     pub async fn send(
         &mut self,
-        state: &SimulationState,
+        message: &HolodeckMessage,
     ) -> Result<()> {
-        if let Ok(message) = state.to_message().map_err(peek_warn!()) {
-            self.sink
-                .send(message)
-                .await
-                .map_err(crate::deps::holodeck_core::Error::from)
-                .map_err(peek_warn!("could not send simulation state"))?;
+        match self.encode(message) {
+            Some(encoded) => self.send_encoded(Arc::new(encoded)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Check the client is still there, it answers with a pong.
+    pub async fn ping(&mut self) -> Result<()> {
+        self.sink
+            .send(Message::Ping(Vec::new()))
+            .await
+            .map_err(crate::deps::holodeck_core::Error::from)
+    }
+
+    /// Say goodbye to the client, it is not sent anything after this.
+    pub async fn close(
+        &mut self,
+        code: CloseCode,
+        reason: &str,
+    ) -> Result<()> {
+        self.sink
+            .send(close_message(code, reason))
+            .await
+            .map_err(crate::deps::holodeck_core::Error::from)
+    }
+
+    /// The broadcast state narrowed down to what the client last reported it wants, `None` when
+    /// the client is sent all of it.
+    fn filtered(
+        &mut self,
+        broadcast: &Broadcast,
+    ) -> Option<SimulationState> {
+        let state = match &broadcast.message {
+            HolodeckMessage::State(state) => state,
+            _ => return None,
+        };
+        let reported = mem::take(
+            &mut *self
+                .reported
+                .lock()
+                .expect("could not lock the client's interest"),
+        );
+        if let Some(interest) = reported.interest {
+            self.filter.set_interest(interest);
+        }
+        if let Some(subscription) = reported.subscription {
+            self.filter.set_subscription(subscription);
+        }
+        if !self.filter.narrowed() {
+            return None;
+        }
+
+        let filter = &mut self.filter;
+        // only an interest in part of the world is worth indexing it for
+        let index = filter.interest().map(|_| broadcast.index(|| filter.index(state)));
+        filter.filter(state, index.as_deref())
+    }
+
+    fn settings(&self) -> FrameSettings {
+        FrameSettings {
+            codec:       self.codec,
+            positions:   self.positions,
+            compression: self.compression,
+            threshold:   self.threshold,
+        }
+    }
+
+    /// Encode and compress `message` the way this client expects it, `None` when it cannot be
+    /// encoded, which is not worth dropping the client over.
+    fn encode(
+        &self,
+        message: &HolodeckMessage,
+    ) -> Option<Encoded> {
+        let codec = self.codec;
+        let started = Instant::now();
+        let encoded = self.positions.scope(|| codec.encode(message));
+        let frame = encoded.map_err(peek_warn!()).ok()?;
+
+        let uncompressed = frame.len();
+        let frame = match self.compression {
+            Some(compression) => {
+                compression
+                    .pack(frame, self.threshold)
+                    .map_err(peek_warn!("could not compress frame"))
+                    .ok()?
+            }
+            None => frame,
+        };
+        self.metrics.record_encode(started.elapsed());
+
+        Some(Encoded { frame, uncompressed })
+    }
+
+    async fn send_encoded(
+        &mut self,
+        encoded: Arc<Encoded>,
+    ) -> Result<()> {
+        self.stats.record(encoded.uncompressed, encoded.frame.len());
+        self.recorded.record_frame(encoded.frame.len());
+
+        // tungstenite owns the bytes of the frames it sends, only frames shared with other clients
+        // are copied, which is still far cheaper than encoding them again
+        let frame = Arc::try_unwrap(encoded).map_or_else(|shared| shared.frame.clone(), |own| own.frame);
+        self.sink
+            .send(frame.into())
+            .await
+            .map_err(crate::deps::holodeck_core::Error::from)
+            .map_err(peek_warn!("could not send simulation state"))?;
+
+        if self.stats.frames % Self::STATS_INTERVAL == 0 {
+            self.log_stats();
+        }
+
+        Ok(())
+    }
+
+    /// Send a state over UDP. A state which cannot be sent is as good as lost, which is no
+    /// reason to drop the client.
+    async fn send_datagrams(
+        &mut self,
+        peer: SocketAddr,
+        encoded: &Encoded,
+    ) {
+        let udp = match self.udp.as_mut() {
+            Some(udp) => udp,
+            None => return,
+        };
+        let sent = udp
+            .send(peer, encoded.frame.as_bytes())
+            .await
+            .map_err(peek_warn!("could not send simulation state over udp"))
+            .unwrap_or(0);
+
+        self.stats.record(encoded.uncompressed, sent);
+        self.recorded.record_frame(sent);
+        if self.stats.frames % Self::STATS_INTERVAL == 0 {
+            self.log_stats();
+        }
+    }
+
+    fn log_stats(&self) {
+        let stats = self.stats();
+        info!(
+            "client {}: frames={}; uncompressed_bytes={}; sent_bytes={}; ratio={:.3}; dropped={}; \
+             compression={:?}; udp={:?}; interest={:?}",
+            self.connection,
+            stats.frames,
+            stats.uncompressed_bytes,
+            stats.sent_bytes,
+            stats.ratio(),
+            stats.dropped,
+            self.compression,
+            self.udp.as_ref().and_then(UdpSender::peer),
+            self.filter.interest()
+        );
+    }
+}
+
+
+impl Drop for SimulationChannel {
+    fn drop(&mut self) {
+        self.log_stats();
+        self.metrics.disconnected(self.connection);
+    }
+}
+
+
+/// Everything which decides the bytes a message is encoded into for a client.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct FrameSettings {
+    codec:       MessageCodec,
+    positions:   PositionCodec,
+    compression: Option<Compression>,
+    threshold:   usize,
+}
+
+
+/// What a client reported it wants to be sent, waiting for the task sending to it to pick it up.
+/// `None` for anything which did not change since.
+#[derive(Debug, Default)]
+struct Reported {
+    interest:     Option<Option<Interest>>,
+    subscription: Option<Option<Subscription>>,
+}
+
+
+/// A message ready to go out, along with its size before compression.
+struct Encoded {
+    frame:        Frame,
+    uncompressed: usize,
+}
+
+
+/// The frame shared by every client which is sent the same bytes, encoded by the first one to
+/// need it while the others wait for it.
+type Shared = Arc<AsyncMutex<Option<Option<Arc<Encoded>>>>>;
+
+/// The frames shared for a broadcast, by the [`Broadcast::fingerprint`] of the delta encoded,
+/// `None` for the state itself, and by settings.
+type SharedFrames = HashMap<Option<u64>, Vec<(FrameSettings, Shared)>>;
+
+
+/// A state on its way to every client. It and every delta clients are sent for it are encoded
+/// at most once for each combination of [`FrameSettings`] the clients negotiated, no matter how
+/// many clients share them, and indexed at most once for the clients which narrowed down their
+/// interest.
+pub struct Broadcast {
+    message: HolodeckMessage,
+    encoded: Mutex<SharedFrames>,
+    /// keys the fingerprints, so which deltas they mistake for each other cannot be worked out
+    keys:    RandomState,
+    index:   Mutex<Option<Arc<SpatialIndex>>>,
+}
+
+
+impl Broadcast {
+    pub fn new(message: HolodeckMessage) -> Self {
+        Broadcast {
+            message,
+            encoded: Mutex::new(HashMap::new()),
+            keys: RandomState::new(),
+            index: Mutex::new(None),
+        }
+    }
+
+    pub fn message(&self) -> &HolodeckMessage {
+        &self.message
+    }
+
+    /// The spatial index of the state, built by `build` for the first client which needs it.
+    fn index<F>(
+        &self,
+        build: F,
+    ) -> Arc<SpatialIndex>
+    where
+        F: FnOnce() -> SpatialIndex,
+    {
+        self.index
+            .lock()
+            .expect("could not lock the broadcast's index")
+            .get_or_insert_with(|| Arc::new(build()))
+            .clone()
+    }
+
+    /// Tells the deltas sent for the state apart without comparing them in full, once for every
+    /// client sent one.
+    fn fingerprint(
+        &self,
+        delta: &SimulationDelta,
+    ) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        // writing to a hasher cannot fail, and the same delta always hashes the same anyway
+        let _ = bincode::serialize_into(Hashing(&mut hasher), delta);
+        hasher.finish()
+    }
+
+    /// The message, or the delta with the `fingerprint` sent for it, encoded with `settings`, by
+    /// `encode` if no client with the same settings has needed it yet. Clients asking for the
+    /// same frame at the same time wait for the first one rather than encoding it again, clients
+    /// asking for any other frame do not wait at all.
+    async fn encoded<F>(
+        &self,
+        settings: FrameSettings,
+        fingerprint: Option<u64>,
+        encode: F,
+    ) -> Option<Arc<Encoded>>
+    where
+        F: FnOnce() -> Option<Encoded>,
+    {
+        let shared = {
+            let mut encoded = self.encoded.lock().expect("could not lock the encoded broadcast");
+            let encoded = encoded.entry(fingerprint).or_default();
+            match encoded.iter().find(|(cached, _)| *cached == settings) {
+                Some((_, shared)) => shared.clone(),
+                None => {
+                    let shared = Shared::default();
+                    encoded.push((settings, shared.clone()));
+                    shared
+                }
+            }
+        };
+
+        let mut frame = shared.lock().await;
+        frame.get_or_insert_with(|| encode().map(Arc::new)).clone()
+    }
+}
+
+
+/// Feeds whatever is written to it to a hasher.
+struct Hashing<'a, H>(&'a mut H);
+
+
+impl<H: Hasher> io::Write for Hashing<'_, H> {
+    fn write(
+        &mut self,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// The server's end of a client connection. Frames are queued here and sent by a task of the
+/// client's own, so a client on a slow link only ever holds up itself.
+pub struct ClientHandle {
+    connection: ConnectionId,
+    role:       Role,
+    mailbox:    Arc<Mailbox>,
+}
+
+
+impl ClientHandle {
+    /// Serve the `accepted` client until either side of the connection is done. `events` hears
+    /// about the commands the client sends and, once, about the connection closing.
+    pub(crate) fn spawn(
+        accepted: Accepted,
+        config: &Config,
+        metrics: &Arc<Metrics>,
+        events: Sender<ClientEvent>,
+    ) -> ClientHandle {
+        let Accepted {
+            connection,
+            peer,
+            stream,
+            codec,
+            role,
+            welcome,
+            udp,
+            ..
+        } = accepted;
+        let (sink, incoming) = stream.split();
+        let channel = SimulationChannel::new(connection, sink, codec, &welcome, udp, config, metrics);
+        let mailbox = Arc::new(Mailbox {
+            queue:    Mutex::new(VecDeque::with_capacity(config.client_queue_size)),
+            ready:    Notify::new(),
+            capacity: config.client_queue_size.max(1),
+            states:   config.state_delivery,
+            events:   config.event_delivery,
+            dropped:  channel.dropped.clone(),
+            closed:   AtomicBool::new(false),
+            hangup:   Mutex::new(None),
+        });
+
+        tokio::task::spawn(serve_client(
+            channel,
+            peer,
+            incoming,
+            mailbox.clone(),
+            config.heartbeat_interval,
+            config.idle_timeout,
+            events,
+        ));
+
+        ClientHandle {
+            connection,
+            role,
+            mailbox,
+        }
+    }
+
+    pub fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+
+    /// What the client's token allows it to do.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The number of frames dropped so far because the client fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.mailbox.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Queue a [`Broadcast`] state for the client, shared with every other client. Fails once the
+    /// client is gone and should be dropped.
+    pub fn send_state(
+        &self,
+        state: &Arc<Broadcast>,
+    ) -> Result<()> {
+        self.mailbox.push(Outgoing::State(state.clone()))
+    }
+
+    /// Queue an event, like a command result, for the client. Fails once the client is gone and
+    /// should be dropped.
+    pub fn send(
+        &self,
+        message: HolodeckMessage,
+    ) -> Result<()> {
+        self.mailbox.push(Outgoing::Event(message))
+    }
+}
+
+
+impl Drop for ClientHandle {
+    /// Lets the send task finish whatever is still queued and hang up.
+    fn drop(&mut self) {
+        self.mailbox
+            .close(Hangup::new(CloseCode::Away, "the server hung up"));
+    }
+}
+
+
+enum Outgoing {
+    State(Arc<Broadcast>),
+    Event(HolodeckMessage),
+}
+
+
+impl Outgoing {
+    fn is_state(&self) -> bool {
+        matches!(self, Outgoing::State(_))
+    }
+
+    /// What to send instead of this when `missed` is dropped without being sent. Events and
+    /// authoritative states stand on their own, partial states take along what `missed` said.
+    fn after(
+        self,
+        missed: &Outgoing,
+    ) -> Outgoing {
+        match (missed, self) {
+            (Outgoing::State(missed), Outgoing::State(latest)) => {
+                let merged = match (&missed.message, &latest.message) {
+                    (HolodeckMessage::State(missed), HolodeckMessage::State(latest))
+                        if !latest.authoritative =>
+                    {
+                        delta::merge(missed, latest)
+                    }
+                    _ => return Outgoing::State(latest),
+                };
+                Outgoing::State(Arc::new(Broadcast::new(HolodeckMessage::State(merged))))
+            }
+            (_, latest) => latest,
+        }
+    }
+}
+
+
+/// Frames waiting for a client's send task. With [`Delivery::Latest`] a new frame replaces the
+/// ones of its kind still waiting, partial states are merged with the states they replace so the
+/// changes and despawns in them are not lost.
+struct Mailbox {
+    queue:    Mutex<VecDeque<Outgoing>>,
+    ready:    Notify,
+    capacity: usize,
+    states:   Delivery,
+    events:   Delivery,
+    dropped:  Arc<AtomicU64>,
+    closed:   AtomicBool,
+    /// why the mailbox was closed, the first reason wins
+    hangup:   Mutex<Option<Hangup>>,
+}
+
+
+impl Mailbox {
+    fn push(
+        &self,
+        mut outgoing: Outgoing,
+    ) -> Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Disconnected {
+                reason: "the connection was closed".into(),
+            });
         }
 
+        let is_state = outgoing.is_state();
+        let delivery = if is_state { self.states } else { self.events };
+
+        {
+            let mut queue = self.queue.lock().expect("could not lock the client's queue");
+            match delivery {
+                Delivery::Latest => {
+                    let (missed, waiting): (VecDeque<_>, VecDeque<_>) = queue
+                        .drain(..)
+                        .partition(|waiting| waiting.is_state() == is_state);
+                    *queue = waiting;
+                    self.dropped.fetch_add(missed.len() as u64, Ordering::Relaxed);
+                    outgoing = missed.iter().rev().fold(outgoing, Outgoing::after);
+                }
+                Delivery::All if queue.len() >= self.capacity => {
+                    // the client is not going to catch up, nothing queued is worth sending
+                    self.dropped.fetch_add(queue.len() as u64 + 1, Ordering::Relaxed);
+                    queue.clear();
+                    drop(queue);
+                    let reason = format!("the client fell {} frames behind", self.capacity);
+                    self.close(Hangup::new(CloseCode::Policy, reason.clone()));
+                    return Err(Error::Disconnected {
+                        reason: reason.into(),
+                    });
+                }
+                Delivery::All => {}
+            }
+            queue.push_back(outgoing);
+        }
+
+        self.ready.notify();
         Ok(())
     }
+
+    /// The next frame to send, `None` once the mailbox is closed and everything queued was sent.
+    async fn next(&self) -> Option<Outgoing> {
+        loop {
+            let next = self
+                .queue
+                .lock()
+                .expect("could not lock the client's queue")
+                .pop_front();
+            if next.is_some() {
+                return next;
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn close(
+        &self,
+        hangup: Hangup,
+    ) {
+        self.hangup
+            .lock()
+            .expect("could not lock the client's queue")
+            .get_or_insert(hangup);
+        self.closed.store(true, Ordering::Release);
+        self.ready.notify();
+    }
+
+    fn hangup(&self) -> Hangup {
+        self.hangup
+            .lock()
+            .expect("could not lock the client's queue")
+            .clone()
+            .unwrap_or_else(|| Hangup::new(CloseCode::Normal, "closed"))
+    }
 }
 
 
-async fn simulation_message_forwarding(
-    mut stream: SplitStream<WebSocketStream<TcpStream>>,
-    mut forwarder: Sender<SpawnRequest>,
+/// Why a connection ended, along with the close code to send the client when it is still there
+/// to hear it.
+#[derive(Clone, Debug)]
+pub(crate) struct Hangup {
+    pub(crate) code:   Option<CloseCode>,
+    pub(crate) reason: String,
+}
+
+
+impl Hangup {
+    pub(crate) fn new<S: Into<String>>(
+        code: CloseCode,
+        reason: S,
+    ) -> Self {
+        Hangup {
+            code:   Some(code),
+            reason: reason.into(),
+        }
+    }
+
+    /// The connection is gone, there is no one left to say goodbye to.
+    pub(crate) fn lost<S: Into<String>>(reason: S) -> Self {
+        Hangup {
+            code:   None,
+            reason: reason.into(),
+        }
+    }
+
+    /// How to close a connection after failing to decode what the peer sent or finding out it
+    /// does not follow the protocol.
+    pub(crate) fn from_error(err: &Error) -> Self {
+        let code = match err {
+            Error::WebSocket { .. } | Error::SystemIo { .. } => return Hangup::lost(err.to_string()),
+            Error::Codec { .. } | Error::BincodeSerialize { .. } => CloseCode::Invalid,
+            Error::Handshake { .. } => CloseCode::Protocol,
+            _ => CloseCode::Error,
+        };
+        Hangup::new(code, err.to_string())
+    }
+}
+
+
+/// A close frame, with the reason cut short to fit in a control frame.
+pub(crate) fn close_message(
+    code: CloseCode,
+    reason: &str,
+) -> Message {
+    // control frames carry at most 125 bytes, two of which are the code
+    const MAX_REASON: usize = 123;
+    let mut end = reason.len().min(MAX_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason[..end].to_string().into(),
+    }))
+}
+
+
+/// Runs both halves of a connection until either one is done, then tears down the other, says
+/// goodbye to the client if it can and lets the server know the client is gone.
+async fn serve_client(
+    mut channel: SimulationChannel,
+    peer: String,
+    incoming: SplitStream<Frames>,
+    mailbox: Arc<Mailbox>,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    mut events: Sender<ClientEvent>,
 ) {
-    while let Some(msg) = stream.next().await {
-        match msg {
-            Ok(Message::Binary(serialized)) => {
-                match crate::deps::bincode::deserialize::<SpawnRequest>(&serialized) {
-                    Ok(input) => {
-                        info!("recv client input: {:?}", input);
-                        forwarder
-                            .send(input)
-                            .await
-                            .unwrap_or_else(crash_on_err!("failed to forward message: {:?}", input));
+    let connection = channel.connection();
+    let codec = channel.codec;
+    let reported = channel.reported.clone();
+
+    let hangup = tokio::select! {
+        hangup = client_sending(&mut channel, &mailbox, heartbeat_interval) => hangup,
+        hangup = client_receiving(incoming, codec, connection, idle_timeout, reported, events.clone()) => {
+            hangup
+        }
+    };
+    mailbox.close(hangup.clone());
+
+    info!(
+        "client {} disconnected: peer={}; reason={}",
+        connection, peer, hangup.reason
+    );
+    if let Some(code) = hangup.code {
+        let _ = channel.close(code, &hangup.reason).await;
+    }
+
+    // the server may be gone already
+    let _ = events
+        .send(ClientEvent::Closed {
+            connection,
+            reason: hangup.reason,
+        })
+        .await;
+}
+
+
+/// Send whatever is queued for the client and ping it when there is nothing to send. Returns why
+/// it stopped.
+async fn client_sending(
+    channel: &mut SimulationChannel,
+    mailbox: &Mailbox,
+    heartbeat_interval: Duration,
+) -> Hangup {
+    use crate::deps::tokio::time::{
+        interval_at,
+        Instant,
+    };
+    let mut heartbeats = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+
+    loop {
+        tokio::select! {
+            outgoing = mailbox.next() => {
+                let sent = match outgoing {
+                    Some(Outgoing::State(state)) => channel.send_state(&state).await,
+                    Some(Outgoing::Event(event)) => channel.send(&event).await,
+                    None => return mailbox.hangup(),
+                };
+                if let Err(err) = sent {
+                    return Hangup::lost(format!("could not send to the client: {}", err));
+                }
+            }
+            _ = heartbeats.tick() => {
+                if let Err(err) = channel.ping().await {
+                    return Hangup::lost(format!("could not ping the client: {}", err));
+                }
+            }
+        }
+    }
+}
+
+
+/// Forward the client's commands to the server and pass on what it `reported` it wants to be
+/// sent, until the client hangs up, goes quiet for longer than the `idle_timeout` or sends
+/// something it should not have. Returns why it stopped.
+async fn client_receiving(
+    mut stream: SplitStream<Frames>,
+    codec: MessageCodec,
+    connection: ConnectionId,
+    idle_timeout: Duration,
+    reported: Arc<Mutex<Reported>>,
+    mut forwarder: Sender<ClientEvent>,
+) -> Hangup {
+    loop {
+        let msg = match tokio::time::timeout(idle_timeout, stream.next()).await {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(Some(Err(err))) => return Hangup::from_error(&err.into()),
+            Ok(None) => return Hangup::lost("the connection was closed"),
+            Err(_) => {
+                let reason = format!("nothing heard from the client for {:?}", idle_timeout);
+                return Hangup::new(CloseCode::Policy, reason);
+            }
+        };
+
+        let serialized = match msg {
+            Message::Binary(bytes) => bytes,
+            Message::Text(text) => text.into_bytes(),
+            // anything counts as a sign of life, tungstenite answers pings by itself
+            Message::Ping(_) | Message::Pong(_) => continue,
+            // tungstenite answers the close frame by itself as well
+            Message::Close(frame) => {
+                return Hangup::lost(format!("the client closed the connection: {:?}", frame))
+            }
+        };
+
+        match codec.decode::<HolodeckMessage>(&serialized) {
+            Ok(HolodeckMessage::Command(command)) => {
+                info!("recv client command: connection={}; {:?}", connection, command);
+                if forwarder
+                    .send(ClientEvent::Command { connection, command })
+                    .await
+                    .is_err()
+                {
+                    return Hangup::new(CloseCode::Away, "the server is shutting down");
+                }
+            }
+            Ok(HolodeckMessage::Interest(Some(interest)))
+                if !(interest.radius >= 0.0 && interest.radius.is_finite()) =>
+            {
+                let reason = format!("invalid interest radius: {}", interest.radius);
+                return Hangup::new(CloseCode::Protocol, reason);
+            }
+            Ok(HolodeckMessage::Interest(interest)) => {
+                debug!("client interest: connection={}; {:?}", connection, interest);
+                reported
+                    .lock()
+                    .expect("could not lock the client's interest")
+                    .interest = Some(interest);
+            }
+            Ok(HolodeckMessage::Subscription(subscription)) => {
+                info!(
+                    "client subscription: connection={}; {:?}",
+                    connection, subscription
+                );
+                let mut reported = reported.lock().expect("could not lock the client's interest");
+                reported.subscription = Some(subscription);
+            }
+            Ok(other) => {
+                let reason = format!("expected a command, got: {:?}", other);
+                return Hangup::new(CloseCode::Protocol, reason);
+            }
+            Err(err) => {
+                warn!(
+                    "could not decode a frame: connection={}; bytes={}",
+                    connection,
+                    serialized.len()
+                );
+                return Hangup::from_error(&err);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        Bounds,
+        PositionEncoding,
+    };
+
+    fn mailbox(
+        capacity: usize,
+        states: Delivery,
+    ) -> Mailbox {
+        Mailbox {
+            queue: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+            capacity,
+            states,
+            events: Delivery::All,
+            dropped: Arc::new(AtomicU64::new(0)),
+            closed: AtomicBool::new(false),
+            hangup: Mutex::new(None),
+        }
+    }
+
+
+    fn state(
+        tick: u64,
+        authoritative: bool,
+        entities: &[(u64, f32)],
+        removed: &[u64],
+    ) -> Outgoing {
+        Outgoing::State(Arc::new(Broadcast::new(HolodeckMessage::State(
+            SimulationState {
+                tick,
+                entity_count: entities.len() as u64,
+                entities: entities
+                    .iter()
+                    .map(|&(id, x)| Entity::new(id, 1, x, 0.0, 0.0))
+                    .collect(),
+                removed: removed.to_vec(),
+                authoritative,
+            },
+        ))))
+    }
+
+
+    /// A queued state as `(tick, authoritative, entities by id, removed)`.
+    type Queued = (u64, bool, Vec<(u64, f32)>, Vec<u64>);
+
+
+    fn queued(mailbox: &Mailbox) -> Vec<Queued> {
+        let queue = mailbox.queue.lock().unwrap();
+        queue
+            .iter()
+            .filter_map(|outgoing| {
+                match outgoing {
+                    Outgoing::State(broadcast) => {
+                        match &broadcast.message {
+                            HolodeckMessage::State(state) => {
+                                let mut entities: Vec<_> =
+                                    state.entities.iter().map(|e| (e.id, e.position()[0])).collect();
+                                entities.sort_by_key(|&(id, _)| id);
+                                let mut removed = state.removed.clone();
+                                removed.sort_unstable();
+                                Some((state.tick, state.authoritative, entities, removed))
+                            }
+                            _ => None,
+                        }
                     }
-                    Err(e) => error!("unable to decode message: {:?}, {:?}", serialized, e,),
+                    Outgoing::Event(_) => None,
                 }
+            })
+            .collect()
+    }
+
+
+    #[test]
+    fn broadcasts_are_encoded_once_for_every_frame() {
+        let settings = |codec| {
+            FrameSettings {
+                codec,
+                positions: PositionCodec::new(PositionEncoding::F32, Bounds::square(100.0)),
+                compression: None,
+                threshold: 0,
             }
-            m => info!("Got invalid message kind: {:?}", m),
         };
+        let delta = |tick| {
+            SimulationDelta {
+                tick,
+                base_tick: 1,
+                moved: vec![Entity::new(1, 1, tick as f32, 0.0, 0.0)],
+                ..SimulationDelta::default()
+            }
+        };
+        let broadcast = Broadcast::new(HolodeckMessage::State(SimulationState::default()));
+        let encodes = AtomicU64::new(0);
+        let encode = || {
+            let n = encodes.fetch_add(1, Ordering::Relaxed);
+            Some(Encoded {
+                frame:        Frame::Binary(vec![n as u8]),
+                uncompressed: 1,
+            })
+        };
+
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        runtime.block_on(async {
+            let bincode = settings(MessageCodec::Bincode);
+            let full = broadcast.encoded(bincode, None, encode).await.unwrap();
+            assert!(Arc::ptr_eq(
+                &full,
+                &broadcast.encoded(bincode, None, encode).await.unwrap()
+            ));
+
+            // deltas are shared by the clients sent the same one, which is not the full state
+            let second = Some(broadcast.fingerprint(&delta(2)));
+            let shared = broadcast.encoded(bincode, second, encode).await.unwrap();
+            assert!(!Arc::ptr_eq(&full, &shared));
+            let same = Some(broadcast.fingerprint(&delta(2)));
+            assert!(Arc::ptr_eq(
+                &shared,
+                &broadcast.encoded(bincode, same, encode).await.unwrap()
+            ));
+            assert_eq!(encodes.load(Ordering::Relaxed), 2);
+
+            assert_ne!(second, Some(broadcast.fingerprint(&delta(3))));
+            broadcast
+                .encoded(bincode, Some(broadcast.fingerprint(&delta(3))), encode)
+                .await
+                .unwrap();
+            broadcast
+                .encoded(settings(MessageCodec::Json), second, encode)
+                .await
+                .unwrap();
+            broadcast
+                .encoded(settings(MessageCodec::Json), None, encode)
+                .await
+                .unwrap();
+            assert_eq!(encodes.load(Ordering::Relaxed), 5);
+        });
+    }
+
+
+    #[test]
+    fn latest_full_states_replace_each_other() {
+        let mailbox = mailbox(4, Delivery::Latest);
+        mailbox.push(state(1, true, &[(1, 1.0), (2, 2.0)], &[])).unwrap();
+        mailbox.push(state(2, true, &[(1, 1.5)], &[])).unwrap();
+        mailbox.push(state(3, true, &[(1, 2.0), (3, 3.0)], &[])).unwrap();
+
+        assert_eq!(
+            queued(&mailbox),
+            vec![(3, true, vec![(1, 2.0), (3, 3.0)], vec![])]
+        );
+        assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 2);
+    }
+
+
+    #[test]
+    fn latest_partial_states_keep_what_they_replace() {
+        let mailbox = mailbox(4, Delivery::Latest);
+        mailbox
+            .push(state(1, false, &[(1, 1.0), (2, 2.0)], &[9]))
+            .unwrap();
+        mailbox
+            .push(state(2, false, &[(1, 1.5), (3, 3.0)], &[2]))
+            .unwrap();
+        mailbox.push(state(3, false, &[(9, 9.0)], &[3])).unwrap();
+
+        // 9 was despawned and spawned again, 2 and 3 were spawned and despawned
+        assert_eq!(
+            queued(&mailbox),
+            vec![(3, false, vec![(1, 1.5), (9, 9.0)], vec![2, 3])]
+        );
+        assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 2);
+    }
+
+
+    #[test]
+    fn latest_partial_states_after_a_full_one_are_full() {
+        let mailbox = mailbox(4, Delivery::Latest);
+        mailbox.push(state(1, true, &[(1, 1.0), (2, 2.0)], &[])).unwrap();
+        mailbox.push(state(2, false, &[(1, 1.5)], &[2])).unwrap();
+        assert_eq!(queued(&mailbox), vec![(2, true, vec![(1, 1.5)], vec![2])]);
+
+        // a full state does not need anything it replaces
+        mailbox.push(state(3, true, &[(3, 3.0)], &[])).unwrap();
+        assert_eq!(queued(&mailbox), vec![(3, true, vec![(3, 3.0)], vec![])]);
+        assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 2);
+    }
+
+
+    #[test]
+    fn all_keeps_every_state_until_the_queue_is_full() {
+        for &authoritative in &[true, false] {
+            let mailbox = mailbox(3, Delivery::All);
+            for tick in 1..=3 {
+                mailbox
+                    .push(state(tick, authoritative, &[(tick, tick as f32)], &[]))
+                    .unwrap();
+            }
+            let ticks: Vec<u64> = queued(&mailbox).iter().map(|&(tick, ..)| tick).collect();
+            assert_eq!(ticks, vec![1, 2, 3]);
+            assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 0);
+
+            assert!(matches!(
+                mailbox.push(state(4, authoritative, &[], &[])),
+                Err(Error::Disconnected { .. })
+            ));
+            assert!(queued(&mailbox).is_empty());
+            assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 4);
+            assert!(mailbox.push(state(5, authoritative, &[], &[])).is_err());
+        }
     }
 }
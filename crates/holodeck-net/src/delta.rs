@@ -0,0 +1,556 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
+};
+
+use crate::message::{
+    Entity,
+    SimulationDelta,
+    SimulationState,
+};
+
+
+/// Tracks what a single client believes about the simulation and turns each new state into the
+/// smallest update that brings the client up to date, falling back to a full state (a keyframe)
+/// every `keyframe_interval` updates.
+pub struct DeltaEncoder {
+    keyframe_interval: u64,
+    threshold_sq:      f32,
+    since_keyframe:    u64,
+    base_tick:         Option<u64>,
+    generation:        u64,
+    /// the last entity sent to the client by id, and the generation it was last seen in
+    known:             HashMap<u64, (u64, Entity)>,
+    /// the latest change to an entity which was not due yet, by id
+    held_back:         HashMap<u64, Entity>,
+}
+
+
+impl DeltaEncoder {
+    pub fn new(
+        keyframe_interval: u64,
+        threshold: f32,
+    ) -> Self {
+        DeltaEncoder {
+            keyframe_interval,
+            threshold_sq: threshold * threshold,
+            since_keyframe: 0,
+            base_tick: None,
+            generation: 0,
+            known: HashMap::new(),
+            held_back: HashMap::new(),
+        }
+    }
+
+    /// Returns the delta between the last state sent to the client and `state`, or `None` when
+    /// the client should be sent `state` in full.
+    pub fn encode(
+        &mut self,
+        state: &SimulationState,
+    ) -> Option<SimulationDelta> {
+        self.encode_with(state, |_| true)
+    }
+
+    /// Like [`DeltaEncoder::encode`], but the changes to an entity are held back until it is
+    /// `due`, they are sent in a later delta or keyframe instead. A held back change is sent once
+    /// its entity is due, whether or not the entity is in the state it is due in. New and removed
+    /// entities are always sent right away.
+    pub fn encode_with<F>(
+        &mut self,
+        state: &SimulationState,
+        due: F,
+    ) -> Option<SimulationDelta>
+    where
+        F: Fn(&Entity) -> bool,
+    {
+        self.generation += 1;
+        let generation = self.generation;
+
+        let base_tick = match self.base_tick {
+            Some(base_tick) if self.since_keyframe < self.keyframe_interval => base_tick,
+            _ => {
+                self.keyframe(state);
+                return None;
+            }
+        };
+
+        let mut delta = SimulationDelta {
+            tick: state.tick,
+            base_tick,
+            entity_count: state.entity_count,
+            ..Default::default()
+        };
+
+        for entity in state.entities.iter() {
+            match self.known.get_mut(&entity.id) {
+                Some((seen, known)) => {
+                    *seen = generation;
+                    if !Self::changed(known, entity, self.threshold_sq) {
+                        self.held_back.remove(&entity.id);
+                    } else if due(entity) {
+                        self.held_back.remove(&entity.id);
+                        *known = *entity;
+                        delta.moved.push(*entity);
+                    } else {
+                        self.held_back.insert(entity.id, *entity);
+                    }
+                }
+                None => {
+                    self.known.insert(entity.id, (generation, *entity));
+                    delta.created.push(*entity);
+                }
+            }
+        }
+
+        if state.authoritative {
+            let removed = &mut delta.removed;
+            self.known.retain(|id, (seen, _)| {
+                if *seen != generation {
+                    removed.push(*id);
+                }
+                *seen == generation
+            });
+        } else {
+            for id in state.removed.iter().copied() {
+                if self.known.remove(&id).is_some() {
+                    delta.removed.push(id);
+                }
+            }
+        }
+
+        // the entities left out of a partial state which have a change held back for them
+        let (known, moved) = (&mut self.known, &mut delta.moved);
+        self.held_back.retain(|id, entity| {
+            match known.get_mut(id) {
+                Some((seen, sent)) if *seen != generation && due(entity) => {
+                    *sent = *entity;
+                    moved.push(*entity);
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            }
+        });
+
+        self.base_tick = Some(state.tick);
+        self.since_keyframe += 1;
+        Some(delta)
+    }
+
+    fn keyframe(
+        &mut self,
+        state: &SimulationState,
+    ) {
+        let generation = self.generation;
+        if state.authoritative {
+            self.known.clear();
+            self.held_back.clear();
+        }
+        self.known
+            .extend(state.entities.iter().map(|e| (e.id, (generation, *e))));
+        for id in state.entities.iter().map(|e| &e.id).chain(state.removed.iter()) {
+            self.held_back.remove(id);
+        }
+        for id in state.removed.iter() {
+            self.known.remove(id);
+        }
+        self.base_tick = Some(state.tick);
+        self.since_keyframe = 0;
+    }
+
+    fn changed(
+        known: &Entity,
+        entity: &Entity,
+        threshold_sq: f32,
+    ) -> bool {
+        let dx = entity.x - known.x;
+        let dy = entity.y - known.y;
+        let dz = entity.z - known.z;
+
+        known.tag != entity.tag
+            || known.kind != entity.kind
+            || known.scale != entity.scale
+            || known.rotation != entity.rotation
+            || known.acceleration != entity.acceleration
+            || Self::diverged(known.velocity, entity.velocity, threshold_sq)
+            || (dx * dx + dy * dy + dz * dz) > threshold_sq
+    }
+
+    fn diverged(
+        known: Option<[f32; 3]>,
+        value: Option<[f32; 3]>,
+        threshold_sq: f32,
+    ) -> bool {
+        match (known, value) {
+            (Some(a), Some(b)) => {
+                let (dx, dy, dz) = (b[0] - a[0], b[1] - a[1], b[2] - a[2]);
+                (dx * dx + dy * dy + dz * dz) > threshold_sq
+            }
+            (None, None) => false,
+            _ => true,
+        }
+    }
+}
+
+
+/// Despawns entities that a simulation sending non-authoritative states stopped reporting,
+/// by adding their ids to the `removed` list of the next state.
+pub struct StaleEntities {
+    timeout:   u64,
+    last_seen: HashMap<u64, u64>,
+}
+
+
+impl StaleEntities {
+    pub fn new(timeout: u64) -> Self {
+        StaleEntities {
+            timeout,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn reap(
+        &mut self,
+        state: &mut SimulationState,
+    ) {
+        if state.authoritative {
+            // absence from an authoritative state already is a despawn
+            self.last_seen.clear();
+            return;
+        }
+
+        let tick = state.tick;
+        for entity in state.entities.iter() {
+            self.last_seen.insert(entity.id, tick);
+        }
+        for id in state.removed.iter() {
+            self.last_seen.remove(id);
+        }
+
+        let timeout = self.timeout;
+        let removed = &mut state.removed;
+        self.last_seen.retain(|id, seen| {
+            let alive = tick.saturating_sub(*seen) <= timeout;
+            if !alive {
+                removed.push(*id);
+            }
+            alive
+        });
+    }
+}
+
+
+/// The state which brings a client up to date on its own when it never gets `missed`, only the
+/// `latest` state after it. An authoritative `latest` already does. A partial one is sent along
+/// with what it leaves out: the entities in `missed` it does not report again and despawns.
+pub fn merge(
+    missed: &SimulationState,
+    latest: &SimulationState,
+) -> SimulationState {
+    if latest.authoritative {
+        return latest.clone();
+    }
+
+    let reported: HashSet<u64> = latest.entities.iter().map(|e| e.id).collect();
+    let mut entities: Vec<Entity> = missed
+        .entities
+        .iter()
+        .filter(|e| !reported.contains(&e.id) && !latest.removed.contains(&e.id))
+        .copied()
+        .collect();
+    entities.extend(latest.entities.iter().copied());
+
+    // an entity despawned in `missed` and reported again since is back
+    let mut removed: Vec<u64> = missed
+        .removed
+        .iter()
+        .copied()
+        .filter(|id| !reported.contains(id) && !latest.removed.contains(id))
+        .collect();
+    removed.extend(latest.removed.iter().copied());
+
+    SimulationState {
+        tick: latest.tick,
+        entity_count: latest.entity_count,
+        entities,
+        removed,
+        authoritative: missed.authoritative,
+    }
+}
+
+
+/// The world as the states broadcast so far describe it, for the clients which connect after
+/// them. Partial states only say what changed, none of them on its own is the world.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// of the last state applied, `None` before the first
+    tick:         Option<u64>,
+    entity_count: u64,
+    entities:     BTreeMap<u64, Entity>,
+    /// whether an authoritative state was applied, before one is the entities are only those
+    /// reported since the server started
+    complete:     bool,
+}
+
+
+impl Snapshot {
+    pub fn apply(
+        &mut self,
+        state: &SimulationState,
+    ) {
+        if state.authoritative {
+            self.entities.clear();
+            self.complete = true;
+        }
+        self.entities
+            .extend(state.entities.iter().map(|entity| (entity.id, *entity)));
+        for id in state.removed.iter() {
+            self.entities.remove(id);
+        }
+        self.tick = Some(state.tick);
+        self.entity_count = state.entity_count;
+    }
+
+    /// The state bringing a client which has seen nothing yet up to date, `None` until a state
+    /// was applied.
+    pub fn state(&self) -> Option<SimulationState> {
+        Some(SimulationState {
+            tick:          self.tick?,
+            entity_count:  self.entity_count,
+            entities:      self.entities.values().copied().collect(),
+            removed:       Vec::new(),
+            authoritative: self.complete,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a client knows about the world, by id.
+    #[derive(Default)]
+    struct Client {
+        entities: BTreeMap<u64, [f32; 3]>,
+        tick:     u64,
+    }
+
+
+    impl Client {
+        fn receive(
+            &mut self,
+            state: &SimulationState,
+            delta: Option<SimulationDelta>,
+        ) {
+            match delta {
+                Some(delta) => {
+                    assert_eq!(delta.base_tick, self.tick, "delta applied to the wrong base");
+                    for entity in delta.created.iter().chain(delta.moved.iter()) {
+                        self.entities.insert(entity.id, entity.position());
+                    }
+                    for id in delta.removed.iter() {
+                        self.entities.remove(id);
+                    }
+                    self.tick = delta.tick;
+                }
+                None => {
+                    if state.authoritative {
+                        self.entities.clear();
+                    }
+                    self.entities
+                        .extend(state.entities.iter().map(|e| (e.id, e.position())));
+                    for id in state.removed.iter() {
+                        self.entities.remove(id);
+                    }
+                    self.tick = state.tick;
+                }
+            }
+        }
+    }
+
+
+    fn state(
+        tick: u64,
+        entities: &[(u64, f32)],
+    ) -> SimulationState {
+        SimulationState {
+            tick,
+            entity_count: entities.len() as u64,
+            entities: entities
+                .iter()
+                .map(|&(id, x)| Entity::new(id, 1, x, 0.0, 0.0))
+                .collect(),
+            removed: Vec::new(),
+            authoritative: true,
+        }
+    }
+
+
+    #[test]
+    fn deltas_bring_the_client_up_to_date() {
+        let mut encoder = DeltaEncoder::new(4, 0.0);
+        let mut client = Client::default();
+        let mut keyframes = Vec::new();
+
+        for tick in 1..=12u64 {
+            // entity 1 moves every tick, 2 never does, 3 exists for a while and one is spawned
+            // every third tick
+            let mut entities = vec![(1, tick as f32), (2, 5.0)];
+            if (3..8).contains(&tick) {
+                entities.push((3, -1.0));
+            }
+            entities.extend((10..10 + tick / 3).map(|id| (id, id as f32)));
+            let state = state(tick, &entities);
+
+            let delta = encoder.encode(&state);
+            if delta.is_none() {
+                keyframes.push(tick);
+            }
+            client.receive(&state, delta);
+
+            let expected: BTreeMap<_, _> = state.entities.iter().map(|e| (e.id, e.position())).collect();
+            assert_eq!(client.entities, expected, "tick {}", tick);
+        }
+
+        assert_eq!(keyframes, vec![1, 6, 11]);
+    }
+
+
+    #[test]
+    fn only_changes_are_sent() {
+        let mut encoder = DeltaEncoder::new(100, 0.5);
+        assert!(encoder.encode(&state(1, &[(1, 0.0), (2, 0.0)])).is_none());
+
+        let delta = encoder
+            .encode(&state(2, &[(1, 0.25), (2, 1.0), (3, 0.0)]))
+            .unwrap();
+        assert_eq!(delta.base_tick, 1);
+        assert_eq!(delta.created.iter().map(|e| e.id).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(delta.moved.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2]);
+        assert!(delta.removed.is_empty());
+
+        // entity 1 crept past the threshold since it was last sent, 2 is gone
+        let delta = encoder.encode(&state(3, &[(1, 0.75), (3, 0.0)])).unwrap();
+        assert!(delta.created.is_empty());
+        assert_eq!(delta.moved.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(delta.removed, vec![2]);
+    }
+
+
+    #[test]
+    fn partial_states_only_remove_what_they_say() {
+        let mut encoder = DeltaEncoder::new(100, 0.0);
+        let mut client = Client::default();
+        let mut partial = |tick, entities: &[(u64, f32)], removed: Vec<u64>| {
+            let state = SimulationState {
+                removed,
+                authoritative: false,
+                ..state(tick, entities)
+            };
+            let delta = encoder.encode(&state);
+            client.receive(&state, delta);
+            client.entities.clone()
+        };
+
+        partial(1, &[(1, 0.0), (2, 0.0)], vec![]);
+        let seen = partial(2, &[(1, 1.0)], vec![]);
+        assert_eq!(
+            seen,
+            vec![(1, [1.0, 0.0, 0.0]), (2, [0.0; 3])].into_iter().collect()
+        );
+        let seen = partial(3, &[], vec![1]);
+        assert_eq!(seen, vec![(2, [0.0; 3])].into_iter().collect());
+    }
+
+    #[test]
+    fn held_back_changes_are_sent_once_due() {
+        let mut encoder = DeltaEncoder::new(100, 0.0);
+        let mut client = Client::default();
+        let mut partial = |tick, entities: &[(u64, f32)], due: bool| {
+            let state = SimulationState {
+                authoritative: false,
+                ..state(tick, entities)
+            };
+            let delta = encoder.encode_with(&state, |_| due);
+            client.receive(&state, delta);
+            client.entities.clone()
+        };
+
+        partial(1, &[(1, 0.0), (2, 0.0)], true);
+        // entity 1 moves while it is not due, and is never reported again
+        let seen = partial(2, &[(1, 1.0)], false);
+        assert_eq!(seen, vec![(1, [0.0; 3]), (2, [0.0; 3])].into_iter().collect());
+        let seen = partial(3, &[(2, 2.0)], false);
+        assert_eq!(seen, vec![(1, [0.0; 3]), (2, [0.0; 3])].into_iter().collect());
+        let seen = partial(4, &[], true);
+        assert_eq!(
+            seen,
+            vec![(1, [1.0, 0.0, 0.0]), (2, [2.0, 0.0, 0.0])]
+                .into_iter()
+                .collect()
+        );
+        // nothing is held back any more
+        let delta = encoder.encode_with(
+            &SimulationState {
+                authoritative: false,
+                ..state(5, &[])
+            },
+            |_| true,
+        );
+        assert!(delta.unwrap().moved.is_empty());
+    }
+
+
+    #[test]
+    fn snapshots_catch_up_clients_joining_after_partial_states() {
+        let mut snapshot = Snapshot::default();
+        assert!(snapshot.state().is_none());
+
+        let mut early = Client::default();
+        let mut states = vec![state(1, &[(1, 0.0), (2, 0.0), (3, 0.0)])];
+        for (tick, entities, removed) in [
+            (2, vec![(1, 1.0)], vec![]),
+            (3, vec![(4, 4.0)], vec![2]),
+            (4, vec![(1, 2.0), (2, 2.0)], vec![3]),
+            (5, vec![], vec![4]),
+        ] {
+            states.push(SimulationState {
+                removed,
+                authoritative: false,
+                ..state(tick, &entities)
+            });
+        }
+        for state in states.iter() {
+            snapshot.apply(state);
+            early.receive(state, None);
+        }
+
+        let joined = snapshot.state().unwrap();
+        assert!(joined.authoritative);
+        assert_eq!(joined.tick, 5);
+        let mut late = Client::default();
+        late.receive(&joined, None);
+        assert_eq!(late.entities, early.entities);
+        assert_eq!(
+            late.entities,
+            vec![(1, [2.0, 0.0, 0.0]), (2, [2.0, 0.0, 0.0])]
+                .into_iter()
+                .collect()
+        );
+    }
+
+
+    #[test]
+    fn snapshots_without_a_full_state_are_partial() {
+        let mut snapshot = Snapshot::default();
+        snapshot.apply(&SimulationState {
+            authoritative: false,
+            ..state(1, &[(1, 0.0)])
+        });
+        let joined = snapshot.state().unwrap();
+        assert!(!joined.authoritative);
+        assert_eq!(joined.entities.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1]);
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+
+use crate::message::{
+    Entity,
+    Interest,
+    SimulationState,
+    Subscription,
+    UpAxis,
+};
+
+
+/// The entities of a state bucketed into square cells on the ground plane, so the ones around a
+/// viewer are found without looking at every entity. Built once per state and shared by every
+/// client the state is sent to.
+pub(crate) struct SpatialIndex {
+    axes:      (usize, usize),
+    cell_size: f32,
+    /// indices into the state's entities, by cell
+    cells:     HashMap<(i64, i64), Vec<usize>>,
+}
+
+
+impl SpatialIndex {
+    pub(crate) fn new(
+        entities: &[Entity],
+        up_axis: UpAxis,
+        cell_size: f32,
+    ) -> Self {
+        let mut index = SpatialIndex {
+            axes:      up_axis.ground_axes(),
+            cell_size: cell_size.max(f32::EPSILON),
+            cells:     HashMap::new(),
+        };
+        for (i, entity) in entities.iter().enumerate() {
+            let cell = index.cell(entity.position());
+            index.cells.entry(cell).or_default().push(i);
+        }
+        index
+    }
+
+    fn cell(
+        &self,
+        position: [f32; 3],
+    ) -> (i64, i64) {
+        let (a, b) = self.axes;
+        (
+            (position[a] / self.cell_size).floor() as i64,
+            (position[b] / self.cell_size).floor() as i64,
+        )
+    }
+
+    /// The indices of the entities within `radius` of `center` on the ground plane, in the order
+    /// they appear in the state, along with a few from the corners of the cells around it which
+    /// are further away.
+    pub(crate) fn near(
+        &self,
+        center: [f32; 3],
+        radius: f32,
+    ) -> Vec<usize> {
+        let (a, b) = self.axes;
+        let (min_a, min_b) = {
+            let mut corner = center;
+            corner[a] -= radius;
+            corner[b] -= radius;
+            self.cell(corner)
+        };
+        let (max_a, max_b) = {
+            let mut corner = center;
+            corner[a] += radius;
+            corner[b] += radius;
+            self.cell(corner)
+        };
+
+        let mut near = Vec::new();
+        // a radius covering more cells than are occupied is cheaper to answer by going through
+        // the occupied ones
+        let span = 2.0 * radius / self.cell_size + 2.0;
+        if span * span > self.cells.len() as f32 {
+            for ((u, v), entities) in self.cells.iter() {
+                if (min_a..=max_a).contains(u) && (min_b..=max_b).contains(v) {
+                    near.extend_from_slice(entities);
+                }
+            }
+        } else {
+            for u in min_a..=max_a {
+                for v in min_b..=max_b {
+                    if let Some(entities) = self.cells.get(&(u, v)) {
+                        near.extend_from_slice(entities);
+                    }
+                }
+            }
+        }
+
+        near.sort_unstable();
+        near
+    }
+}
+
+
+/// Narrows the states sent to a client down to the entities within its [`Interest`] which match
+/// its [`Subscription`]. Entities enter once they come within the radius but only leave once they
+/// are further away than the radius grown by the hysteresis, so ones on the edge do not flicker
+/// in and out.
+///
+/// Entities leaving an authoritative state are despawned by leaving them out. Other states only
+/// carry the entities that changed, the ones which moved out of view are added to `removed`
+/// instead and come back the next time they are reported within view.
+pub(crate) struct InterestFilter {
+    up_axis:         UpAxis,
+    cell_size:       f32,
+    hysteresis:      f32,
+    interest:        Option<Interest>,
+    subscription:    Option<Subscription>,
+    /// the entities within view the client was sent
+    visible:         HashSet<u64>,
+    /// the client was sent the whole world before narrowing its interest, it may know about any
+    /// entity until the next authoritative state
+    sent_everything: bool,
+    /// entities out of view a client which was sent everything is known not to have, so they
+    /// are only removed once
+    hidden:          HashSet<u64>,
+}
+
+
+impl InterestFilter {
+    pub(crate) fn new(
+        up_axis: UpAxis,
+        cell_size: f32,
+        hysteresis: f32,
+    ) -> Self {
+        InterestFilter {
+            up_axis,
+            cell_size,
+            hysteresis: hysteresis.max(0.0),
+            interest: None,
+            subscription: None,
+            visible: HashSet::new(),
+            sent_everything: true,
+            hidden: HashSet::new(),
+        }
+    }
+
+    /// The part of the world the client is interested in, `None` for all of it.
+    pub(crate) fn interest(&self) -> Option<Interest> {
+        self.interest
+    }
+
+    pub(crate) fn set_interest(
+        &mut self,
+        interest: Option<Interest>,
+    ) {
+        let narrowed = self.narrowed();
+        self.interest = interest;
+        self.narrowing(narrowed);
+    }
+
+    pub(crate) fn set_subscription(
+        &mut self,
+        subscription: Option<Subscription>,
+    ) {
+        let narrowed = self.narrowed();
+        self.subscription = subscription;
+        self.narrowing(narrowed);
+    }
+
+    /// Whether the client is sent less than the whole world.
+    pub(crate) fn narrowed(&self) -> bool {
+        self.interest.is_some() || self.subscription.is_some()
+    }
+
+    /// A client which was sent everything may know about any entity.
+    fn narrowing(
+        &mut self,
+        narrowed: bool,
+    ) {
+        if !narrowed && self.narrowed() {
+            self.visible.clear();
+            self.hidden.clear();
+            self.sent_everything = true;
+        }
+    }
+
+    /// Index the entities of `state` the way the filter looks them up.
+    pub(crate) fn index(
+        &self,
+        state: &SimulationState,
+    ) -> SpatialIndex {
+        SpatialIndex::new(&state.entities, self.up_axis, self.cell_size)
+    }
+
+    /// The part of `state` the client is interested in, `None` when that is all of it. `index`
+    /// has to be the index of `state`, every entity is looked at without one.
+    pub(crate) fn filter(
+        &mut self,
+        state: &SimulationState,
+        index: Option<&SpatialIndex>,
+    ) -> Option<SimulationState> {
+        if !self.narrowed() {
+            return None;
+        }
+
+        let candidates = match (self.interest, index) {
+            (Some(interest), Some(index)) => index.near(interest.center, self.leave_radius(&interest)),
+            _ => (0..state.entities.len()).collect(),
+        };
+        let entities = candidates
+            .into_iter()
+            .map(|i| state.entities[i])
+            .filter(|entity| self.keeps(entity))
+            .collect::<Vec<Entity>>();
+        let kept = entities.iter().map(|e| e.id).collect::<HashSet<u64>>();
+
+        let removed = if state.authoritative {
+            let removed = state
+                .removed
+                .iter()
+                .copied()
+                .filter(|id| self.visible.contains(id))
+                .collect();
+            self.visible = kept;
+            self.hidden.clear();
+            self.sent_everything = false;
+            removed
+        } else {
+            let mut removed = Vec::new();
+            for id in state.removed.iter().copied() {
+                let hidden = self.hidden.remove(&id);
+                if self.visible.remove(&id) || (self.sent_everything && !hidden) {
+                    removed.push(id);
+                }
+            }
+            for entity in state.entities.iter() {
+                if kept.contains(&entity.id) {
+                    self.hidden.remove(&entity.id);
+                } else if self.visible.remove(&entity.id)
+                    || (self.sent_everything && !self.hidden.contains(&entity.id))
+                {
+                    removed.push(entity.id);
+                    if self.sent_everything {
+                        self.hidden.insert(entity.id);
+                    }
+                }
+            }
+            self.visible.extend(kept);
+            removed
+        };
+
+        Some(SimulationState {
+            tick: state.tick,
+            entity_count: state.entity_count,
+            entities,
+            removed,
+            authoritative: state.authoritative,
+        })
+    }
+
+    fn leave_radius(
+        &self,
+        interest: &Interest,
+    ) -> f32 {
+        interest.radius * (1.0 + self.hysteresis)
+    }
+
+    /// Whether the client is sent `entity`, given what it was sent before.
+    fn keeps(
+        &self,
+        entity: &Entity,
+    ) -> bool {
+        if let Some(subscription) = self.subscription.as_ref() {
+            if !subscription.matches(entity) {
+                return false;
+            }
+        }
+
+        match self.interest.as_ref() {
+            Some(interest) => {
+                let distance_sq = interest.distance_sq(entity.position());
+                let leave = self.leave_radius(interest);
+                distance_sq <= interest.radius * interest.radius
+                    || (distance_sq <= leave * leave && self.visible.contains(&entity.id))
+            }
+            None => true,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        IdRange,
+        TagSet,
+    };
+
+    fn state(
+        authoritative: bool,
+        entities: &[(u64, f32)],
+    ) -> SimulationState {
+        SimulationState {
+            tick: 0,
+            entity_count: entities.len() as u64,
+            entities: entities
+                .iter()
+                .map(|&(id, x)| Entity::new(id, 0, x, 0.0, 0.0))
+                .collect(),
+            removed: Vec::new(),
+            authoritative,
+        }
+    }
+
+    fn filter(
+        filter: &mut InterestFilter,
+        state: &SimulationState,
+    ) -> (Vec<u64>, Vec<u64>) {
+        let index = filter.index(state);
+        let filtered = filter
+            .filter(state, Some(&index))
+            .expect("the filter has an interest");
+        (filtered.entities.iter().map(|e| e.id).collect(), filtered.removed)
+    }
+
+    #[test]
+    fn the_index_finds_everything_within_the_radius() {
+        let entities = (0..400)
+            .map(|i| {
+                Entity::new(
+                    i,
+                    0,
+                    (i % 20) as f32 * 7.0 - 70.0,
+                    (i / 20) as f32 * 7.0 - 70.0,
+                    3.0,
+                )
+            })
+            .collect::<Vec<Entity>>();
+        let interest = Interest::new([5.0, -12.0, 0.0], 30.0);
+
+        for &cell_size in [1.0, 10.0, 25.0, 1000.0].iter() {
+            let index = SpatialIndex::new(&entities, UpAxis::Z, cell_size);
+            let near = index.near(interest.center, interest.radius);
+            for (i, entity) in entities.iter().enumerate() {
+                let [x, y, _] = entity.position();
+                let (dx, dy) = (x - interest.center[0], y - interest.center[1]);
+                if dx * dx + dy * dy <= interest.radius * interest.radius {
+                    assert!(near.contains(&i), "cell_size={}; missing {:?}", cell_size, entity);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn entities_on_the_edge_do_not_flicker() {
+        let mut interest = InterestFilter::new(UpAxis::Z, 10.0, 0.1);
+        interest.set_interest(Some(Interest::new([0.0; 3], 100.0)));
+
+        // entering takes coming within the radius
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 105.0), (2, 50.0)])).0,
+            vec![2]
+        );
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 99.0), (2, 50.0)])).0,
+            vec![1, 2]
+        );
+        // leaving takes going past the radius and then some
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 109.0), (2, 50.0)])).0,
+            vec![1, 2]
+        );
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 101.0), (2, 50.0)])).0,
+            vec![1, 2]
+        );
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 111.0), (2, 50.0)])).0,
+            vec![2]
+        );
+        assert_eq!(
+            filter(&mut interest, &state(true, &[(1, 105.0), (2, 50.0)])).0,
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn entities_leaving_partial_states_are_removed() {
+        let mut interest = InterestFilter::new(UpAxis::Z, 10.0, 0.0);
+        interest.set_interest(Some(Interest::new([0.0; 3], 100.0)));
+
+        // the client may have been sent anything before, out of view entities are removed once
+        let (entities, removed) = filter(&mut interest, &state(false, &[(1, 50.0), (2, 500.0)]));
+        assert_eq!((entities, removed), (vec![1], vec![2]));
+        let (entities, removed) = filter(&mut interest, &state(false, &[(2, 400.0)]));
+        assert_eq!((entities, removed), (vec![], vec![]));
+
+        // entity 1 is not reported, it stays in view until it is reported out of it
+        let (entities, removed) = filter(&mut interest, &state(false, &[(2, 90.0)]));
+        assert_eq!((entities, removed), (vec![2], vec![]));
+        let (entities, removed) = filter(&mut interest, &state(false, &[(1, 150.0), (2, 80.0)]));
+        assert_eq!((entities, removed), (vec![2], vec![1]));
+
+        let mut despawned = state(false, &[]);
+        despawned.removed = vec![1, 2];
+        let (entities, removed) = filter(&mut interest, &despawned);
+        assert_eq!((entities, removed), (vec![], vec![2]));
+    }
+
+    #[test]
+    fn only_subscribed_entities_are_sent() {
+        let mut interest = InterestFilter::new(UpAxis::Z, 10.0, 0.1);
+        let mut state = state(true, &[(1, 10.0), (2, 20.0), (3, 500.0)]);
+        state.entities[1].tag = 3;
+        state.entities[2].tag = 3;
+
+        interest.set_subscription(Some(Subscription {
+            tags: TagSet::Only(vec![3]),
+            ids:  None,
+        }));
+        assert_eq!(filter(&mut interest, &state).0, vec![2, 3]);
+
+        interest.set_interest(Some(Interest::new([0.0; 3], 100.0)));
+        assert_eq!(filter(&mut interest, &state).0, vec![2]);
+
+        interest.set_subscription(Some(Subscription {
+            tags: TagSet::Except(vec![3]),
+            ids:  Some(vec![IdRange { first: 1, last: 2 }]),
+        }));
+        assert_eq!(filter(&mut interest, &state).0, vec![1]);
+
+        interest.set_interest(None);
+        interest.set_subscription(None);
+        assert!(interest.filter(&state, None).is_none());
+    }
+}
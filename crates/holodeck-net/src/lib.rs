@@ -1,4 +1,5 @@
 pub(crate) mod deps {
+    pub(crate) use bytes;
     pub(crate) use holodeck_core;
 
 
@@ -11,14 +12,26 @@ pub(crate) mod deps {
         tokio_tungstenite,
     };
     pub(crate) use log;
+    pub(crate) use rand;
+    pub(crate) use tokio_rustls;
+    pub(crate) use tokio_util;
     #[cfg(feature = "tracing")]
     pub(crate) use tracing;
+    pub(crate) use webpki_roots;
 }
 
 #[macro_use]
 mod macros;
+pub mod access;
 mod channel;
+mod delta;
+mod interest;
+mod lod;
 pub mod message;
+pub mod metrics;
 pub mod protocol;
 pub mod server;
+pub mod tls;
+pub mod transport;
+pub mod udp;
 mod utils;
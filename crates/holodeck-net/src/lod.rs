@@ -0,0 +1,77 @@
+use std::cmp::Ordering;
+
+use crate::{
+    message::{
+        Entity,
+        Interest,
+    },
+    server::UpdateRate,
+};
+
+
+/// Spreads the updates of entities far from a client's camera over several states, the further
+/// away the fewer. Entities are staggered by id, so every state carries some of them rather than
+/// every so often a state carrying all of them.
+pub(crate) struct UpdateSchedule {
+    /// furthest first
+    rates: Vec<UpdateRate>,
+    frame: u64,
+}
+
+
+impl UpdateSchedule {
+    pub(crate) fn new(rates: &[UpdateRate]) -> Self {
+        let mut rates = rates.to_vec();
+        rates.sort_by(|a, b| b.distance.partial_cmp(&a.distance).unwrap_or(Ordering::Equal));
+        UpdateSchedule { rates, frame: 0 }
+    }
+
+    /// Move on to the next state sent to the client.
+    pub(crate) fn advance(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Whether the changes to `entity` are sent in the current state, to a client looking at
+    /// `interest`.
+    pub(crate) fn is_due(
+        &self,
+        entity: &Entity,
+        interest: &Interest,
+    ) -> bool {
+        let distance_sq = interest.distance_sq(entity.position());
+        let interval = self
+            .rates
+            .iter()
+            .find(|rate| distance_sq >= rate.distance * rate.distance)
+            .map_or(1, |rate| rate.interval.max(1));
+
+        self.frame.wrapping_add(entity.id) % interval == 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn far_entities_are_updated_less_often() {
+        let mut schedule = UpdateSchedule::new(&[UpdateRate::new(400.0, 16), UpdateRate::new(150.0, 4)]);
+        let interest = Interest::new([10.0, 0.0, 0.0], 1000.0);
+        let near = Entity::new(7, 0, 50.0, 0.0, 0.0);
+        let mid = Entity::new(8, 0, 10.0, 200.0, 0.0);
+        let far = Entity::new(9, 0, -600.0, 0.0, 0.0);
+
+        let mut updates = [0; 3];
+        for _ in 0..64 {
+            schedule.advance();
+            for (updated, entity) in updates.iter_mut().zip([near, mid, far].iter()) {
+                if schedule.is_due(entity, &interest) {
+                    *updated += 1;
+                }
+            }
+        }
+
+        assert_eq!(updates, [64, 16, 4]);
+    }
+}
@@ -2,10 +2,44 @@
 
 
 pub use crate::deps::holodeck_core::messages::{
+    Bounds,
+    Codec,
+    Command,
+    CommandError,
+    CommandId,
+    CommandKind,
+    CommandResult,
+    Compression,
+    ConnectionId,
+    Dispatch,
     Entity,
+    Envelope,
+    Features,
+    Frame,
+    Hello,
+    IdRange,
+    Interest,
+    Kind,
     Message,
+    MessageCodec,
+    PositionCodec,
+    PositionEncoding,
+    Reject,
+    Reply,
+    Role,
+    ServerEvent,
+    SimulationDelta,
     SimulationState,
     SpawnRequest,
-    ToWebSocketMessage,
+    Subscription,
+    TagColor,
+    TagSet,
+    UdpSession,
+    UpAxis,
     WebSocketMessage,
+    Welcome,
+    WorldDescription,
+    Zone,
+    MAX_NAME_LEN,
+    PROTOCOL_VERSION,
 };
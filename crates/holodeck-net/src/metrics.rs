@@ -0,0 +1,386 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{
+        self,
+        Write as _,
+    },
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    deps::{
+        log::{
+            debug,
+            info,
+            warn,
+        },
+        tokio::{
+            self,
+            io::{
+                AsyncReadExt,
+                AsyncWriteExt,
+            },
+            net::{
+                TcpListener,
+                TcpStream,
+            },
+        },
+    },
+    message::ConnectionId,
+    transport::ACCEPT_RETRY_DELAY,
+};
+
+
+/// How long a scraper has to send its request before it is hung up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests with larger heads than this are not from a scraper.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+
+/// Reads one of the counters kept for every client.
+type ClientCounter = fn(&ClientMetrics) -> u64;
+
+
+/// The numbers a running server keeps about itself, served in the Prometheus text format on
+/// [`Config::metrics_port`](crate::server::Config::metrics_port). The simulation records its
+/// ticks and entities through the [`Metrics`] of its
+/// [`WebSocketServer`](crate::server::WebSocketServer), the server records the rest.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// the clients connected right now, they are forgotten once they disconnect
+    clients:      Mutex<BTreeMap<ConnectionId, Arc<ClientMetrics>>>,
+    /// commands received from clients, including those they were not allowed to send
+    commands:     AtomicU64,
+    /// every frame once, however many clients share it
+    encodes:      AtomicU64,
+    encode_nanos: AtomicU64,
+    entities:     AtomicU64,
+    ticks:        AtomicU64,
+    tick_nanos:   AtomicU64,
+    /// ticks which took longer than they were meant to
+    overruns:     AtomicU64,
+}
+
+
+/// What was sent to a single client.
+#[derive(Debug, Default)]
+pub struct ClientMetrics {
+    frames:     AtomicU64,
+    sent_bytes: AtomicU64,
+    /// shared with the client's mailbox, which does the dropping
+    dropped:    Arc<AtomicU64>,
+}
+
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start keeping the numbers of the client `connection`, until it
+    /// [`disconnected`](Self::disconnected).
+    pub(crate) fn connected(
+        &self,
+        connection: ConnectionId,
+        dropped: Arc<AtomicU64>,
+    ) -> Arc<ClientMetrics> {
+        let client = Arc::new(ClientMetrics {
+            dropped,
+            ..ClientMetrics::default()
+        });
+        self.clients
+            .lock()
+            .expect("could not lock the client metrics")
+            .insert(connection, client.clone());
+        client
+    }
+
+    pub(crate) fn disconnected(
+        &self,
+        connection: ConnectionId,
+    ) {
+        self.clients
+            .lock()
+            .expect("could not lock the client metrics")
+            .remove(&connection);
+    }
+
+    pub(crate) fn record_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame took `elapsed` to encode and compress. Frames shared by several clients are
+    /// encoded, and recorded, once.
+    pub(crate) fn record_encode(
+        &self,
+        elapsed: Duration,
+    ) {
+        self.encodes.fetch_add(1, Ordering::Relaxed);
+        self.encode_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// A simulation tick took `elapsed`, it was meant to take at most `budget`.
+    pub fn record_tick(
+        &self,
+        elapsed: Duration,
+        budget: Duration,
+    ) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.tick_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if elapsed > budget {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of entities in the simulation.
+    pub fn set_entities(
+        &self,
+        entities: usize,
+    ) {
+        self.entities.store(entities as u64, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        self.write(&mut text).expect("writing to a String cannot fail");
+        text
+    }
+
+    fn write(
+        &self,
+        out: &mut String,
+    ) -> fmt::Result {
+        let clients = self
+            .clients
+            .lock()
+            .expect("could not lock the client metrics")
+            .clone();
+
+        header(
+            out,
+            "holodeck_clients_connected",
+            "gauge",
+            "Clients connected to the server.",
+        )?;
+        writeln!(out, "holodeck_clients_connected {}", clients.len())?;
+
+        let per_client: [(&str, &str, ClientCounter); 3] = [
+            (
+                "holodeck_client_frames_sent_total",
+                "Frames sent to a client.",
+                |c| count(&c.frames),
+            ),
+            (
+                "holodeck_client_bytes_sent_total",
+                "Bytes sent to a client, after compression.",
+                |c| count(&c.sent_bytes),
+            ),
+            (
+                "holodeck_client_frames_dropped_total",
+                "Frames dropped because a client fell behind.",
+                |c| count(&c.dropped),
+            ),
+        ];
+        for (name, help, value) in per_client.iter() {
+            header(out, name, "counter", help)?;
+            for (connection, client) in clients.iter() {
+                writeln!(out, "{}{{client=\"{}\"}} {}", name, connection, value(client))?;
+            }
+        }
+
+        let name = "holodeck_encode_seconds";
+        header(
+            out,
+            name,
+            "summary",
+            "Time spent encoding and compressing frames, once for every frame.",
+        )?;
+        writeln!(out, "{}_sum {}", name, seconds(&self.encode_nanos))?;
+        writeln!(out, "{}_count {}", name, count(&self.encodes))?;
+
+        let name = "holodeck_tick_duration_seconds";
+        header(out, name, "summary", "Time spent on simulation ticks.")?;
+        writeln!(out, "{}_sum {}", name, seconds(&self.tick_nanos))?;
+        writeln!(out, "{}_count {}", name, count(&self.ticks))?;
+
+        let name = "holodeck_tick_overruns_total";
+        header(
+            out,
+            name,
+            "counter",
+            "Simulation ticks which took longer than the tick rate allows.",
+        )?;
+        writeln!(out, "{} {}", name, count(&self.overruns))?;
+
+        let name = "holodeck_commands_received_total";
+        header(out, name, "counter", "Commands received from clients.")?;
+        writeln!(out, "{} {}", name, count(&self.commands))?;
+
+        let name = "holodeck_entities";
+        header(out, name, "gauge", "Entities in the simulation.")?;
+        writeln!(out, "{} {}", name, count(&self.entities))
+    }
+
+    /// Listen for scrapers on `addr`, failing right away if it cannot. Any `GET` is answered with
+    /// the metrics, they are not worth routing. A failure to accept a scraper is waited out
+    /// rather than the end of the endpoint.
+    pub(crate) async fn listen(
+        self: Arc<Self>,
+        addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        let mut listener = TcpListener::bind(addr).await?;
+        info!("serving metrics on http://{}/metrics", listener.local_addr()?);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("could not accept a scraper: {}", err);
+                        tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let metrics = self.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = metrics.respond(stream).await {
+                        debug!("could not serve metrics to {}: {}", peer, err);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn respond(
+        &self,
+        mut stream: TcpStream,
+    ) -> std::io::Result<()> {
+        let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+            Ok(head) => head?,
+            Err(_) => return Ok(()),
+        };
+
+        let response = if head.starts_with(b"GET ") {
+            let body = self.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+
+impl ClientMetrics {
+    pub(crate) fn record_frame(
+        &self,
+        sent: usize,
+    ) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(sent as u64, Ordering::Relaxed);
+    }
+}
+
+
+fn header(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+
+fn count(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+
+fn seconds(nanos: &AtomicU64) -> f64 {
+    count(nanos) as f64 / 1e9
+}
+
+
+/// Read up to the end of the request head, the body of a scrape is empty.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !head.ends_with(b"\r\n\r\n") && head.len() < MAX_REQUEST_LEN {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    Ok(head)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_are_labelled_and_forgotten() {
+        let metrics = Metrics::new();
+        let dropped = Arc::new(AtomicU64::new(3));
+        let client = metrics.connected(7, dropped);
+        client.record_frame(120);
+        client.record_frame(80);
+        metrics.record_encode(Duration::from_millis(2));
+        metrics.record_tick(Duration::from_millis(40), Duration::from_millis(33));
+        metrics.record_tick(Duration::from_millis(10), Duration::from_millis(33));
+        metrics.record_command();
+        metrics.set_entities(42);
+
+        let text = metrics.render();
+        for line in &[
+            "holodeck_clients_connected 1",
+            "holodeck_client_frames_sent_total{client=\"7\"} 2",
+            "holodeck_client_bytes_sent_total{client=\"7\"} 200",
+            "holodeck_client_frames_dropped_total{client=\"7\"} 3",
+            "holodeck_encode_seconds_sum 0.002",
+            "holodeck_encode_seconds_count 1",
+            "holodeck_tick_duration_seconds_sum 0.05",
+            "holodeck_tick_duration_seconds_count 2",
+            "holodeck_tick_overruns_total 1",
+            "holodeck_commands_received_total 1",
+            "holodeck_entities 42",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "missing {:?} in:\n{}",
+                line,
+                text
+            );
+        }
+
+        metrics.disconnected(7);
+        let text = metrics.render();
+        assert!(text.lines().any(|l| l == "holodeck_clients_connected 0"));
+        assert!(!text.contains("client=\"7\""));
+    }
+}
@@ -5,15 +5,39 @@ use std::sync::mpsc::{
     TryRecvError,
 };
 
-use crate::message::{
-    ToWebSocketMessage,
-    WebSocketMessage,
+use crate::{
+    deps::{
+        holodeck_core::{
+            Error,
+            Result,
+        },
+        serde::{
+            de::DeserializeOwned,
+            Serialize,
+        },
+        tokio::sync::mpsc::{
+            error::TryRecvError as TryRecvMessageError,
+            unbounded_channel,
+            UnboundedReceiver,
+            UnboundedSender,
+        },
+    },
+    message::{
+        Codec,
+        MessageCodec,
+        WebSocketMessage,
+    },
 };
 
 
-#[derive(Copy, Clone, Debug)]
+/// How clients connect to the server, see [`crate::transport`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Transport {
     WebSocket,
+    /// length prefixed frames over TCP
+    Tcp,
+    /// length prefixed frames over a Unix domain socket
+    Unix,
 }
 
 
@@ -23,6 +47,22 @@ impl Transport {
             "web-socket" => Some(Self::WebSocket),
             "websocket" => Some(Self::WebSocket),
             "ws" => Some(Self::WebSocket),
+            "tcp" => Some(Self::Tcp),
+            "unix" => Some(Self::Unix),
+            "uds" => Some(Self::Unix),
+            _ => None,
+        }
+    }
+
+    /// The transport named by the scheme of `url`: `ws://` and `wss://` for websockets,
+    /// `tcp://` and `unix://`.
+    pub fn from_url<S: AsRef<str>>(url: S) -> Option<Self> {
+        let url = url.as_ref();
+        let scheme = &url[..url.find("://")?];
+        match scheme.to_ascii_lowercase().as_str() {
+            "ws" | "wss" => Some(Self::WebSocket),
+            "tcp" => Some(Self::Tcp),
+            "unix" => Some(Self::Unix),
             _ => None,
         }
     }
@@ -32,7 +72,7 @@ impl Transport {
 impl std::str::FromStr for Transport {
     type Err = crate::deps::holodeck_core::Error;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Transport::from_str(s).ok_or_else(|| {
             crate::deps::holodeck_core::Error::BadValue {
                 from:  "str".into(),
@@ -76,10 +116,13 @@ impl std::str::FromStr for Transport {
 // }
 
 
+/// Both halves are async channels so the server can sleep until the simulation has something for
+/// it, sending never blocks which keeps the simulation side usable from a plain thread.
 pub struct BidirectionMessageStream<Tx, Rx> {
-    tx: Sender<WebSocketMessage>,
-    rx: Receiver<WebSocketMessage>,
-    _p: std::marker::PhantomData<(Tx, Rx)>,
+    tx:    UnboundedSender<WebSocketMessage>,
+    rx:    UnboundedReceiver<WebSocketMessage>,
+    codec: MessageCodec,
+    _p:    std::marker::PhantomData<(Tx, Rx)>,
 }
 
 
@@ -92,34 +135,55 @@ pub enum Recv<M> {
 
 impl<Tx, Rx> BidirectionMessageStream<Tx, Rx>
 where
-    Tx: ToWebSocketMessage,
+    Tx: Serialize,
 {
+    /// Fails when `value` cannot be encoded or once the other side hung up.
     pub fn send(
         &self,
         value: &Tx,
-    ) {
-        value
-            .to_message()
-            .map(|msg| self.tx.send(msg).unwrap_or_else(crash_on_err!()))
-            .unwrap_or_else(crash_on_err!());
+    ) -> Result<()> {
+        let frame = self.codec.encode(value)?;
+        self.tx.send(frame.into()).map_err(|_| hung_up())
     }
 }
 
 
 impl<Tx, Rx> BidirectionMessageStream<Tx, Rx>
 where
-    for<'de> Rx: crate::deps::serde::Deserialize<'de>,
+    Rx: DeserializeOwned,
 {
-    pub fn recv(&self) -> Recv<Rx> {
+    /// Take the next message if one is waiting, never blocks.
+    pub fn recv(&mut self) -> Recv<Rx> {
         match self.rx.try_recv() {
-            Ok(WebSocketMessage::Binary(m)) => {
-                Recv::Msg(crate::deps::bincode::deserialize(m.as_slice()).unwrap())
-            }
-            Err(TryRecvError::Empty) => Recv::Empty,
-            Err(TryRecvError::Disconnected) => Recv::Disconnected,
-            _ => Recv::Invalid,
+            Ok(message) => self.decode(message),
+            Err(TryRecvMessageError::Empty) => Recv::Empty,
+            Err(TryRecvMessageError::Closed) => Recv::Disconnected,
+        }
+    }
+
+    /// Wait for the next message, never [`Recv::Empty`].
+    pub async fn recv_async(&mut self) -> Recv<Rx> {
+        match self.rx.recv().await {
+            Some(message) => self.decode(message),
+            None => Recv::Disconnected,
         }
     }
+
+    fn decode(
+        &self,
+        message: WebSocketMessage,
+    ) -> Recv<Rx> {
+        let decoded = match message {
+            WebSocketMessage::Binary(bytes) => self.codec.decode(bytes.as_slice()),
+            WebSocketMessage::Text(text) => self.codec.decode(text.as_bytes()),
+            _ => return Recv::Invalid,
+        };
+
+        decoded
+            .map(Recv::Msg)
+            .map_err(warn_on_err!("could not decode message"))
+            .unwrap_or(Recv::Invalid)
+    }
 }
 
 
@@ -132,11 +196,12 @@ pub struct BidirectionalStream<Tx, Rx> {
 
 
 impl<Tx, Rx> BidirectionalStream<Tx, Rx> {
+    /// Fails once the other side hung up.
     pub fn send(
         &self,
         value: Tx,
-    ) {
-        self.tx.send(value).unwrap_or_else(crash_on_err!())
+    ) -> Result<()> {
+        self.tx.send(value).map_err(|_| hung_up())
     }
 
     pub fn recv(&self) -> Recv<Rx> {
@@ -149,6 +214,13 @@ impl<Tx, Rx> BidirectionalStream<Tx, Rx> {
 }
 
 
+fn hung_up() -> Error {
+    Error::Disconnected {
+        reason: "the other side of the channel hung up".into(),
+    }
+}
+
+
 #[derive(derive_more::Deref, derive_more::DerefMut)]
 pub struct Channel<Tx, Rx>(BidirectionalStream<Tx, Rx>);
 
@@ -176,14 +248,22 @@ pub type BackEnd<Tx, Rx> = MessageChannel<Tx, Rx>;
 
 
 pub fn server_channel<S, C>() -> (FrontEnd<C, S>, BackEnd<S, C>) {
-    let (s_tx, s_rx) = channel(); // from server to sim
-    let (c_tx, c_rx) = channel(); // from sim to server
+    server_channel_with_codec(MessageCodec::default())
+}
+
+
+/// A [`server_channel`] with messages encoded using `codec` rather than bincode, for simulations
+/// which are not written in rust on the other end.
+pub fn server_channel_with_codec<S, C>(codec: MessageCodec) -> (FrontEnd<C, S>, BackEnd<S, C>) {
+    let (s_tx, s_rx) = unbounded_channel(); // from server to sim
+    let (c_tx, c_rx) = unbounded_channel(); // from sim to server
 
     (
         FrontEnd {
             stream: BidirectionMessageStream {
                 tx: c_tx,
                 rx: s_rx,
+                codec,
                 _p: std::marker::PhantomData,
             },
         },
@@ -191,6 +271,7 @@ pub fn server_channel<S, C>() -> (FrontEnd<C, S>, BackEnd<S, C>) {
             stream: BidirectionMessageStream {
                 tx: s_tx,
                 rx: c_rx,
+                codec,
                 _p: std::marker::PhantomData,
             },
         },
@@ -1,30 +1,95 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    path::PathBuf,
+    time::Duration,
+};
 
 #[cfg(feature = "tracing")]
 use crate::deps::tracing::tracing;
 use crate::{
-    channel::SimulationChannel,
+    access::Access,
+    channel::{
+        close_message,
+        Broadcast,
+        ClientEvent,
+        ClientHandle,
+        Hangup,
+    },
+    delta::{
+        Snapshot,
+        StaleEntities,
+    },
     deps::{
-        futures::SinkExt,
-        log::info,
+        futures::{
+            future::{
+                abortable,
+                AbortHandle,
+            },
+            SinkExt,
+        },
+        futures_util::StreamExt,
+        holodeck_core::Error,
+        log::{
+            info,
+            warn,
+        },
         tokio,
-        tokio::{
-            net::TcpStream,
-            sync::mpsc::{
-                channel,
-                Receiver,
-                Sender,
+        tokio::sync::mpsc::{
+            channel,
+            Receiver,
+            Sender,
+        },
+        tokio_rustls::TlsAcceptor,
+        tokio_tungstenite::{
+            stream::Stream,
+            tungstenite::handshake::server::{
+                ErrorResponse,
+                Request,
+                Response,
             },
+            WebSocketStream,
         },
-        tokio_tungstenite::WebSocketStream,
     },
     message::{
-        SimulationState,
-        SpawnRequest,
+        Bounds,
+        Codec,
+        CommandResult,
+        ConnectionId,
+        Dispatch,
+        Envelope,
+        Features,
+        Message,
+        MessageCodec,
+        Role,
+        ServerEvent,
+        UpAxis,
+        WebSocketMessage,
+        Welcome,
+        WorldDescription,
+        PROTOCOL_VERSION,
     },
+    metrics::Metrics,
     protocol::{
         FrontEnd,
         Recv,
+        Transport,
+    },
+    tls::{
+        ServerStream,
+        TlsIdentity,
+    },
+    transport::{
+        self,
+        framed,
+        Frames,
+        Listener,
+        Socket,
+        ACCEPT_RETRY_DELAY,
+    },
+    udp::{
+        self,
+        UdpEndpoint,
+        UdpSender,
     },
 };
 use std::{
@@ -32,172 +97,1140 @@ use std::{
         IpAddr,
         SocketAddr,
     },
-    sync::{
-        atomic::{
-            AtomicBool,
-            Ordering,
-        },
-        Arc,
-    },
+    sync::Arc,
 };
 
 use crate::deps::holodeck_core::Result;
 use smallvec::SmallVec;
-use std::mem;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Config {
+    /// how clients connect, on `ip:port` unless it is [`Transport::Unix`]
+    pub transport:             Transport,
     pub ip:                    IpAddr,
     pub port:                  u16,
+    /// the socket file for [`Transport::Unix`], whoever may open it may do anything
+    pub socket_path:           PathBuf,
     pub tick:                  Duration,
     pub simulation_world_size: f32,
     pub max_entities:          usize,
+    /// the optional protocol features offered to clients
+    pub features:              Features,
+    /// how long a new connection has to complete the protocol handshake
+    pub handshake_timeout:     Duration,
+    /// send a full state to delta capable clients every N updates
+    pub keyframe_interval:     u64,
+    /// entities that moved less than this since the client last heard about them are left out
+    /// of deltas
+    pub delta_threshold:       f32,
+    /// despawn entities a non-authoritative simulation has not reported for this many ticks
+    pub stale_entity_timeout:  Option<u64>,
+    /// frames smaller than this are not worth compressing
+    pub compression_threshold: usize,
+    /// how often clients are pinged
+    pub heartbeat_interval:    Duration,
+    /// clients which have not been heard from, not even a pong, for this long are disconnected
+    pub idle_timeout:          Duration,
+    /// the simulation axis which points up
+    pub up_axis:               UpAxis,
+    /// offset added to simulation positions by viewers
+    pub origin:                [f32; 3],
+    pub ground_height:         f32,
+    /// the size of the zones the ground is divided into, 0 for none
+    pub zone_size:             f32,
+    /// frames queued for a client before it is considered to have fallen behind
+    pub client_queue_size:     usize,
+    /// how states are delivered to clients which fall behind
+    pub state_delivery:        Delivery,
+    /// how command results are delivered to clients which fall behind
+    pub event_delivery:        Delivery,
+    /// serve `wss://` with this certificate rather than plain `ws://`, the server refuses to start
+    /// with it for any other transport
+    pub tls:                   Option<TlsIdentity>,
+    /// the tokens and origins clients are let in with
+    pub access:                Access,
+    /// send states over UDP from this port, on `ip`, to clients which ask for it
+    pub udp_port:              Option<u16>,
+    /// the largest datagram sent over UDP, fragments of a state included
+    pub udp_mtu:               usize,
+    /// the size of the ground cells entities are indexed by for clients which report an interest
+    pub interest_cell_size:    f32,
+    /// how much further than the radius of a client's interest entities go before they leave it,
+    /// as a fraction of the radius
+    pub interest_hysteresis:   f32,
+    /// how often entities are updated by their distance from a client's camera, for clients
+    /// which report an interest and take deltas, entities nearer than all of them every state
+    pub update_rates:          Vec<UpdateRate>,
+    /// serve the server's [`Metrics`] over HTTP on this port, on `ip`
+    pub metrics_port:          Option<u16>,
+}
+
+impl Config {
+    /// The world announced to clients, without any tag colors.
+    pub fn world_description(&self) -> WorldDescription {
+        let bounds = Bounds::square(self.simulation_world_size);
+        let mut world = WorldDescription::new(bounds, self.max_entities as u64);
+        world.up_axis = self.up_axis;
+        world.origin = self.origin;
+        world.ground_height = self.ground_height;
+        world.with_zone_grid(self.zone_size)
+    }
 }
 
 impl std::default::Default for Config {
     fn default() -> Self {
         Self {
+            transport:             Transport::WebSocket,
             ip:                    IpAddr::from([0, 0, 0, 0]),
             port:                  7000,
+            socket_path:           transport::default_socket_path(),
             tick:                  Duration::from_millis(33),
             simulation_world_size: 1000.0,
             max_entities:          1024,
+            features:              Features::supported(),
+            handshake_timeout:     Duration::from_secs(5),
+            keyframe_interval:     30,
+            delta_threshold:       0.01,
+            stale_entity_timeout:  None,
+            compression_threshold: 1024,
+            heartbeat_interval:    Duration::from_secs(5),
+            idle_timeout:          Duration::from_secs(15),
+            up_axis:               UpAxis::Z,
+            origin:                [0.0; 3],
+            ground_height:         0.0,
+            zone_size:             200.0,
+            client_queue_size:     64,
+            state_delivery:        Delivery::Latest,
+            event_delivery:        Delivery::All,
+            tls:                   None,
+            access:                Access::default(),
+            udp_port:              None,
+            udp_mtu:               udp::DEFAULT_MTU,
+            interest_cell_size:    100.0,
+            interest_hysteresis:   0.1,
+            update_rates:          vec![UpdateRate::new(150.0, 4), UpdateRate::new(400.0, 16)],
+            metrics_port:          None,
         }
     }
 }
 
 
+/// Entities at least `distance` away from a client's camera only have their changes sent every
+/// `interval` states, the viewer extrapolates their motion in between.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UpdateRate {
+    pub distance: f32,
+    pub interval: u64,
+}
+
+
+impl UpdateRate {
+    pub fn new(
+        distance: f32,
+        interval: u64,
+    ) -> Self {
+        UpdateRate { distance, interval }
+    }
+}
+
+
+/// What happens to the frames queued for a client which is not keeping up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// a newer frame replaces the one still waiting to be sent, for streams where only the
+    /// latest value matters. Partial states keep the changes and despawns of those they replace.
+    Latest,
+    /// every frame is sent in order, the client is disconnected once its queue is full
+    All,
+}
+
+
 pub struct WebSocketServer {
-    config: Config,
+    config:  Config,
+    world:   Arc<WorldDescription>,
+    metrics: Arc<Metrics>,
 }
 
 
 impl WebSocketServer {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        let world = Arc::new(config.world_description());
+        Self {
+            config,
+            world,
+            metrics: Arc::new(Metrics::new()),
+        }
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, service, running)))]
-    pub async fn run_until_shutdown(
-        self,
-        service: FrontEnd<SpawnRequest, SimulationState>,
-        running: Arc<AtomicBool>,
-    ) -> Result<()> {
-        let Self { config } = self;
+    /// What the server keeps count of, served on [`Config::metrics_port`]. The simulation
+    /// records its ticks and entities here.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
 
+    /// Announce `world` to clients instead of the description derived from the config.
+    pub fn with_world_description(
+        mut self,
+        world: WorldDescription,
+    ) -> Self {
+        self.world = Arc::new(world);
+        self
+    }
 
+    /// Serve clients until the simulation hangs up or `shutdown` completes. The simulation hears
+    /// about clients connecting and disconnecting and gets their
+    /// [`Command`](crate::message::Command)s in an [`Envelope`] saying who sent them. It
+    /// [`Dispatch`]es [`Message::State`]s to broadcast and a [`Message::CommandResult`] for every
+    /// command, to every client or only to some.
+    ///
+    /// The world as the states broadcast so far describe it is kept for clients which connect
+    /// between two states, they get it right after the handshake instead of an empty world until
+    /// the simulation next reports.
+    ///
+    /// The loop sleeps until a client connects, the simulation sends something or a client sends
+    /// a command, whichever comes first. Everything the server listens on is bound before it
+    /// starts serving, failing to bind any of it is returned right away.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, service, shutdown)))]
+    pub async fn run_until_shutdown<F>(
+        self,
+        mut service: FrontEnd<ServerEvent, Dispatch>,
+        shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let Self {
+            config,
+            world,
+            metrics,
+        } = self;
 
-        let mut ws_server = ServerImpl::spawn(config);
+        // a bad certificate is better found out now than by the first client, and a client
+        // expecting TLS should not be served in plaintext
+        let tls = match (config.transport, config.tls.as_ref()) {
+            (Transport::WebSocket, tls) => tls.map(TlsIdentity::acceptor).transpose()?,
+            (_, None) => None,
+            (other, Some(_)) => {
+                return Err(Error::Tls {
+                    reason: format!("TLS is only supported with websockets, not for {:?}", other).into(),
+                })
+            }
+        };
+        let udp = match config.udp_port {
+            Some(port) => Some(UdpEndpoint::bind(SocketAddr::new(config.ip, port), config.udp_mtu).await?),
+            None => None,
+        };
+        if let Some(port) = config.metrics_port {
+            metrics.clone().listen(SocketAddr::new(config.ip, port)).await?;
+        }
+        let listener = Listener::bind(&config).await?;
+        let mut ws_server = ServerImpl::spawn(listener, config.clone(), world, tls, udp.clone());
 
-        let mut clients = SmallVec::<[Box<SimulationChannel>; 32]>::new();
+        let mut clients = SmallVec::<[ClientHandle; 32]>::new();
 
         let (forwarder, mut client_inputs) = channel(32);
+        let mut stale_entities = config.stale_entity_timeout.map(StaleEntities::new);
+        let mut snapshot = Snapshot::default();
 
-        let _count = 0;
-        'serve: while running.load(Ordering::Relaxed) {
-            // add any new clients
-            // just the client stream
-            while let Some(client_stream) = ws_server.recv() {
-                clients.push(Box::new(SimulationChannel::new(client_stream, forwarder.clone())));
-            }
+        // clients register for their states over UDP on a task of its own, until the server stops
+        let udp_registrations = udp.clone().map(|udp| {
+            let (registrations, handle) = abortable(async move { udp.serve().await });
+            tokio::spawn(registrations);
+            handle
+        });
 
-            // read state from agent app
-            match service.recv() {
-                Recv::Msg(state) => {
-                    // send state to all clients
+        tokio::pin!(shutdown);
 
-                    for mut client in mem::take(&mut clients).into_iter() {
-                        if let Ok(_) = client.send(&state).await {
-                            clients.push(client)
-                        }
+        'serve: loop {
+            tokio::select! {
+                _ = &mut shutdown => break 'serve,
+
+                // add any new clients
+                Some(accepted) = ws_server.recv() => {
+                    let connection = accepted.connection;
+                    let connected = ServerEvent::Connected {
+                        connection,
+                        peer: accepted.peer.clone(),
+                        role: accepted.role,
+                        name: accepted.name.clone(),
+                    };
+                    let client = ClientHandle::spawn(accepted, &config, &metrics, forwarder.clone());
+                    if let Some(state) = snapshot.state() {
+                        // the queue is empty, this only fails if the client is already gone
+                        client
+                            .send_state(&Arc::new(Broadcast::new(Message::State(state))))
+                            .map_err(warn_on_err!("could not send the snapshot to client {}", connection))
+                            .unwrap_or(());
+                    }
+                    clients.push(client);
+                    if service.send(&connected).is_err() {
+                        break 'serve;
                     }
                 }
-                Recv::Invalid | Recv::Empty => { /* no-op */ }
-                Recv::Disconnected => break 'serve,
-            }
 
-            while let Some(input) = client_inputs.try_recv().ok() {
-                // forward?
-                service.send(&input);
+                // read state from agent app
+                recv = service.recv_async() => match recv {
+                    Recv::Msg(sent) => {
+                        // only the states every client got are what the next client to connect sees
+                        let sent = route(&mut clients, stale_entities.as_mut(), sent);
+                        if let Some(Message::State(state)) = sent.as_deref().map(Broadcast::message) {
+                            snapshot.apply(state);
+                        }
+                    }
+                    Recv::Invalid | Recv::Empty => { /* no-op */ }
+                    // the simulation hung up, as it has once sending to it fails
+                    Recv::Disconnected => break 'serve,
+                },
+
+                Some(event) = client_inputs.recv() => match event {
+                    ClientEvent::Command { connection, command } => {
+                        metrics.record_command();
+                        let index = match clients.iter().position(|c| c.connection() == connection) {
+                            Some(index) => index,
+                            // dropped by the server while the command was on its way
+                            None => continue 'serve,
+                        };
+
+                        let role = clients[index].role();
+                        if role.allows(&command.kind) {
+                            let command = ServerEvent::Command(Envelope {
+                                client: connection,
+                                body:   command,
+                            });
+                            if service.send(&command).is_err() {
+                                break 'serve;
+                            }
+                            continue 'serve;
+                        }
+
+                        warn!("refusing command from client {}; role={:?}: {:?}", connection, role, command);
+                        let refused = CommandResult::err(
+                            command.id,
+                            format!("requires the {:?} role", command.kind.required_role()),
+                        );
+                        if let Err(err) = clients[index].send(Message::CommandResult(refused)) {
+                            warn!("dropping client {}: {}", connection, err);
+                            clients.remove(index);
+                        }
+                    }
+                    ClientEvent::Closed { connection, reason } => {
+                        clients.retain(|client| client.connection() != connection);
+                        if service.send(&ServerEvent::Disconnected { connection, reason }).is_err() {
+                            break 'serve;
+                        }
+                    }
+                },
             }
         }
 
+        if let Some(registrations) = udp_registrations {
+            registrations.abort();
+        }
+        ws_server.stop();
         info!("websocket server terminating gracefully");
         Ok(())
     }
 }
 
 
+/// Queue what the simulation `sent` for the clients it is addressed to, returns the state every
+/// client was sent if it was one.
+fn route(
+    clients: &mut SmallVec<[ClientHandle; 32]>,
+    stale_entities: Option<&mut StaleEntities>,
+    sent: Dispatch,
+) -> Option<Arc<Broadcast>> {
+    match sent {
+        Dispatch::Broadcast(message) => dispatch(clients, stale_entities, message, |_| true),
+        // states for some clients only say nothing about which entities went stale
+        Dispatch::To { clients: to, message } => {
+            dispatch(clients, None, message, |client| to.contains(&client));
+            None
+        }
+        Dispatch::Except {
+            clients: except,
+            message,
+        } => {
+            dispatch(clients, None, message, |client| !except.contains(&client));
+            None
+        }
+    }
+}
+
+
+/// Queue a `message` from the simulation for the clients `to` picks out, dropping any which fell
+/// too far behind. States go through the stale entity reaper, when given one, and each client's
+/// delta encoder, the state as sent is returned.
+fn dispatch<F>(
+    clients: &mut SmallVec<[ClientHandle; 32]>,
+    stale_entities: Option<&mut StaleEntities>,
+    message: Message,
+    to: F,
+) -> Option<Arc<Broadcast>>
+where
+    F: Fn(ConnectionId) -> bool,
+{
+    match message {
+        Message::State(mut state) => {
+            if let Some(stale_entities) = stale_entities {
+                stale_entities.reap(&mut state);
+            }
+
+            // queue the state for the clients, each sends it on its own time
+            let message = Arc::new(Broadcast::new(Message::State(state)));
+            clients.retain(|client| {
+                !to(client.connection())
+                    || client
+                        .send_state(&message)
+                        .map_err(peek_warn!(
+                            "dropping client {}; dropped_frames={}",
+                            client.connection(),
+                            client.dropped()
+                        ))
+                        .is_ok()
+            });
+            Some(message)
+        }
+        message @ Message::CommandResult(_) => {
+            clients.retain(|client| {
+                !to(client.connection())
+                    || client
+                        .send(message.clone())
+                        .map_err(peek_warn!("dropping client {}", client.connection()))
+                        .is_ok()
+            });
+            None
+        }
+        other => {
+            warn!("unexpected message from the simulation: {:?}", other);
+            None
+        }
+    }
+}
+
+
+/// A client connection which completed the protocol handshake.
+pub(crate) struct Accepted {
+    /// assigned in the order clients connect
+    pub(crate) connection: ConnectionId,
+    pub(crate) peer:       String,
+    pub(crate) stream:     Frames,
+    pub(crate) codec:      MessageCodec,
+    /// what the client's token allows it to do
+    pub(crate) role:       Role,
+    /// what the client asked to be called
+    pub(crate) name:       Option<String>,
+    /// what was agreed on during the handshake
+    pub(crate) welcome:    Welcome,
+    /// sends the client its states once it registers, if it gets them over udp
+    pub(crate) udp:        Option<UdpSender>,
+}
+
+
 struct ServerImpl {
-    rx:     Receiver<WebSocketStream<TcpStream>>,
-    handle: tokio::task::JoinHandle<()>,
+    rx:        Receiver<Accepted>,
+    listening: AbortHandle,
 }
 
 impl ServerImpl {
-    fn spawn(config: Config) -> ServerImpl {
+    fn spawn(
+        listener: Listener,
+        config: Config,
+        world: Arc<WorldDescription>,
+        tls: Option<TlsAcceptor>,
+        udp: Option<Arc<UdpEndpoint>>,
+    ) -> ServerImpl {
         let (tx, rx) = channel(32);
-        let handle = tokio::task::spawn(Self::listen(config, tx));
-        ServerImpl { rx, handle }
+        let (listen, listening) = abortable(Self::listen(listener, config, world, tls, udp, tx));
+        tokio::task::spawn(listen);
+        ServerImpl { rx, listening }
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(config, socket_tx)))]
+    /// Accept connections until [`ServerImpl::stop`]ped, a failure to accept one is waited out
+    /// rather than the end of the listener.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(listener, config, world, tls, udp, socket_tx))
+    )]
     async fn listen(
+        mut listener: Listener,
         config: Config,
-        socket_tx: Sender<WebSocketStream<TcpStream>>,
+        world: Arc<WorldDescription>,
+        tls: Option<TlsAcceptor>,
+        udp: Option<Arc<UdpEndpoint>>,
+        socket_tx: Sender<Accepted>,
     ) {
-        use crate::deps::tokio::net::TcpListener;
-        let addr = SocketAddr::new(config.ip, config.port);
-
-        let mut listener = TcpListener::bind(&addr).await.unwrap_or_else(crash_on_err!(
-            "cannot listen to {:?}, config: {:?}",
-            addr,
-            config
-        ));
-        info!("ready to accept connections, listening on: {}", addr);
-
-        while let Ok((stream, _socketaddr)) = listener.accept().await {
-            let peer = stream
-                .peer_addr()
-                .unwrap_or_else(crash_on_err!("connected streams should have a peer address"));
-            info!("peer address: {}", peer);
+        info!(
+            "ready to accept connections, listening on: {}; transport={:?}; tls={}; udp_port={:?}",
+            listener,
+            config.transport,
+            tls.is_some(),
+            udp.as_ref().map(|udp| udp.port())
+        );
+        let config = Arc::new(config);
+        let mut next_connection: ConnectionId = 0;
+
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("could not accept a connection: {}", err);
+                    tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            next_connection += 1;
+            let connection = next_connection;
+            info!("peer address: {}; client={}", peer, connection);
 
             let mut tx_ws = socket_tx.clone();
+            let world = world.clone();
+            let config = config.clone();
+            let tls = tls.clone();
+            let udp = udp.clone();
             tokio::spawn(async move {
-                let ws_stream = accept_connection(peer, stream).await;
-                let _ = tx_ws.send(ws_stream).await;
+                match admit(
+                    connection,
+                    &peer,
+                    socket,
+                    tls.as_ref(),
+                    udp.as_ref(),
+                    &config,
+                    &world,
+                )
+                .await
+                {
+                    Ok(accepted) => {
+                        let _ = tx_ws.send(accepted).await;
+                    }
+                    Err(err) => warn!("dropping connection from {}: {}", peer, err),
+                }
             });
         }
     }
 
-    async fn join(self) {
-        self.handle
-            .await
-            .unwrap_or_else(crash_on_err!("could not join() the listener thread!"));
+    fn stop(&self) {
+        self.listening.abort();
     }
 
-    fn recv(&mut self) -> Option<WebSocketStream<TcpStream>> {
-        // clients com out of here..
-        self.rx.try_recv().ok()
+    async fn recv(&mut self) -> Option<Accepted> {
+        // clients come out of here..
+        self.rx.recv().await
     }
 }
 
 
-#[cfg_attr(feature = "tracing", tracing::instrument(skip(stream)))]
+/// Take a new connection through the TLS, transport and protocol handshakes. Anyone can connect,
+/// so nothing they send is trusted until then, a connection which fails the protocol handshake is
+/// closed with a code saying why.
+pub(crate) async fn admit(
+    connection: ConnectionId,
+    peer: &str,
+    socket: Socket,
+    tls: Option<&TlsAcceptor>,
+    udp: Option<&Arc<UdpEndpoint>>,
+    config: &Config,
+    world: &WorldDescription,
+) -> Result<Accepted> {
+    // where the client's UDP registrations have to come from
+    let udp = match udp {
+        Some(udp) => Some((udp, socket.peer_ip()?)),
+        None => None,
+    };
+    let (mut frames, codec, role): (Frames, _, _) = match socket {
+        Socket::Tcp(stream) if config.transport == Transport::WebSocket => {
+            let stream = match tls {
+                Some(acceptor) => {
+                    match tokio::time::timeout(config.handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(tls) => {
+                            Stream::Tls(tls.map_err(|err| {
+                                Error::Tls {
+                                    reason: format!("the TLS handshake failed: {}", err).into(),
+                                }
+                            })?)
+                        }
+                        Err(_elapsed) => {
+                            return Err(Error::Tls {
+                                reason: format!("no TLS handshake within {:?}", config.handshake_timeout)
+                                    .into(),
+                            })
+                        }
+                    }
+                }
+                None => Stream::Plain(stream),
+            };
+            let (ws_stream, codec, role) = accept_connection(peer, stream, &config.access).await?;
+            (Box::new(ws_stream), codec, role)
+        }
+        Socket::Tcp(stream) => {
+            let role = config.access.anonymous.ok_or_else(|| {
+                Error::Handshake {
+                    reason: "a token is required and framed tcp clients cannot present one".into(),
+                }
+            })?;
+            let mut frames: Frames = Box::new(framed(stream));
+            let codec = transport::select_codec(&mut frames, config.handshake_timeout).await?;
+            (frames, codec, role)
+        }
+        #[cfg(unix)]
+        Socket::Unix(stream) => {
+            // the socket file's permissions are the access control
+            let mut frames: Frames = Box::new(framed(stream));
+            let codec = transport::select_codec(&mut frames, config.handshake_timeout).await?;
+            (frames, codec, Role::Admin)
+        }
+    };
+
+    match handshake(connection, peer, &mut frames, codec, udp, config, world).await {
+        Ok((welcome, name, udp)) => {
+            Ok(Accepted {
+                connection,
+                peer: peer.to_string(),
+                stream: frames,
+                codec,
+                role,
+                name,
+                welcome,
+                udp,
+            })
+        }
+        Err(err) => {
+            let hangup = Hangup::from_error(&err);
+            if let Some(code) = hangup.code {
+                let _ = frames.send(close_message(code, &hangup.reason)).await;
+            }
+            Err(err)
+        }
+    }
+}
+
+
+/// Upgrade the connection to a websocket, picking the [`MessageCodec`] from the subprotocols the
+/// client offered. Clients `access` does not let in are refused with an HTTP error instead.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(stream, access)))]
 async fn accept_connection(
-    peer: SocketAddr,
-    stream: TcpStream,
-) -> WebSocketStream<TcpStream> {
-    let addr = stream
-        .peer_addr()
-        .unwrap_or_else(crash_on_err!("connected streams should have a peer address"));
+    peer: &str,
+    stream: ServerStream,
+    access: &Access,
+) -> Result<(WebSocketStream<ServerStream>, MessageCodec, Role)> {
+    use crate::deps::tokio_tungstenite::tungstenite::http::HeaderValue;
+    const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+    let mut selected = None;
+    let mut authorized = None;
+    // tungstenite decides what the callback returns
+    #[allow(clippy::result_large_err)]
+    let select_codec =
+        |request: &Request, mut response: Response| -> std::result::Result<Response, ErrorResponse> {
+            authorized = Some(access.authorize(request).map_err(|refused| *refused)?);
+            selected = request
+                .headers()
+                .get_all(SEC_WEBSOCKET_PROTOCOL)
+                .iter()
+                .filter_map(|offered| offered.to_str().ok())
+                .filter_map(MessageCodec::select)
+                .next();
 
-    info!("Peer address: {}", addr);
+            if let Some(codec) = selected {
+                response
+                    .headers_mut()
+                    .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(codec.protocol()));
+            }
+            Ok(response)
+        };
+
+    let ws_stream = crate::deps::tokio_tungstenite::accept_hdr_async(stream, select_codec).await?;
 
-    let ws_stream = crate::deps::tokio_tungstenite::accept_async(stream)
-        .await
-        .unwrap_or_else(crash_on_err!("Error during the websocket handshake occurred"));
+    let codec = selected.unwrap_or_default();
+    // the upgrade only succeeds once the client is authorized
+    let role = authorized.expect("authorized during the upgrade");
+    info!(
+        "New WebSocket connection: {}; codec={:?}; role={:?}",
+        peer, codec, role
+    );
 
-    info!("New WebSocket connection: {}", addr);
+    Ok((ws_stream, codec, role))
+}
 
-    ws_stream
+
+/// Wait for the client's [`Message::Hello`] and answer it with a [`Message::Welcome`] listing the
+/// features, position encoding and compression enabled for the connection and describing the
+/// `world`, returned along with the name the client goes by and, for clients getting their states
+/// over `udp`, the session to send them with, to be registered from the address `udp` comes with.
+/// A client speaking another protocol version gets a [`Message::Reject`]. Failures are returned
+/// rather than closing the connection, the caller closes it with a code matching the error.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(ws_stream, udp, config, world)))]
+async fn handshake(
+    connection: ConnectionId,
+    peer: &str,
+    ws_stream: &mut Frames,
+    codec: MessageCodec,
+    udp: Option<(&Arc<UdpEndpoint>, IpAddr)>,
+    config: &Config,
+    world: &WorldDescription,
+) -> Result<(Welcome, Option<String>, Option<UdpSender>)> {
+    let frame = match tokio::time::timeout(config.handshake_timeout, ws_stream.next()).await {
+        Ok(Some(Ok(WebSocketMessage::Binary(bytes)))) => bytes,
+        Ok(Some(Ok(WebSocketMessage::Text(text)))) => text.into_bytes(),
+        Ok(Some(Ok(other))) => {
+            return Err(Error::Handshake {
+                reason: format!("expected a hello, got: {:?}", other).into(),
+            })
+        }
+        Ok(Some(Err(err))) => return Err(err.into()),
+        Ok(None) => {
+            return Err(Error::Handshake {
+                reason: "connection closed before hello".into(),
+            })
+        }
+        Err(_elapsed) => {
+            return Err(Error::Handshake {
+                reason: format!("no hello within {:?}", config.handshake_timeout).into(),
+            })
+        }
+    };
+
+    let hello = match codec.decode::<Message>(&frame)? {
+        Message::Hello(hello) => hello,
+        other => {
+            return Err(Error::Handshake {
+                reason: format!("expected a hello, got: {:?}", other).into(),
+            })
+        }
+    };
+
+    let supported = match udp {
+        Some(_) => config.features | Features::UDP,
+        None => config.features.without(Features::UDP),
+    };
+    match hello.negotiate(supported) {
+        Ok(mut features) => {
+            let udp = udp
+                .filter(|_| features.contains(Features::UDP))
+                .map(|(udp, ip)| udp.open(connection, ip));
+            if udp.is_some() {
+                // a delta is no good once the state it is against was lost
+                features = features.without(Features::DELTAS);
+            }
+            let welcome = Welcome {
+                version: PROTOCOL_VERSION,
+                client: connection,
+                features,
+                position_encoding: hello.position_encoding(features),
+                compression: hello.compression(features),
+                world: world.clone(),
+                udp: udp.as_ref().map(UdpSender::session),
+            };
+            ws_stream
+                .send(codec.encode(&Message::Welcome(welcome.clone()))?.into())
+                .await?;
+            let name = hello.display_name();
+            info!(
+                "handshake complete: peer={}; client={}; name={:?}; features={:?}; positions={:?}; \
+                 compression={:?}; udp={}",
+                peer,
+                connection,
+                name,
+                features,
+                welcome.position_encoding,
+                welcome.compression,
+                welcome.udp.is_some()
+            );
+            Ok((welcome, name, udp))
+        }
+        Err(reject) => {
+            ws_stream
+                .send(codec.encode(&Message::Reject(reject.clone()))?.into())
+                .await
+                .map_err(warn_on_err!("could not send reject to {}", peer))
+                .unwrap_or(());
+            // closed with a protocol error by the caller
+            Err(Error::Handshake {
+                reason: reject.reason.into(),
+            })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deps::{
+        tokio::{
+            io::AsyncWriteExt,
+            net::{
+                TcpListener,
+                TcpStream,
+            },
+            runtime::Runtime,
+        },
+        tokio_tungstenite::{
+            client_async,
+            tungstenite::protocol::frame::coding::CloseCode,
+        },
+    };
+    use rand::{
+        rngs::StdRng,
+        Rng,
+        SeedableRng,
+    };
+
+    const ROUNDS: usize = 64;
+
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new()
+            .enable_all()
+            .basic_scheduler()
+            .build()
+            .unwrap()
+    }
+
+
+    fn config() -> Config {
+        Config {
+            handshake_timeout: Duration::from_millis(500),
+            ..Config::default()
+        }
+    }
+
+
+    /// A connected pair of streams, the client's end and the server's.
+    async fn connect() -> (TcpStream, TcpStream, String) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (server, peer) = accepted.unwrap();
+        (client.unwrap(), server, peer.to_string())
+    }
+
+
+    /// Send the raw bytes instead of an upgrade request, the server should hang up rather than
+    /// panic.
+    async fn admit_raw(bytes: &[u8]) -> Result<Accepted> {
+        let (mut client, server, peer) = connect().await;
+        client.write_all(bytes).await.unwrap();
+        // a half sent request would otherwise keep the upgrade waiting
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let world = config().world_description();
+        admit(1, &peer, Socket::Tcp(server), None, None, &config(), &world).await
+    }
+
+
+    /// Upgrade to a websocket and send `frame` instead of a hello. Returns the server's verdict
+    /// and the close code the client saw.
+    async fn admit_frame(frame: WebSocketMessage) -> (Result<Accepted>, Option<CloseCode>) {
+        let (client, server, peer) = connect().await;
+        let world = config().world_description();
+        let config = config();
+
+        let client = async move {
+            let (mut ws, _) = client_async("ws://localhost/", client).await.unwrap();
+            ws.send(frame).await.unwrap();
+            loop {
+                match ws.next().await {
+                    Some(Ok(WebSocketMessage::Close(frame))) => return frame.map(|frame| frame.code),
+                    Some(Ok(_)) => continue,
+                    _ => return None,
+                }
+            }
+        };
+
+        tokio::join!(
+            admit(1, &peer, Socket::Tcp(server), None, None, &config, &world),
+            client
+        )
+    }
+
+
+    /// Admit a framed TCP client as `connection` with `config` and serve it like the server would.
+    /// Returns the client's end, the server's handle on it and what it tells the server.
+    async fn serve_framed(
+        connection: ConnectionId,
+        mut config: Config,
+    ) -> (Frames, ClientHandle, Receiver<ClientEvent>) {
+        config.transport = Transport::Tcp;
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let world = config.world_description();
+
+        let server = async {
+            let (stream, peer) = listener.accept().await.unwrap();
+            admit(
+                connection,
+                &peer.to_string(),
+                Socket::Tcp(stream),
+                None,
+                None,
+                &config,
+                &world,
+            )
+            .await
+        };
+        let client = async {
+            let tls = crate::tls::connector(None).unwrap();
+            let (mut frames, codec) = crate::transport::connect(&url, MessageCodec::Json, None, &tls)
+                .await
+                .unwrap();
+            let hello = crate::message::Hello::new(Features::supported());
+            frames
+                .send(codec.encode(&Message::Hello(hello)).unwrap().into())
+                .await
+                .unwrap();
+            assert!(matches!(frames.next().await, Some(Ok(WebSocketMessage::Text(_)))));
+            frames
+        };
+
+        let (admitted, frames) = tokio::join!(server, client);
+        let (events, received) = channel(8);
+        let metrics = Arc::new(Metrics::new());
+        let client = ClientHandle::spawn(admitted.unwrap(), &config, &metrics, events);
+        (frames, client, received)
+    }
+
+
+    #[test]
+    fn plain_http_is_not_admitted() {
+        runtime().block_on(async {
+            let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            assert!(admit_raw(request).await.is_err());
+        });
+    }
+
+
+    #[test]
+    fn random_bytes_are_not_admitted() {
+        let mut rng = StdRng::seed_from_u64(0x05ca_99e2);
+        runtime().block_on(async {
+            for _ in 0..ROUNDS {
+                let len = rng.gen_range(0, 1024);
+                let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                assert!(admit_raw(&bytes).await.is_err());
+            }
+        });
+    }
+
+
+    #[test]
+    fn random_hellos_are_closed_with_a_close_code() {
+        let mut rng = StdRng::seed_from_u64(0x0004_e110);
+        runtime().block_on(async {
+            for _ in 0..ROUNDS {
+                let len = rng.gen_range(0, 512);
+                let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+                let (admitted, code) = admit_frame(WebSocketMessage::Binary(bytes)).await;
+                assert!(admitted.is_err());
+                assert!(
+                    matches!(code, Some(CloseCode::Invalid) | Some(CloseCode::Protocol)),
+                    "unexpected close code: {:?}",
+                    code
+                );
+            }
+        });
+    }
+
+
+    #[test]
+    fn commands_instead_of_a_hello_are_a_protocol_error() {
+        runtime().block_on(async {
+            let command = crate::message::Command {
+                id:   1,
+                kind: crate::message::CommandKind::Pause,
+            };
+            let frame = MessageCodec::Bincode.encode(&Message::Command(command)).unwrap();
+
+            let (admitted, code) = admit_frame(frame.into()).await;
+            assert!(admitted.is_err());
+            assert_eq!(code, Some(CloseCode::Protocol));
+        });
+    }
+
+
+    #[test]
+    fn framed_tcp_clients_are_admitted() {
+        runtime().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("tcp://{}", listener.local_addr().unwrap());
+            let world = config().world_description();
+            let mut config = config();
+            config.transport = Transport::Tcp;
+
+            let server = async {
+                let (stream, peer) = listener.accept().await.unwrap();
+                admit(
+                    1,
+                    &peer.to_string(),
+                    Socket::Tcp(stream),
+                    None,
+                    None,
+                    &config,
+                    &world,
+                )
+                .await
+            };
+            let client = async {
+                let tls = crate::tls::connector(None).unwrap();
+                let (mut frames, codec) = crate::transport::connect(&url, MessageCodec::Json, None, &tls)
+                    .await
+                    .unwrap();
+                let hello = crate::message::Hello::new(Features::supported()).with_name(Some("tcp"));
+                frames
+                    .send(codec.encode(&Message::Hello(hello)).unwrap().into())
+                    .await
+                    .unwrap();
+                match frames.next().await {
+                    Some(Ok(WebSocketMessage::Text(text))) => {
+                        codec.decode::<Message>(text.as_bytes()).unwrap()
+                    }
+                    other => panic!("expected a welcome, got: {:?}", other),
+                }
+            };
+
+            let (admitted, welcome) = tokio::join!(server, client);
+            let admitted = admitted.unwrap();
+            assert_eq!(admitted.codec, MessageCodec::Json);
+            assert_eq!(admitted.name.as_deref(), Some("tcp"));
+            assert!(matches!(welcome, Message::Welcome(welcome) if welcome.client == 1));
+        });
+    }
+
+
+    #[test]
+    fn clients_answering_pings_stay_and_quiet_ones_are_hung_up_on() {
+        runtime().block_on(async {
+            let mut config = config();
+            config.heartbeat_interval = Duration::from_millis(20);
+            config.idle_timeout = Duration::from_millis(200);
+            let (mut frames, _client, mut events) = serve_framed(1, config).await;
+
+            let served = async {
+                // the pongs are all the client sends, which keeps it connected past the timeout
+                let answering = std::time::Instant::now();
+                let mut pings = 0;
+                while answering.elapsed() < Duration::from_millis(500) {
+                    match frames.next().await {
+                        Some(Ok(WebSocketMessage::Ping(payload))) => {
+                            pings += 1;
+                            frames.send(WebSocketMessage::Pong(payload)).await.unwrap();
+                        }
+                        other => panic!("expected a ping, got: {:?}", other),
+                    }
+                }
+                assert!(pings >= 10, "only pinged {} times", pings);
+
+                loop {
+                    match frames.next().await {
+                        Some(Ok(WebSocketMessage::Ping(_))) => continue,
+                        Some(Ok(WebSocketMessage::Close(frame))) => return frame.map(|frame| frame.code),
+                        other => panic!("expected to be hung up on, got: {:?}", other),
+                    }
+                }
+            };
+            let code = tokio::time::timeout(Duration::from_secs(5), served)
+                .await
+                .unwrap();
+            assert_eq!(code, Some(CloseCode::Policy));
+
+            match events.recv().await {
+                Some(ClientEvent::Closed { connection, reason }) => {
+                    assert_eq!(connection, 1);
+                    assert!(reason.contains("nothing heard"), "unexpected reason: {}", reason);
+                }
+                other => panic!("expected the client to be closed, got: {:?}", other),
+            }
+        });
+    }
+
+
+    #[test]
+    fn dispatches_reach_the_clients_they_are_for() {
+        runtime().block_on(async {
+            let mut clients = SmallVec::<[ClientHandle; 32]>::new();
+            let mut frames = Vec::new();
+            for connection in 1..=3 {
+                let (client_frames, client, _events) = serve_framed(connection, config()).await;
+                frames.push(client_frames);
+                clients.push(client);
+            }
+
+            let result = |id| Message::CommandResult(CommandResult::err(id, "routed"));
+            let sent = vec![
+                Dispatch::To {
+                    clients: vec![2],
+                    message: result(1),
+                },
+                Dispatch::Except {
+                    clients: vec![2],
+                    message: result(2),
+                },
+                Dispatch::To {
+                    clients: vec![1, 3, 7],
+                    message: result(3),
+                },
+                Dispatch::Broadcast(result(4)),
+            ];
+            for sent in sent {
+                route(&mut clients, None, sent);
+            }
+
+            // every client is sent the last result, whatever came before it is what it was sent
+            let mut received = Vec::new();
+            for client in frames.iter_mut() {
+                let mut ids = Vec::new();
+                while ids.last() != Some(&4) {
+                    let frame = tokio::time::timeout(Duration::from_secs(5), client.next()).await;
+                    match frame.unwrap() {
+                        Some(Ok(WebSocketMessage::Text(text))) => {
+                            match MessageCodec::Json.decode::<Message>(text.as_bytes()).unwrap() {
+                                Message::CommandResult(result) => ids.push(result.id),
+                                other => panic!("expected a command result, got: {:?}", other),
+                            }
+                        }
+                        other => panic!("expected a command result, got: {:?}", other),
+                    }
+                }
+                received.push(ids);
+            }
+            assert_eq!(received, vec![vec![2, 3, 4], vec![1, 4], vec![2, 3, 4]]);
+        });
+    }
+
+    #[test]
+    fn tls_without_websockets_is_refused() {
+        runtime().block_on(async {
+            let mut config = config();
+            config.transport = Transport::Tcp;
+            config.port = 0;
+            config.tls = Some(TlsIdentity::new("cert.pem", "key.pem"));
+
+            let (service, _simulation) = crate::protocol::server_channel();
+            let served = WebSocketServer::new(config)
+                .run_until_shutdown(service, async {})
+                .await;
+            assert!(
+                matches!(served, Err(Error::Tls { .. })),
+                "served: {:?}",
+                served.err()
+            );
+        });
+    }
+
+
+    #[test]
+    fn ports_in_use_are_refused_before_serving() {
+        runtime().block_on(async {
+            let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut config = config();
+            config.port = taken.local_addr().unwrap().port();
+
+            let (service, _simulation) = crate::protocol::server_channel();
+            let served = WebSocketServer::new(config)
+                .run_until_shutdown(service, async {})
+                .await;
+            assert!(served.is_err());
+        });
+    }
 }
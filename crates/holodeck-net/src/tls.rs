@@ -0,0 +1,354 @@
+//! `wss://` support. The server wraps accepted connections in TLS when it is given a certificate
+//! and key, clients trust the usual web PKI roots and optionally a CA of their own, which is how
+//! self-signed certificates on the corporate network are trusted.
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+};
+
+use crate::deps::{
+    holodeck_core::{
+        Error,
+        Result,
+    },
+    tokio::net::TcpStream,
+    tokio_rustls::{
+        client,
+        rustls::{
+            internal::pemfile,
+            ClientConfig,
+            NoClientAuth,
+            ServerConfig,
+        },
+        server,
+        webpki::DNSNameRef,
+    },
+    tokio_tungstenite::{
+        client_async,
+        stream::Stream,
+        tungstenite::{
+            client::IntoClientRequest,
+            handshake::client::Response,
+        },
+        WebSocketStream,
+    },
+    webpki_roots,
+};
+
+pub use crate::deps::tokio_rustls::{
+    TlsAcceptor,
+    TlsConnector,
+};
+
+
+/// A connection accepted by the server, wrapped in TLS when the server has a [`TlsIdentity`].
+pub type ServerStream = Stream<TcpStream, server::TlsStream<TcpStream>>;
+
+/// A connection to a server, wrapped in TLS for `wss://` urls.
+pub type ClientStream = Stream<TcpStream, client::TlsStream<TcpStream>>;
+
+
+/// The certificate chain and private key a server presents to its clients, as PEM files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsIdentity {
+    /// the server's certificate followed by any intermediates
+    pub certificate: PathBuf,
+    /// a PKCS #8 or RSA private key
+    pub private_key: PathBuf,
+}
+
+
+impl TlsIdentity {
+    pub fn new<C, K>(
+        certificate: C,
+        private_key: K,
+    ) -> Self
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        TlsIdentity {
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+        }
+    }
+
+    /// Load the certificate and key, failing if either file is missing or does not hold what
+    /// it should.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certificates = pemfile::certs(&mut open(&self.certificate)?)
+            .map_err(|()| tls_error(format!("{} is not a PEM file", self.certificate.display())))?;
+        if certificates.is_empty() {
+            return Err(tls_error(format!(
+                "no certificates in {}",
+                self.certificate.display()
+            )));
+        }
+
+        let mut keys = pemfile::pkcs8_private_keys(&mut open(&self.private_key)?).unwrap_or_default();
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(&self.private_key)?).unwrap_or_default();
+        }
+        let key = keys
+            .into_iter()
+            .next()
+            .ok_or_else(|| tls_error(format!("no private key in {}", self.private_key.display())))?;
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(certificates, key)
+            .map_err(|err| tls_error(format!("the certificate does not go with the key: {}", err)))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+
+/// A connector trusting the web PKI roots, along with the certificates in the PEM file `ca`
+/// when given one.
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    if let Some(ca) = ca {
+        let (added, _ignored) = config
+            .root_store
+            .add_pem_file(&mut open(ca)?)
+            .map_err(|()| tls_error(format!("{} is not a PEM file", ca.display())))?;
+        if added == 0 {
+            return Err(tls_error(format!("no usable certificates in {}", ca.display())));
+        }
+    }
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+
+/// Connect to a `ws://` or `wss://` url, the websocket counterpart to
+/// [`tokio_tungstenite::connect_async`] using `tls` for secure urls.
+pub async fn connect_async<R>(
+    request: R,
+    tls: &TlsConnector,
+) -> Result<(WebSocketStream<ClientStream>, Response)>
+where
+    R: IntoClientRequest + Unpin,
+{
+    let request = request.into_client_request()?;
+    let uri = request.uri();
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        other => return Err(tls_error(format!("unsupported url scheme: {:?}", other))),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| tls_error(format!("no host in {}", uri)))?
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+    let stream = if secure {
+        let name = DNSNameRef::try_from_ascii_str(&host)
+            .map_err(|_| tls_error(format!("{} is not a valid DNS name", host)))?;
+        let tls = tls
+            .connect(name, tcp)
+            .await
+            .map_err(|err| tls_error(format!("the TLS handshake with {} failed: {}", host, err)))?;
+        Stream::Tls(tls)
+    } else {
+        Stream::Plain(tcp)
+    };
+
+    Ok(client_async(request, stream).await?)
+}
+
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path).map(BufReader::new).map_err(|err| {
+        Error::SystemIo {
+            err,
+            message: format!("could not open {}", path.display()).into(),
+        }
+    })
+}
+
+
+fn tls_error(reason: String) -> Error {
+    Error::Tls {
+        reason: reason.into(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deps::{
+            futures::SinkExt,
+            futures_util::StreamExt,
+            tokio::{
+                net::TcpListener,
+                runtime::Runtime,
+                task::JoinHandle,
+            },
+        },
+        message::{
+            Codec,
+            Features,
+            Hello,
+            Message,
+            MessageCodec,
+            WebSocketMessage,
+        },
+        server::{
+            admit,
+            Accepted,
+            Config,
+        },
+        transport::Socket,
+    };
+
+    /// A self-signed certificate for localhost, written to PEM files in a fresh directory.
+    struct SelfSigned {
+        dir:      PathBuf,
+        identity: TlsIdentity,
+    }
+
+
+    impl SelfSigned {
+        fn generate(name: &str) -> Self {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let dir = std::env::temp_dir().join(format!("holodeck-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let identity = TlsIdentity::new(dir.join("cert.pem"), dir.join("key.pem"));
+            std::fs::write(&identity.certificate, cert.serialize_pem().unwrap()).unwrap();
+            std::fs::write(&identity.private_key, cert.serialize_private_key_pem()).unwrap();
+
+            SelfSigned { dir, identity }
+        }
+    }
+
+
+    impl Drop for SelfSigned {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+
+    fn runtime() -> Runtime {
+        crate::deps::tokio::runtime::Builder::new()
+            .enable_all()
+            .basic_scheduler()
+            .build()
+            .unwrap()
+    }
+
+
+    /// Admit a single connection the way the server does, wrapping it in TLS with `acceptor`.
+    /// Returns the port to reach it on and the server's verdict.
+    async fn serve(acceptor: TlsAcceptor) -> (u16, JoinHandle<Result<Accepted>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let admitted = crate::deps::tokio::spawn(async move {
+            let (tcp, peer) = listener.accept().await.unwrap();
+            let config = Config::default();
+            let world = config.world_description();
+            admit(
+                1,
+                &peer.to_string(),
+                Socket::Tcp(tcp),
+                Some(&acceptor),
+                None,
+                &config,
+                &world,
+            )
+            .await
+        });
+        (port, admitted)
+    }
+
+
+    #[test]
+    fn self_signed_certificates_are_trusted_given_the_ca() {
+        let cert = SelfSigned::generate("trusted");
+        let acceptor = cert.identity.acceptor().unwrap();
+        let tls = connector(Some(&cert.identity.certificate)).unwrap();
+
+        runtime().block_on(async {
+            let (port, admitted) = serve(acceptor).await;
+            let url = format!("wss://localhost:{}", port);
+            let (mut ws, _) = connect_async(url.as_str(), &tls).await.unwrap();
+
+            let hello = MessageCodec::Bincode
+                .encode(&Message::Hello(Hello::new(Features::supported())))
+                .unwrap();
+            ws.send(hello.into()).await.unwrap();
+            match ws.next().await {
+                Some(Ok(WebSocketMessage::Binary(welcome))) => {
+                    let welcome = MessageCodec::Bincode.decode::<Message>(&welcome).unwrap();
+                    assert!(matches!(welcome, Message::Welcome(welcome) if welcome.client == 1));
+                }
+                other => panic!("expected a welcome, got: {:?}", other),
+            }
+            assert!(admitted.await.unwrap().is_ok());
+        });
+    }
+
+
+    #[test]
+    fn self_signed_certificates_are_not_trusted_by_default() {
+        let cert = SelfSigned::generate("untrusted");
+        let acceptor = cert.identity.acceptor().unwrap();
+        let tls = connector(None).unwrap();
+
+        runtime().block_on(async {
+            let (port, admitted) = serve(acceptor).await;
+            let url = format!("wss://localhost:{}", port);
+            assert!(matches!(
+                connect_async(url.as_str(), &tls).await,
+                Err(Error::Tls { .. })
+            ));
+            assert!(matches!(admitted.await.unwrap(), Err(Error::Tls { .. })));
+        });
+    }
+
+
+    #[test]
+    fn plaintext_clients_are_not_admitted_by_tls_servers() {
+        let cert = SelfSigned::generate("plaintext");
+        let acceptor = cert.identity.acceptor().unwrap();
+        let tls = connector(None).unwrap();
+
+        runtime().block_on(async {
+            let (port, admitted) = serve(acceptor).await;
+            let url = format!("ws://localhost:{}", port);
+            assert!(connect_async(url.as_str(), &tls).await.is_err());
+            assert!(matches!(admitted.await.unwrap(), Err(Error::Tls { .. })));
+        });
+    }
+
+
+    #[test]
+    fn missing_or_misplaced_files_are_errors() {
+        let cert = SelfSigned::generate("broken");
+
+        let missing = TlsIdentity::new(cert.dir.join("missing.pem"), &cert.identity.private_key);
+        assert!(missing.acceptor().is_err());
+
+        let swapped = TlsIdentity::new(&cert.identity.private_key, &cert.identity.certificate);
+        assert!(swapped.acceptor().is_err());
+
+        assert!(connector(Some(&cert.identity.private_key)).is_err());
+    }
+}
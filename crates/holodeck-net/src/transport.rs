@@ -0,0 +1,512 @@
+//! The ways clients connect. Websockets suit browsers and remote viewers, on the same host their
+//! framing is needless overhead and plain length prefixed frames over TCP, or over a Unix domain
+//! socket whose file permissions decide who may connect, do instead. Every transport carries
+//! websocket [`Message`]s, so neither the server nor its clients care which one a connection came
+//! in over.
+//!
+//! A framed connection starts with the client sending a text frame naming the codecs it would
+//! like, as it would in the `Sec-WebSocket-Protocol` header, and the server answering with the one
+//! it picked. The protocol handshake follows as usual.
+use std::{
+    fmt,
+    io,
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    deps::{
+        bytes::{
+            Buf,
+            BufMut,
+            BytesMut,
+        },
+        futures::{
+            Sink,
+            SinkExt,
+            Stream,
+        },
+        futures_util::StreamExt,
+        holodeck_core::{
+            Error,
+            Result,
+        },
+        tokio::net::{
+            TcpListener,
+            TcpStream,
+        },
+        tokio_tungstenite::tungstenite::{
+            client::IntoClientRequest,
+            http::{
+                header::AUTHORIZATION,
+                HeaderValue,
+            },
+            protocol::{
+                frame::coding::CloseCode,
+                CloseFrame,
+            },
+            Error as WsError,
+            Message,
+        },
+        tokio_util::codec::{
+            Decoder,
+            Encoder,
+            Framed,
+        },
+    },
+    message::MessageCodec,
+    protocol::Transport,
+    server::Config,
+    tls::{
+        self,
+        TlsConnector,
+    },
+};
+
+#[cfg(unix)]
+use crate::deps::tokio::net::{
+    UnixListener,
+    UnixStream,
+};
+
+
+const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+/// The largest frame a framed transport accepts, anything bigger is a broken or hostile peer.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+
+/// A connection over any transport, frames go in and out as websocket [`Message`]s.
+pub trait FrameStream:
+    Stream<Item = std::result::Result<Message, WsError>> + Sink<Message, Error = WsError> + Send + Unpin
+{
+}
+
+
+impl<T> FrameStream for T where
+    T: Stream<Item = std::result::Result<Message, WsError>> + Sink<Message, Error = WsError> + Send + Unpin
+{
+}
+
+
+pub type Frames = Box<dyn FrameStream>;
+
+
+/// Where the server's socket file goes unless configured otherwise.
+pub fn default_socket_path() -> PathBuf {
+    std::env::temp_dir().join("holodeck.sock")
+}
+
+
+/// Wrap a byte stream in the [`FrameCodec`].
+pub fn framed<S>(io: S) -> Framed<S, FrameCodec>
+where
+    S: crate::deps::tokio::io::AsyncRead + crate::deps::tokio::io::AsyncWrite,
+{
+    Framed::new(io, FrameCodec)
+}
+
+
+/// Connect to a `ws://`, `wss://`, `tcp://host:port` or `unix:///path/to/socket` url asking for
+/// `codec`, returns the connection and the codec the server picked. Only websockets have
+/// somewhere to put the bearer `token`, and only they use `tls`.
+pub async fn connect(
+    url: &str,
+    codec: MessageCodec,
+    token: Option<&str>,
+    tls: &TlsConnector,
+) -> Result<(Frames, MessageCodec)> {
+    let (_, address) = url.split_once("://").ok_or_else(|| bad_url(url))?;
+
+    match Transport::from_url(url) {
+        Some(Transport::WebSocket) => {
+            let mut request = url.into_client_request().map_err(|_| bad_url(url))?;
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(codec.protocol()));
+            if let Some(token) = token {
+                let bearer = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+                    Error::BadValue {
+                        from:  "str".into(),
+                        to:    "HeaderValue".into(),
+                        value: "<token>".into(),
+                    }
+                })?;
+                request.headers_mut().insert(AUTHORIZATION, bearer);
+            }
+
+            let (socket, response) = tls::connect_async(request, tls).await?;
+            // servers which predate codec negotiation ignore the subprotocol and speak bincode
+            let accepted = response
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|accepted| accepted.to_str().ok())
+                .and_then(MessageCodec::from_protocol)
+                .unwrap_or_default();
+            Ok((Box::new(socket), accepted))
+        }
+        Some(Transport::Tcp) => {
+            let mut frames: Frames = Box::new(framed(TcpStream::connect(address).await?));
+            let accepted = request_codec(&mut frames, codec).await?;
+            Ok((frames, accepted))
+        }
+        #[cfg(unix)]
+        Some(Transport::Unix) => {
+            let mut frames: Frames = Box::new(framed(UnixStream::connect(address).await?));
+            let accepted = request_codec(&mut frames, codec).await?;
+            Ok((frames, accepted))
+        }
+        _ => Err(bad_url(url)),
+    }
+}
+
+
+/// Client side of the codec negotiation on a framed connection.
+async fn request_codec(
+    frames: &mut Frames,
+    codec: MessageCodec,
+) -> Result<MessageCodec> {
+    frames.send(Message::Text(codec.protocol().to_string())).await?;
+    match frames.next().await {
+        Some(Ok(Message::Text(accepted))) => Ok(MessageCodec::from_protocol(accepted).unwrap_or_default()),
+        Some(Ok(other)) => {
+            Err(Error::Handshake {
+                reason: format!("expected the server's codec, got: {:?}", other).into(),
+            })
+        }
+        Some(Err(err)) => Err(err.into()),
+        None => {
+            Err(Error::Handshake {
+                reason: "connection closed before the server picked a codec".into(),
+            })
+        }
+    }
+}
+
+
+/// Server side of the codec negotiation on a framed connection, falling back to the default
+/// codec when the client offers none this build speaks.
+pub(crate) async fn select_codec(
+    frames: &mut Frames,
+    timeout: Duration,
+) -> Result<MessageCodec> {
+    use crate::deps::tokio::time;
+
+    let codec = match time::timeout(timeout, frames.next()).await {
+        Ok(Some(Ok(Message::Text(offered)))) => MessageCodec::select(offered).unwrap_or_default(),
+        Ok(Some(Ok(other))) => {
+            return Err(Error::Handshake {
+                reason: format!("expected the codecs offered, got: {:?}", other).into(),
+            })
+        }
+        Ok(Some(Err(err))) => return Err(err.into()),
+        Ok(None) => {
+            return Err(Error::Handshake {
+                reason: "connection closed before offering codecs".into(),
+            })
+        }
+        Err(_elapsed) => {
+            return Err(Error::Handshake {
+                reason: format!("no codecs offered within {:?}", timeout).into(),
+            })
+        }
+    };
+
+    frames.send(Message::Text(codec.protocol().to_string())).await?;
+    Ok(codec)
+}
+
+
+fn bad_url(url: &str) -> Error {
+    Error::BadValue {
+        from:  "str".into(),
+        to:    "url".into(),
+        value: url.to_string().into(),
+    }
+}
+
+
+/// How long to wait before accepting again after accepting a connection failed, running out of
+/// file descriptors for one does not clear up right away.
+pub(crate) const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+
+/// Where the server accepts connections for its [`Transport`].
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path:     PathBuf,
+    },
+}
+
+
+/// A connection accepted by a [`Listener`], before any handshakes.
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+
+impl Socket {
+    /// The address the other end connected from, the loopback for a unix domain socket since it
+    /// is on the same host.
+    pub(crate) fn peer_ip(&self) -> io::Result<IpAddr> {
+        match self {
+            Socket::Tcp(stream) => stream.peer_addr().map(|peer| peer.ip()),
+            #[cfg(unix)]
+            Socket::Unix(_) => Ok(IpAddr::from([127, 0, 0, 1])),
+        }
+    }
+}
+
+
+impl Listener {
+    pub(crate) async fn bind(config: &Config) -> io::Result<Listener> {
+        match config.transport {
+            Transport::WebSocket | Transport::Tcp => {
+                let addr = SocketAddr::new(config.ip, config.port);
+                TcpListener::bind(&addr).await.map(Listener::Tcp)
+            }
+            #[cfg(unix)]
+            Transport::Unix => {
+                let path = config.socket_path.clone();
+                // a socket file left behind by a server which did not shut down cleanly
+                if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                    use std::os::unix::fs::FileTypeExt;
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(&path)?;
+                    }
+                }
+                let listener = UnixListener::bind(&path)?;
+                Ok(Listener::Unix { listener, path })
+            }
+            #[cfg(not(unix))]
+            Transport::Unix => {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "unix domain sockets are not supported on this platform",
+                ))
+            }
+        }
+    }
+
+    /// The next connection and who it is from.
+    pub(crate) async fn accept(&mut self) -> io::Result<(Socket, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Socket::Tcp(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, path } => {
+                let (stream, _unnamed) = listener.accept().await?;
+                Ok((Socket::Unix(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for Listener {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => {
+                match listener.local_addr() {
+                    Ok(addr) => write!(f, "{}", addr),
+                    Err(_) => write!(f, "<tcp>"),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+const BINARY: u8 = 0;
+const TEXT: u8 = 1;
+const PING: u8 = 2;
+const PONG: u8 = 3;
+const CLOSE: u8 = 4;
+
+
+/// Frames as a big endian `u32` length, counting the tag byte which follows it, then a tag
+/// byte saying which kind of [`Message`] the payload is. A close frame's payload is the close
+/// code, big endian, followed by the reason.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameCodec;
+
+
+impl Decoder for FrameCodec {
+    type Error = WsError;
+    type Item = Message;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> std::result::Result<Option<Message>, WsError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len == 0 || len > MAX_FRAME_LEN {
+            return Err(invalid(format!("bad frame length: {}", len)));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let mut frame = src.split_to(len);
+        let tag = frame[0];
+        frame.advance(1);
+        let payload = frame.to_vec();
+
+        let message = match tag {
+            BINARY => Message::Binary(payload),
+            TEXT => {
+                Message::Text(String::from_utf8(payload).map_err(|_| invalid("text frame is not utf-8"))?)
+            }
+            PING => Message::Ping(payload),
+            PONG => Message::Pong(payload),
+            CLOSE if payload.is_empty() => Message::Close(None),
+            CLOSE if payload.len() >= 2 => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = String::from_utf8(payload[2..].to_vec())
+                    .map_err(|_| invalid("close reason is not utf-8"))?;
+                Message::Close(Some(CloseFrame {
+                    code:   CloseCode::from(code),
+                    reason: reason.into(),
+                }))
+            }
+            other => return Err(invalid(format!("bad frame tag: {}", other))),
+        };
+
+        Ok(Some(message))
+    }
+}
+
+
+impl Encoder<Message> for FrameCodec {
+    type Error = WsError;
+
+    fn encode(
+        &mut self,
+        message: Message,
+        dst: &mut BytesMut,
+    ) -> std::result::Result<(), WsError> {
+        let (tag, payload) = match message {
+            Message::Binary(bytes) => (BINARY, bytes),
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Ping(bytes) => (PING, bytes),
+            Message::Pong(bytes) => (PONG, bytes),
+            Message::Close(None) => (CLOSE, Vec::new()),
+            Message::Close(Some(frame)) => {
+                let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+                payload.extend_from_slice(frame.reason.as_bytes());
+                (CLOSE, payload)
+            }
+        };
+
+        let len = payload.len() + 1;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid(format!("frame too large: {}", len)));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        dst.put_u8(tag);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+
+fn invalid<S: Into<String>>(reason: S) -> WsError {
+    WsError::Io(io::Error::new(io::ErrorKind::InvalidData, reason.into()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let messages = vec![
+            Message::Binary(vec![1, 2, 3]),
+            Message::Text("hello".to_string()),
+            Message::Ping(vec![]),
+            Message::Pong(vec![9]),
+            Message::Close(None),
+            Message::Close(Some(CloseFrame {
+                code:   CloseCode::Policy,
+                reason: "too slow".into(),
+            })),
+        ];
+
+        let mut buffer = BytesMut::new();
+        for message in messages.iter().cloned() {
+            FrameCodec.encode(message, &mut buffer).unwrap();
+        }
+
+        // one byte at a time, as a slow socket would deliver them
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in buffer.iter() {
+            src.put_u8(*byte);
+            if let Some(message) = FrameCodec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages);
+    }
+
+
+    #[test]
+    fn bad_frames_are_refused() {
+        let oversized = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert!(FrameCodec.decode(&mut BytesMut::from(&oversized[..])).is_err());
+
+        let empty = 0u32.to_be_bytes();
+        assert!(FrameCodec.decode(&mut BytesMut::from(&empty[..])).is_err());
+
+        let unknown = [0, 0, 0, 1, 0xff];
+        assert!(FrameCodec.decode(&mut BytesMut::from(&unknown[..])).is_err());
+    }
+
+
+    #[test]
+    fn urls_without_a_scheme_are_bad_values() {
+        let mut runtime = crate::deps::tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let tls = crate::tls::connector(None).unwrap();
+        for url in &["localhost:8080", "tcp:/localhost:8080", "ftp://localhost:8080"] {
+            let connected = runtime.block_on(connect(url, MessageCodec::Bincode, None, &tls));
+            assert!(matches!(connected, Err(Error::BadValue { .. })), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,776 @@
+//! Simulation states over UDP. On a LAN a state lost to TCP's retransmits holds up every state
+//! behind it, while a lost datagram only costs the one state, which the next replaces anyway.
+//!
+//! A client asks for [`Features::UDP`](crate::message::Features::UDP) in its hello and is welcomed
+//! with a [`UdpSession`]: the server's UDP port and a token. The client sends the token to that
+//! port, from then on the server sends it full states as sequence numbered fragments no larger
+//! than the MTU, and everything else, commands and their results included, stays on the
+//! connection. The client keeps only the newest state it has every fragment of, anything older or
+//! incomplete is dropped. Deltas and partial states need every state to arrive, so deltas are not
+//! sent to UDP clients and partial states stay on the connection.
+//!
+//! The token travels in the clear, so it is not trusted much: it is only taken from the address
+//! the client connected from, and only the first address to present it is ever sent states. A
+//! datagram with a spoofed source cannot point the states at any host but the client, and a client
+//! whose address changes has to connect again.
+use std::{
+    collections::HashMap,
+    io,
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::{
+    deps::{
+        holodeck_core::{
+            Error,
+            Result,
+        },
+        log::{
+            debug,
+            info,
+            warn,
+        },
+        rand,
+        tokio::{
+            self,
+            net::{
+                udp::{
+                    RecvHalf,
+                    SendHalf,
+                },
+                UdpSocket,
+            },
+        },
+    },
+    message::{
+        ConnectionId,
+        UdpSession,
+    },
+};
+
+
+/// The largest datagram sent unless configured otherwise, small enough to make it through
+/// tunnels and VPNs without being fragmented by IP.
+pub const DEFAULT_MTU: usize = 1200;
+
+/// How often a client registers with the server again, in case the datagram was lost or a NAT
+/// forgot about it.
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// a tag byte, the sequence number, then the fragment's index and the number of fragments
+const HEADER_LEN: usize = 1 + 4 + 2 + 2;
+/// a tag byte, then the session token
+const REGISTER_LEN: usize = 1 + 8;
+
+const FRAGMENT: u8 = 0;
+const REGISTER: u8 = 1;
+
+
+/// Split a `snapshot` into datagrams of at most `mtu` bytes, each one a big endian header of the
+/// `sequence` number, the fragment's index and the number of fragments, then a slice of the
+/// snapshot. Fails when the snapshot needs more fragments than the header can count.
+pub fn fragment(
+    sequence: u32,
+    snapshot: &[u8],
+    mtu: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let chunk = mtu.saturating_sub(HEADER_LEN).max(1);
+    let count = ((snapshot.len() + chunk - 1) / chunk).max(1);
+    if count > u16::MAX as usize {
+        return Err(Error::BadValue {
+            from:  "snapshot".into(),
+            to:    "udp fragments".into(),
+            value: format!("{} bytes in fragments of {}", snapshot.len(), chunk).into(),
+        });
+    }
+
+    let fragments = (0..count)
+        .map(|index| {
+            let start = (index * chunk).min(snapshot.len());
+            let payload = &snapshot[start..(start + chunk).min(snapshot.len())];
+            let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len());
+            datagram.push(FRAGMENT);
+            datagram.extend_from_slice(&sequence.to_be_bytes());
+            datagram.extend_from_slice(&(index as u16).to_be_bytes());
+            datagram.extend_from_slice(&(count as u16).to_be_bytes());
+            datagram.extend_from_slice(payload);
+            datagram
+        })
+        .collect();
+
+    Ok(fragments)
+}
+
+
+/// What the [`Reassembler`] did with the datagrams it was given.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReassemblyStats {
+    /// snapshots put back together
+    pub delivered:  u64,
+    /// snapshots abandoned because a newer one started arriving before all of their fragments
+    pub incomplete: u64,
+    /// fragments of snapshots older than one already delivered or being put together
+    pub stale:      u64,
+    /// datagrams which are not fragments at all
+    pub malformed:  u64,
+}
+
+
+/// Puts snapshots back together from their fragments. Only the newest snapshot is worth waiting
+/// for: a fragment of a newer one abandons whatever is incomplete, fragments of anything older
+/// are dropped, and so are repeats of the snapshot last delivered.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    delivered: Option<u32>,
+    pending:   Option<Pending>,
+    stats:     ReassemblyStats,
+}
+
+
+#[derive(Debug)]
+struct Pending {
+    sequence:  u32,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing:   usize,
+}
+
+
+impl Reassembler {
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Add a fragment, returns the snapshot it completes if any.
+    pub fn push(
+        &mut self,
+        datagram: &[u8],
+    ) -> Option<Vec<u8>> {
+        let (sequence, index, count) = match parse_fragment(datagram) {
+            Some(header) => header,
+            None => {
+                self.stats.malformed += 1;
+                return None;
+            }
+        };
+
+        if self
+            .delivered
+            .map_or(false, |delivered| !is_newer(sequence, delivered))
+        {
+            self.stats.stale += 1;
+            return None;
+        }
+        match &self.pending {
+            Some(pending) if pending.sequence == sequence => {}
+            Some(pending) if is_newer(pending.sequence, sequence) => {
+                self.stats.stale += 1;
+                return None;
+            }
+            Some(_) => {
+                self.stats.incomplete += 1;
+                self.pending = None;
+            }
+            None => {}
+        }
+
+        let pending = self.pending.get_or_insert_with(|| {
+            Pending {
+                sequence,
+                fragments: vec![None; count],
+                missing: count,
+            }
+        });
+        if pending.fragments.len() != count {
+            self.stats.malformed += 1;
+            return None;
+        }
+        if pending.fragments[index].is_none() {
+            pending.fragments[index] = Some(datagram[HEADER_LEN..].to_vec());
+            pending.missing -= 1;
+        }
+        if pending.missing > 0 {
+            return None;
+        }
+
+        let pending = self.pending.take()?;
+        self.delivered = Some(sequence);
+        self.stats.delivered += 1;
+        Some(pending.fragments.into_iter().flatten().flatten().collect())
+    }
+}
+
+
+/// The sequence number, index and fragment count of a fragment.
+fn parse_fragment(datagram: &[u8]) -> Option<(u32, usize, usize)> {
+    if datagram.len() < HEADER_LEN || datagram[0] != FRAGMENT {
+        return None;
+    }
+    let sequence = u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]);
+    let index = u16::from_be_bytes([datagram[5], datagram[6]]) as usize;
+    let count = u16::from_be_bytes([datagram[7], datagram[8]]) as usize;
+    if index >= count {
+        return None;
+    }
+    Some((sequence, index, count))
+}
+
+
+/// Whether sequence number `a` comes after `b`, allowing for the sequence wrapping around.
+fn is_newer(
+    a: u32,
+    b: u32,
+) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+
+fn registration(token: u64) -> [u8; REGISTER_LEN] {
+    let mut datagram = [REGISTER; REGISTER_LEN];
+    datagram[1..].copy_from_slice(&token.to_be_bytes());
+    datagram
+}
+
+
+/// The server's UDP socket, shared by every client which gets its states over UDP.
+pub(crate) struct UdpEndpoint {
+    port:     u16,
+    mtu:      usize,
+    send:     tokio::sync::Mutex<SendHalf>,
+    recv:     tokio::sync::Mutex<RecvHalf>,
+    sessions: Mutex<HashMap<u64, Registration>>,
+}
+
+
+/// A client welcomed with a [`UdpSession`], and where its states go once it has registered.
+struct Registration {
+    connection: ConnectionId,
+    /// the address the client connected from, registrations from anywhere else are ignored
+    ip:         IpAddr,
+    peer:       Option<SocketAddr>,
+}
+
+
+impl Registration {
+    /// Whether a registration `from` is the client's, the first one from its address sets where
+    /// the states go and that never changes.
+    fn is_from(
+        &self,
+        from: SocketAddr,
+    ) -> bool {
+        // a client on the same host may come from any of its loopback addresses
+        let ip = from.ip() == self.ip || (from.ip().is_loopback() && self.ip.is_loopback());
+        ip && self.peer.map_or(true, |peer| peer == from)
+    }
+}
+
+
+impl UdpEndpoint {
+    pub(crate) async fn bind(
+        addr: SocketAddr,
+        mtu: usize,
+    ) -> io::Result<Arc<UdpEndpoint>> {
+        let socket = UdpSocket::bind(addr).await?;
+        let port = socket.local_addr()?.port();
+        let (recv, send) = socket.split();
+        Ok(Arc::new(UdpEndpoint {
+            port,
+            mtu,
+            send: tokio::sync::Mutex::new(send),
+            recv: tokio::sync::Mutex::new(recv),
+            sessions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Start a session for the client `connection` connected from `ip`, it ends when the returned
+    /// sender is dropped.
+    pub(crate) fn open(
+        self: &Arc<Self>,
+        connection: ConnectionId,
+        ip: IpAddr,
+    ) -> UdpSender {
+        let mut sessions = self.sessions.lock().expect("could not lock the udp sessions");
+        let token = loop {
+            let token = rand::random::<u64>();
+            if !sessions.contains_key(&token) {
+                break token;
+            }
+        };
+        sessions.insert(
+            token,
+            Registration {
+                connection,
+                ip,
+                peer: None,
+            },
+        );
+
+        UdpSender {
+            endpoint: self.clone(),
+            token,
+            sequence: 0,
+        }
+    }
+
+    /// Take in the registrations clients send, for as long as the server runs.
+    pub(crate) async fn serve(&self) {
+        let mut recv = self.recv.lock().await;
+        let mut buffer = [0u8; 64];
+        loop {
+            match recv.recv_from(&mut buffer).await {
+                Ok((len, from)) => self.register(&buffer[..len], from),
+                // most likely an ICMP port unreachable from a client which went away
+                Err(err) => debug!("could not receive a udp registration: {}", err),
+            }
+        }
+    }
+
+    fn register(
+        &self,
+        datagram: &[u8],
+        from: SocketAddr,
+    ) {
+        if datagram.len() != REGISTER_LEN || datagram[0] != REGISTER {
+            debug!("ignoring a {} byte datagram from {}", datagram.len(), from);
+            return;
+        }
+        let mut token = [0u8; 8];
+        token.copy_from_slice(&datagram[1..]);
+
+        let mut sessions = self.sessions.lock().expect("could not lock the udp sessions");
+        match sessions.get_mut(&u64::from_be_bytes(token)) {
+            Some(registration) if !registration.is_from(from) => {
+                debug!(
+                    "ignoring client {}'s udp session from {}",
+                    registration.connection, from
+                );
+            }
+            Some(registration) if registration.peer.is_none() => {
+                info!(
+                    "client {} gets its states over udp at {}",
+                    registration.connection, from
+                );
+                registration.peer = Some(from);
+            }
+            // the client keeping its NAT's mapping alive
+            Some(_) => {}
+            None => debug!("ignoring an unknown udp session from {}", from),
+        }
+    }
+}
+
+
+/// The server's end of a [`UdpSession`], sends one client its states.
+pub(crate) struct UdpSender {
+    endpoint: Arc<UdpEndpoint>,
+    token:    u64,
+    sequence: u32,
+}
+
+
+impl UdpSender {
+    /// What the client is told in its welcome.
+    pub(crate) fn session(&self) -> UdpSession {
+        UdpSession {
+            port:  self.endpoint.port,
+            token: self.token,
+        }
+    }
+
+    /// Where the client's states go, `None` until it registers.
+    pub(crate) fn peer(&self) -> Option<SocketAddr> {
+        self.endpoint
+            .sessions
+            .lock()
+            .expect("could not lock the udp sessions")
+            .get(&self.token)
+            .and_then(|registration| registration.peer)
+    }
+
+    /// Send `snapshot` to `peer` as the next in the sequence, returns the bytes sent. A
+    /// datagram which cannot be sent is as good as lost, the rest are sent anyway.
+    pub(crate) async fn send(
+        &mut self,
+        peer: SocketAddr,
+        snapshot: &[u8],
+    ) -> Result<usize> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let fragments = fragment(self.sequence, snapshot, self.endpoint.mtu)?;
+
+        let mut socket = self.endpoint.send.lock().await;
+        let mut sent = 0;
+        for datagram in fragments.iter() {
+            match socket.send_to(datagram, &peer).await {
+                Ok(len) => sent += len,
+                Err(err) => warn!("could not send a fragment to {}: {}", peer, err),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+
+impl Drop for UdpSender {
+    fn drop(&mut self) {
+        self.endpoint
+            .sessions
+            .lock()
+            .expect("could not lock the udp sessions")
+            .remove(&self.token);
+    }
+}
+
+
+/// The client's end of a [`UdpSession`]. Registers with the server and puts the states it sends
+/// back together, only datagrams from the server are let through.
+pub struct SnapshotReceiver {
+    socket:      UdpSocket,
+    token:       u64,
+    registered:  Option<Instant>,
+    reassembler: Reassembler,
+    buffer:      Vec<u8>,
+}
+
+
+impl SnapshotReceiver {
+    /// Receive the states of `session` from the server on `host`.
+    pub async fn connect(
+        host: &str,
+        session: UdpSession,
+    ) -> Result<SnapshotReceiver> {
+        let server = tokio::net::lookup_host((host, session.port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                Error::BadValue {
+                    from:  "str".into(),
+                    to:    "SocketAddr".into(),
+                    value: host.to_string().into(),
+                }
+            })?;
+        let local = if server.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+
+        Ok(SnapshotReceiver {
+            socket,
+            token: session.token,
+            registered: None,
+            reassembler: Reassembler::default(),
+            buffer: vec![0; u16::MAX as usize],
+        })
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.reassembler.stats()
+    }
+
+    /// The next complete snapshot, registering with the server every [`REGISTER_INTERVAL`]
+    /// along the way.
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if self
+                .registered
+                .map_or(true, |at| at.elapsed() >= REGISTER_INTERVAL)
+            {
+                self.socket.send(&registration(self.token)).await?;
+                self.registered = Some(Instant::now());
+            }
+
+            let received = tokio::time::timeout(REGISTER_INTERVAL, self.socket.recv(&mut self.buffer));
+            let len = match received.await {
+                Ok(received) => received?,
+                Err(_elapsed) => continue,
+            };
+            if let Some(snapshot) = self.reassembler.push(&self.buffer[..len]) {
+                return Ok(snapshot);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{
+        rngs::StdRng,
+        seq::SliceRandom,
+        Rng,
+        SeedableRng,
+    };
+
+    const MTU: usize = 256;
+
+
+    /// A snapshot which says which one it is, so a mixed up one would be noticed.
+    fn snapshot(
+        sequence: u32,
+        len: usize,
+    ) -> Vec<u8> {
+        let mut snapshot = sequence.to_be_bytes().to_vec();
+        snapshot.extend((0..len).map(|i| (i as u32 ^ sequence) as u8));
+        snapshot
+    }
+
+
+    fn sequence_of(snapshot: &[u8]) -> u32 {
+        let sequence = u32::from_be_bytes([snapshot[0], snapshot[1], snapshot[2], snapshot[3]]);
+        assert_eq!(
+            snapshot,
+            &self::snapshot(sequence, snapshot.len() - 4)[..],
+            "mixed up snapshot"
+        );
+        sequence
+    }
+
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let mut rng = StdRng::seed_from_u64(0xf4a9);
+        let mut reassembler = Reassembler::default();
+
+        for sequence in 1..32 {
+            let expected = snapshot(sequence, rng.gen_range(0, 8 * MTU));
+            let mut fragments = fragment(sequence, &expected, MTU).unwrap();
+            assert!(fragments.iter().all(|datagram| datagram.len() <= MTU));
+
+            // duplicated and shuffled on the way
+            fragments.extend(fragments.clone());
+            fragments.shuffle(&mut rng);
+            let delivered: Vec<_> = fragments
+                .iter()
+                .filter_map(|datagram| reassembler.push(datagram))
+                .collect();
+            assert_eq!(delivered, vec![expected]);
+        }
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.delivered, 31);
+        assert_eq!(stats.incomplete, 0);
+    }
+
+
+    #[test]
+    fn older_and_incomplete_snapshots_are_discarded() {
+        let mut reassembler = Reassembler::default();
+        let fragments = |sequence| fragment(sequence, &snapshot(sequence, 3 * MTU), MTU).unwrap();
+
+        for datagram in fragments(2).iter() {
+            reassembler.push(datagram);
+        }
+        // arrives after a newer one
+        assert!(fragments(1)
+            .iter()
+            .all(|datagram| reassembler.push(datagram).is_none()));
+
+        // half of 3, then all of 4
+        let three = fragments(3);
+        assert!(reassembler.push(&three[0]).is_none());
+        let four: Vec<_> = fragments(4)
+            .iter()
+            .filter_map(|datagram| reassembler.push(datagram))
+            .collect();
+        assert_eq!(
+            four.iter()
+                .map(|snapshot| sequence_of(snapshot))
+                .collect::<Vec<_>>(),
+            vec![4]
+        );
+        // the rest of 3 is too late
+        assert!(three[1..]
+            .iter()
+            .all(|datagram| reassembler.push(datagram).is_none()));
+
+        assert!(reassembler.push(b"not a fragment").is_none());
+        let mut bad_index = fragments(5).remove(0);
+        bad_index[5..9].copy_from_slice(&[0, 9, 0, 9]);
+        assert!(reassembler.push(&bad_index).is_none());
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.delivered, 2);
+        assert_eq!(stats.incomplete, 1);
+        assert_eq!(stats.stale as usize, fragments(1).len() + three.len() - 1);
+        assert_eq!(stats.malformed, 2);
+    }
+
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(is_newer(0, u32::MAX));
+        assert!(is_newer(5, u32::MAX - 5));
+        assert!(!is_newer(u32::MAX, 0));
+        assert!(!is_newer(7, 7));
+    }
+
+
+    /// Relays datagrams between a client and the server, losing and reordering the server's.
+    /// Returns the port to reach the server at through it.
+    async fn lossy_relay(
+        server: SocketAddr,
+        loss: f64,
+        reorder: f64,
+        seed: u64,
+    ) -> u16 {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut buffer = vec![0u8; u16::MAX as usize];
+            let mut client = None;
+            let mut held: Option<Vec<u8>> = None;
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let datagram = buffer[..len].to_vec();
+                if from != server {
+                    client = Some(from);
+                    socket.send_to(&datagram, &server).await.unwrap();
+                    continue;
+                }
+
+                let client = match client {
+                    Some(client) if !rng.gen_bool(loss) => client,
+                    _ => continue,
+                };
+                if held.is_none() && rng.gen_bool(reorder) {
+                    held = Some(datagram);
+                    continue;
+                }
+                socket.send_to(&datagram, &client).await.unwrap();
+                if let Some(held) = held.take() {
+                    socket.send_to(&held, &client).await.unwrap();
+                }
+            }
+        });
+
+        port
+    }
+
+
+    /// Send `count` snapshots to a client over loopback through a [`lossy_relay`], returns the
+    /// sequence numbers of those the client put back together.
+    fn over_loopback(
+        count: u32,
+        loss: f64,
+        reorder: f64,
+    ) -> (Vec<u32>, ReassemblyStats) {
+        let mut rt = tokio::runtime::Builder::new()
+            .enable_all()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let endpoint = UdpEndpoint::bind(SocketAddr::from(([127, 0, 0, 1], 0)), MTU)
+                .await
+                .unwrap();
+            let mut sender = endpoint.open(1, IpAddr::from([127, 0, 0, 1]));
+            let server = SocketAddr::from(([127, 0, 0, 1], endpoint.port()));
+            let serving = endpoint.clone();
+            tokio::spawn(async move { serving.serve().await });
+
+            let relay = lossy_relay(server, loss, reorder, 0x10_55).await;
+            let session = UdpSession {
+                port: relay,
+                ..sender.session()
+            };
+            let mut receiver = SnapshotReceiver::connect("127.0.0.1", session).await.unwrap();
+            let receiving = tokio::spawn(async move {
+                let mut received = Vec::new();
+                let idle = Duration::from_millis(500);
+                while let Ok(snapshot) = tokio::time::timeout(idle, receiver.recv()).await {
+                    received.push(sequence_of(&snapshot.unwrap()));
+                }
+                (received, receiver.stats())
+            });
+
+            let peer = loop {
+                match sender.peer() {
+                    Some(peer) => break peer,
+                    None => tokio::time::delay_for(Duration::from_millis(5)).await,
+                }
+            };
+            for sequence in 1..=count {
+                sender.send(peer, &snapshot(sequence, 6 * MTU)).await.unwrap();
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+
+            receiving.await.unwrap()
+        })
+    }
+
+
+    #[test]
+    fn sessions_are_only_registered_once_and_from_the_client() {
+        let mut rt = tokio::runtime::Builder::new()
+            .enable_all()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let endpoint = rt
+            .block_on(UdpEndpoint::bind(SocketAddr::from(([127, 0, 0, 1], 0)), MTU))
+            .unwrap();
+        let sender = endpoint.open(1, IpAddr::from([10, 0, 0, 1]));
+        let token = registration(sender.session().token);
+
+        // a spoofed source
+        endpoint.register(&token, SocketAddr::from(([10, 0, 0, 2], 4000)));
+        assert_eq!(sender.peer(), None);
+
+        let client = SocketAddr::from(([10, 0, 0, 1], 4000));
+        endpoint.register(&token, client);
+        assert_eq!(sender.peer(), Some(client));
+        endpoint.register(&token, SocketAddr::from(([10, 0, 0, 1], 4001)));
+        endpoint.register(&token, SocketAddr::from(([10, 0, 0, 2], 4000)));
+        assert_eq!(sender.peer(), Some(client));
+
+        let local = endpoint.open(2, IpAddr::from([127, 0, 0, 1]));
+        let from = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 4000));
+        endpoint.register(&registration(local.session().token), from);
+        assert_eq!(local.peer(), Some(from));
+    }
+
+
+    #[test]
+    fn every_snapshot_arrives_without_loss() {
+        let (received, stats) = over_loopback(50, 0.0, 0.0);
+        assert_eq!(received, (1..=50).collect::<Vec<_>>());
+        assert_eq!(stats.incomplete, 0);
+    }
+
+
+    #[test]
+    fn lossy_links_only_deliver_complete_snapshots_in_order() {
+        let (received, stats) = over_loopback(200, 0.1, 0.05);
+
+        assert!(!received.is_empty(), "nothing made it through");
+        assert!(received.len() < 200, "nothing was lost");
+        assert!(
+            received.windows(2).all(|pair| pair[0] < pair[1]),
+            "out of order: {:?}",
+            received
+        );
+        assert!(stats.incomplete > 0);
+        assert_eq!(stats.delivered as usize, received.len());
+    }
+}
@@ -2,7 +2,7 @@ pub mod branch_optimization {
     // Branch prediction hint. This is currently only available on
     // nightly
     #[cfg(feature = "nightly")]
-    pub(in crate) use core::intrinsics::{
+    pub(crate) use core::intrinsics::{
         likely,
         unlikely,
     };
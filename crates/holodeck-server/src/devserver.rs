@@ -1,5 +1,7 @@
 use std::{
     collections::HashMap,
+    fmt,
+    path::PathBuf,
     sync::{
         atomic::{
             AtomicBool,
@@ -8,28 +10,49 @@ use std::{
         Arc,
     },
     thread,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use crate::deps::{
-    holodeck_core::Result,
+    holodeck_core::{
+        Error,
+        Result,
+    },
     holodeck_net::{
+        access::Access,
         message::{
+            Command,
+            CommandKind,
+            CommandResult,
+            ConnectionId,
+            Dispatch,
             Entity,
+            Kind,
+            Message,
+            Reply,
+            Role,
+            ServerEvent,
             SimulationState,
             SpawnRequest,
         },
+        metrics::Metrics,
         protocol::{
             server_channel,
             BackEnd,
             Recv,
+            Transport,
         },
         server::{
             Config,
             WebSocketServer,
         },
+        tls::TlsIdentity,
     },
     log::warn,
+    tokio::sync::oneshot,
 };
 
 use crate::{
@@ -47,30 +70,112 @@ use crate::{
     CommonArgs,
 };
 
-#[derive(Copy, Clone, Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 pub struct Args {
     /// the maximum number of entities
     #[structopt(long, default_value = "250")]
-    max_entities: u32,
+    max_entities:    u32,
     /// the number of entity spawns per tick, a N >= 1.0 will spawn at least
     /// int(N) entities per tick
     #[structopt(long, default_value = "0.3")]
-    spawn_chance: f32,
+    spawn_chance:    f32,
+    /// the chance per tick that a random entity is despawned
+    #[structopt(long, default_value = "0.0")]
+    despawn_chance:  f32,
     /// the simulation update rate
     #[structopt(long, default_value = "30.0")]
-    tick_hz:      f64,
+    tick_hz:         f64,
     /// the world size along each axis
     #[structopt(long, default_value = "1000.0")]
-    world_size:   f32,
+    world_size:      f32,
     /// how long to run the devserver in seconds (0 = no limit)
     #[structopt(long, default_value = "120")]
-    run_seconds:  u64,
+    run_seconds:     u64,
     /// the amount an entity may move per tick
     #[structopt(long, default_value = "0.5")]
-    target_speed: f32,
+    target_speed:    f32,
     /// report every N ticks
     #[structopt(long, default_value = "1")]
-    report_rate:  u8,
+    report_rate:     u8,
+    /// the size of the zones announced to viewers, 0 for none
+    #[structopt(long, default_value = "200.0")]
+    zone_size:       f32,
+    /// serve wss:// with the certificate chain in this PEM file, requires --tls-key and the
+    /// websocket transport
+    #[structopt(long, requires = "tls-key")]
+    tls_cert:        Option<PathBuf>,
+    /// the PEM private key for --tls-cert
+    #[structopt(long, requires = "tls-cert")]
+    tls_key:         Option<PathBuf>,
+    /// let in clients presenting TOKEN as ROLE (viewer, operator or admin), as TOKEN=ROLE
+    #[structopt(long = "token")]
+    tokens:          Vec<Grant>,
+    /// the role of clients without a token, they are turned away when tokens are given and
+    /// this is not
+    #[structopt(long)]
+    anonymous_role:  Option<Role>,
+    /// only let browsers in from these origins, e.g. https://holodeck.example
+    #[structopt(long = "allow-origin")]
+    allowed_origins: Vec<String>,
+    /// how clients connect: websocket, tcp or unix
+    #[structopt(long, default_value = "websocket")]
+    transport:       Transport,
+    /// where to listen with --transport unix, a holodeck.sock in the temp dir by default
+    #[structopt(long)]
+    socket_path:     Option<PathBuf>,
+    /// send states over UDP from this port to clients which ask for it
+    #[structopt(long)]
+    udp_port:        Option<u16>,
+    /// serve prometheus metrics over HTTP on this port
+    #[structopt(long)]
+    metrics_port:    Option<u16>,
+}
+
+
+/// A `--token`, the token is kept out of the logs.
+#[derive(Clone)]
+struct Grant {
+    token: String,
+    role:  Role,
+}
+
+
+impl std::str::FromStr for Grant {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.rsplitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(role), Some(token)) if !token.is_empty() => {
+                Ok(Grant {
+                    token: token.to_string(),
+                    role:  role.parse()?,
+                })
+            }
+            _ => {
+                Err(Error::BadValue {
+                    from:  "str".into(),
+                    to:    "TOKEN=ROLE".into(),
+                    value: s.to_string().into(),
+                })
+            }
+        }
+    }
+}
+
+
+impl fmt::Debug for Grant {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "Grant {{ token: <{} bytes>, role: {:?} }}",
+            self.token.len(),
+            self.role
+        )
+    }
 }
 
 
@@ -89,13 +194,37 @@ impl Server {
         config.simulation_world_size = args.world_size;
         config.max_entities = args.max_entities as usize;
         config.tick = Duration::from_secs_f64(1.0f64 / args.tick_hz);
+        config.zone_size = args.zone_size;
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            config.tls = Some(TlsIdentity::new(cert, key));
+        }
+        config.access = args
+            .tokens
+            .iter()
+            .fold(Access::default(), |access, grant| {
+                access.with_token(&grant.token, grant.role)
+            })
+            .with_anonymous(if args.tokens.is_empty() {
+                args.anonymous_role.or(Some(Role::Admin))
+            } else {
+                args.anonymous_role
+            });
+        config.access.allowed_origins = args.allowed_origins.clone();
+        config.transport = args.transport;
+        if let Some(path) = &args.socket_path {
+            config.socket_path = path.clone();
+        }
+        config.udp_port = args.udp_port;
+        config.metrics_port = args.metrics_port;
 
         info!("{:?} {:?} {:?}", common, args, config);
 
         let (viewer_channel, sim_channel) = server_channel();
         let run_condition = Arc::new(AtomicBool::new(true));
+        let (stop_server, server_stopped) = oneshot::channel::<()>();
 
-        let run_cond_server = run_condition.clone();
+        let server = WebSocketServer::new(config.clone());
+        let metrics = server.metrics();
         let client_server_handle = thread::spawn(move || {
             let mut rt = crate::deps::tokio::runtime::Builder::new()
                 .enable_all()
@@ -106,8 +235,11 @@ impl Server {
                 .unwrap();
 
             let fut = async move {
-                WebSocketServer::new(config)
-                    .run_until_shutdown(viewer_channel, run_cond_server)
+                server
+                    .run_until_shutdown(viewer_channel, async {
+                        // a dropped sender means shutdown all the same
+                        let _ = server_stopped.await;
+                    })
                     .await
                     .unwrap_or_else(|err| {
                         panic!("websocket server failed to shutdown gracefully: error={}", err)
@@ -124,9 +256,9 @@ impl Server {
 
         let run_cond_sim = run_condition.clone();
         let simulation_handle = {
-            let args = *args;
+            let args = args.clone();
             thread::spawn(move || {
-                run_sim(&args, config, sim_channel, run_cond_sim);
+                run_sim(&args, config, sim_channel, run_cond_sim, &metrics);
             })
         };
 
@@ -144,6 +276,7 @@ impl Server {
         thread::spawn(move || {
             debug!("simulation done, signaling server shutdown");
             run_condition.store(false, Ordering::SeqCst);
+            let _ = stop_server.send(());
             info!("waiting for server to shutdown...");
 
             client_server_handle
@@ -183,19 +316,38 @@ impl Velocity {
 
         Velocity { dx, dy }
     }
+
+    pub fn heading(&self) -> f32 {
+        f32::atan2(self.dy, self.dx)
+    }
+}
+
+
+impl From<Velocity> for [f32; 3] {
+    fn from(velocity: Velocity) -> Self {
+        [velocity.dx, velocity.dy, 0.0]
+    }
 }
 
 
 struct Simulation {
-    config:       Config,
-    next_id:      u64,
-    spawn_chance: f32,
-    target_speed: f32,
-    report_rate:  u64,
-    bounds:       AABB2<f32>,
-    state:        SimulationState,
-    movement:     HashMap<u64, Velocity>,
-    channel:      BackEnd<SimulationState, SpawnRequest>,
+    config:         Config,
+    next_id:        u64,
+    spawn_chance:   f32,
+    despawn_chance: f32,
+    target_speed:   f32,
+    report_rate:    u64,
+    /// how long a tick lasts, clients may change it
+    tick:           Duration,
+    paused:         bool,
+    /// ticks left to run while paused
+    steps:          u32,
+    bounds:         AABB2<f32>,
+    state:          SimulationState,
+    movement:       HashMap<u64, Velocity>,
+    /// the names of the connected clients which gave one, for the logs
+    names:          HashMap<ConnectionId, String>,
+    channel:        BackEnd<Dispatch, ServerEvent>,
 }
 
 
@@ -203,7 +355,7 @@ impl Simulation {
     pub fn new(
         args: &Args,
         config: Config,
-        channel: BackEnd<SimulationState, SpawnRequest>,
+        channel: BackEnd<Dispatch, ServerEvent>,
     ) -> Self {
         let x_min = -(config.simulation_world_size / 2.0);
         let y_min = -(config.simulation_world_size / 2.0);
@@ -211,19 +363,26 @@ impl Simulation {
         let y_max = config.simulation_world_size / 2.0;
 
         Simulation {
-            config,
             next_id: 1,
             spawn_chance: args.spawn_chance,
+            despawn_chance: args.despawn_chance,
             target_speed: args.target_speed,
             report_rate: std::cmp::max(args.report_rate as u64, 1),
+            tick: config.tick,
+            paused: false,
+            steps: 0,
             bounds: AABB2::with_min_max(x_min, y_min, x_max, y_max),
             state: SimulationState {
-                tick:         0,
-                entity_count: 0,
-                entities:     Vec::with_capacity(config.max_entities),
+                tick:          0,
+                entity_count:  0,
+                entities:      Vec::with_capacity(config.max_entities),
+                removed:       Vec::new(),
+                authoritative: true,
             },
             movement: HashMap::with_capacity(config.max_entities),
+            names: HashMap::new(),
             channel,
+            config,
         }
     }
 }
@@ -236,52 +395,180 @@ impl Simulation {
         id
     }
 
+    /// Spawn an entity with its bottom at the requested position.
     fn spawn_entity(
         &mut self,
-        x: f32,
-        y: f32,
+        request: SpawnRequest,
     ) -> Option<u64> {
         if self.state.entities.len() >= self.config.max_entities {
             return None;
         }
 
         let id = self.next_id();
-        self.state.entities.push(Entity {
-            id,
-            tag: 1,
-            x,
-            y,
-            z: 0.0,
-        });
-
         let velocity = Velocity::with_random_direction(self.target_speed);
+
+        let [length, width, height] = [3.0, 1.5, 1.5];
+        let tag = request.tag.unwrap_or(1);
+        let mut entity = Entity::new(id, tag, request.x, request.y, request.z + height / 2.0);
+        entity.kind = Some(request.kind.unwrap_or(Kind::Block));
+        entity.scale = Some([length, width, height]);
+        entity.set_yaw_pitch_roll(velocity.heading(), 0.0, 0.0);
+        entity.velocity = Some(velocity.into());
+        self.state.entities.push(entity);
+
         self.movement.insert(id, velocity);
         info!("spawned entity: {:?} {:?}", self.state.entities.last(), velocity);
         Some(id)
     }
 
+    fn despawn_entity(
+        &mut self,
+        index: usize,
+    ) {
+        let entity = self.state.entities.swap_remove(index);
+        self.movement.remove(&entity.id);
+        self.state.removed.push(entity.id);
+        info!("despawned entity: {:?}", entity);
+    }
+
     fn process_messages(&mut self) {
-        let recv: Recv<SpawnRequest> = (&mut *self.channel).recv();
-        match recv {
-            Recv::Msg(msg) => {
-                self.spawn_entity(msg.x, msg.y);
+        loop {
+            let recv: Recv<ServerEvent> = (&mut *self.channel).recv();
+            match recv {
+                Recv::Msg(ServerEvent::Connected {
+                    connection,
+                    peer,
+                    role,
+                    name,
+                }) => {
+                    info!(
+                        "client {} connected from {} as {:?}; name={:?}",
+                        connection, peer, role, name
+                    );
+                    if let Some(name) = name {
+                        self.names.insert(connection, name);
+                    }
+                }
+                Recv::Msg(ServerEvent::Disconnected { connection, reason }) => {
+                    info!("client {} disconnected: {}", connection, reason);
+                    self.names.remove(&connection);
+                }
+                Recv::Msg(ServerEvent::Command(envelope)) => {
+                    let sender = self.names.get(&envelope.client).cloned();
+                    info!(
+                        "client {} ({:?}) sent: {:?}",
+                        envelope.client, sender, envelope.body
+                    );
+
+                    let result = self.execute(envelope.body.clone());
+                    if let Err(err) = &result.outcome {
+                        warn!("command {} failed: {}", result.id, err.reason);
+                    }
+                    if let Err(err) = self.channel.send(&envelope.reply(result.outcome)) {
+                        warn!("could not reply to client {}: {}", envelope.client, err);
+                        break;
+                    }
+                }
+                Recv::Invalid => continue,
+                Recv::Empty | Recv::Disconnected => break,
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        command: Command,
+    ) -> CommandResult {
+        let Command { id, kind } = command;
+        info!("executing command {}: {:?}", id, kind);
+
+        match kind {
+            CommandKind::Spawn(request) => {
+                match self.spawn_entity(request) {
+                    Some(entity) => CommandResult::ok(id, Reply::Spawned { id: entity }),
+                    None => CommandResult::err(id, "the simulation is full"),
+                }
+            }
+            CommandKind::Despawn { id: entity } => {
+                match self.state.entities.iter().position(|e| e.id == entity) {
+                    Some(index) => {
+                        self.despawn_entity(index);
+                        CommandResult::ok(id, Reply::Done)
+                    }
+                    None => CommandResult::err(id, format!("no such entity: {}", entity)),
+                }
+            }
+            CommandKind::Move {
+                id: entity,
+                position: [x, y, z],
+            } => {
+                match self.state.entities.iter_mut().find(|e| e.id == entity) {
+                    Some(entity) => {
+                        entity.x = x;
+                        entity.y = y;
+                        entity.z = z;
+                        CommandResult::ok(id, Reply::Done)
+                    }
+                    None => CommandResult::err(id, format!("no such entity: {}", entity)),
+                }
+            }
+            CommandKind::Pause => {
+                self.paused = true;
+                self.steps = 0;
+                CommandResult::ok(id, Reply::Done)
+            }
+            CommandKind::Resume => {
+                self.paused = false;
+                CommandResult::ok(id, Reply::Done)
+            }
+            CommandKind::Step { .. } if !self.paused => {
+                CommandResult::err(id, "only a paused simulation can be stepped")
+            }
+            CommandKind::Step { ticks } => {
+                self.steps = self.steps.saturating_add(ticks);
+                CommandResult::ok(id, Reply::Done)
+            }
+            CommandKind::SetTickRate { hz } if !(hz.is_finite() && hz > 0.0) => {
+                CommandResult::err(id, format!("invalid tick rate: {}", hz))
+            }
+            CommandKind::SetTickRate { hz } => {
+                self.tick = Duration::from_secs_f64(1.0f64 / hz);
+                CommandResult::ok(id, Reply::Done)
+            }
+            CommandKind::Custom { name, .. } => {
+                CommandResult::err(id, format!("unsupported custom command: {}", name))
             }
-            Recv::Empty | Recv::Disconnected | Recv::Invalid => {}
         }
     }
 
     pub fn on_tick(&mut self) {
+        self.process_messages();
+
+        if self.paused {
+            if self.steps == 0 {
+                return;
+            }
+            self.steps -= 1;
+        }
+
         self.state.tick += 1;
 
+        let ground = SpawnRequest::at(0.0, 0.0, self.config.ground_height);
         let mut chance = self.spawn_chance;
         while chance >= 1.0 {
             chance -= 1.0;
-            self.spawn_entity(0.0, 0.0);
+            self.spawn_entity(ground);
         }
 
         let spawn_value: f32 = crate::deps::rand::random();
         if spawn_value < chance {
-            self.spawn_entity(0.0, 0.0);
+            self.spawn_entity(ground);
+        }
+
+        let despawn_value: f32 = crate::deps::rand::random();
+        if despawn_value < self.despawn_chance && !self.state.entities.is_empty() {
+            let index = crate::deps::rand::random::<usize>() % self.state.entities.len();
+            self.despawn_entity(index);
         }
 
         let bounds = &self.bounds;
@@ -308,6 +595,8 @@ impl Simulation {
                     entity.y = bounds.max.y;
                     velocity.dy = -velocity.dy;
                 }
+                entity.set_yaw_pitch_roll(velocity.heading(), 0.0, 0.0);
+                entity.velocity = Some((*velocity).into());
                 debug!("bounced entity: {:?}; {:?} -> {:?}", entity, old_vel, velocity);
             }
 
@@ -315,10 +604,18 @@ impl Simulation {
             entity.y += velocity.dy;
         }
 
-        let state = &self.state;
+        let state = &mut self.state;
         let channel = &mut self.channel;
         if state.tick % self.report_rate == 0 {
-            channel.send(&state);
+            let message = Dispatch::Broadcast(Message::State(std::mem::take(state)));
+            if let Err(err) = channel.send(&message) {
+                warn!("could not send the state to the server: {}", err);
+            }
+            if let Dispatch::Broadcast(Message::State(reported)) = message {
+                *state = reported;
+            }
+            // removals accumulate between reports so none are skipped
+            state.removed.clear();
         }
     }
 }
@@ -327,19 +624,22 @@ impl Simulation {
 fn run_sim(
     args: &Args,
     config: Config,
-    sim_channel: BackEnd<SimulationState, SpawnRequest>,
+    sim_channel: BackEnd<Dispatch, ServerEvent>,
     running: Arc<AtomicBool>,
+    metrics: &Metrics,
 ) {
     let mut simulation = Simulation::new(args, config, sim_channel);
 
 
     'update: while running.load(Ordering::Relaxed) {
-        let start = std::time::Instant::now();
-        let end = start + config.tick;
+        let start = Instant::now();
+        let end = start + simulation.tick;
 
         simulation.on_tick();
+        metrics.record_tick(start.elapsed(), simulation.tick);
+        metrics.set_entities(simulation.state.entities.len());
 
-        while std::time::Instant::now() < end {
+        while Instant::now() < end {
             std::sync::atomic::spin_loop_hint();
         }
     }
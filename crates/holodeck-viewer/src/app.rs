@@ -11,8 +11,14 @@ use crate::{
     config::Config,
     deps::{
         holodeck_core::messages::{
-            SimulationState,
-            SpawnRequest,
+            Command,
+            CommandId,
+            CommandKind,
+            CommandResult,
+            Interest,
+            Message,
+            Subscription,
+            TagSet,
         },
         kiss3d::{
             camera::Camera,
@@ -38,6 +44,7 @@ use crate::{
         log::{
             debug,
             info,
+            warn,
         },
         na,
         na::{
@@ -54,7 +61,7 @@ use crate::{
     world,
 };
 
-pub type ViewerChannel = Box<dyn BackendChannel<Tx = SpawnRequest, Rx = Box<SimulationState>>>;
+pub type ViewerChannel = Box<dyn BackendChannel<Tx = Message, Rx = Message>>;
 
 const ICON: &'static [u8] = include_bytes!("./holodeck.png");
 
@@ -74,18 +81,28 @@ pub trait BackendChannel {
 
 
 pub struct PlayerGameClient {
-    config:     Config,
-    world:      world::World,
-    client:     Client,
-    graphics:   engine::GraphicsManager,
-    cursor_pos: Point2<f32>,
-    frontend:   Option<ViewerChannel>,
+    config:       Config,
+    world:        world::World,
+    client:       Client,
+    graphics:     engine::GraphicsManager,
+    cursor_pos:   Point2<f32>,
+    frontend:     Option<ViewerChannel>,
+    /// the id of the last command sent to the simulation
+    last_command: CommandId,
+    /// the interest last reported to the server
+    reported:     Option<Interest>,
+    /// the entities the server is asked to send, `None` for all of them
+    subscription: Option<Subscription>,
     #[cfg(feature = "ui")]
-    hud:        crate::ui::HeadsUpDisplay,
+    hud:          crate::ui::HeadsUpDisplay,
 }
 
 
 impl PlayerGameClient {
+    /// The camera moves this fraction of the view radius away from the interest last reported
+    /// before it is reported again, well within the hysteresis the server gives it.
+    const INTEREST_SLACK: f32 = 0.05;
+
     pub fn run(frontend: Option<ViewerChannel>) {
         let config = Config::default();
 
@@ -125,6 +142,9 @@ impl PlayerGameClient {
             graphics,
             cursor_pos: Point2::new(0.0f32, 0.0),
             frontend,
+            last_command: 0,
+            reported: None,
+            subscription: None,
             #[cfg(feature = "ui")]
             hud: ui,
         });
@@ -146,6 +166,20 @@ impl PlayerGameClient {
                 }
             }
             WindowEvent::Key(Key::S, Action::Release, _) => self.client.run_mode = RunMode::Running,
+            WindowEvent::Key(Key::Pause, Action::Release, _) => {
+                if self.client.simulation == RunMode::Paused {
+                    self.send_command(CommandKind::Resume);
+                    self.client.simulation = RunMode::Running;
+                } else {
+                    self.send_command(CommandKind::Pause);
+                    self.client.simulation = RunMode::Paused;
+                }
+            }
+            WindowEvent::Key(Key::Period, Action::Release, _) => {
+                if self.client.simulation == RunMode::Paused {
+                    self.send_command(CommandKind::Step { ticks: 1 });
+                }
+            }
             // WindowEvent::Key(Key::Escape, Action::Release, _) => self.client.run_mode = RunMode::Paused,
             WindowEvent::Key(Key::P, Action::Release, _) => {
                 self.hud.minimap_mut().toggle_hidden();
@@ -153,6 +187,9 @@ impl PlayerGameClient {
             WindowEvent::Key(Key::Add, Action::Release, _) => {
                 self.hud.minimap_mut().toggle_size();
             }
+            WindowEvent::Key(Key::F, Action::Release, _) => {
+                self.hud.tags_mut().toggle_hidden();
+            }
             WindowEvent::Key(Key::R, Action::Release, _) => {
                 // self
                 //     .state
@@ -271,6 +308,73 @@ impl PlayerGameClient {
         _direction: Vector3<f32>,
     ) {
     }
+
+    /// Send a command to the simulation, returns its id or `None` when there is no backend.
+    fn send_command(
+        &mut self,
+        kind: CommandKind,
+    ) -> Option<CommandId> {
+        let frontend = self.frontend.as_ref()?;
+        self.last_command += 1;
+        let id = self.last_command;
+        frontend.send(Message::Command(Command { id, kind }));
+        Some(id)
+    }
+
+    /// Let the server know which part of the world the camera looks at, once the camera strayed
+    /// far enough from where it was last reported for it to matter.
+    fn report_interest(&mut self) {
+        let (frontend, radius) = match (self.frontend.as_ref(), self.config.view_radius) {
+            (Some(frontend), Some(radius)) => (frontend, radius),
+            _ => return,
+        };
+        let eye = self.graphics.camera().eye();
+        let interest = Interest::new(self.world.placement.position(&eye), radius);
+
+        let slack = radius * Self::INTEREST_SLACK;
+        let strayed = match self.reported {
+            Some(reported) => {
+                reported.radius != radius || reported.distance_sq(interest.center) > slack * slack
+            }
+            None => true,
+        };
+        if strayed {
+            frontend.send(Message::Interest(Some(interest)));
+            self.reported = Some(interest);
+        }
+    }
+
+    /// Ask the server to only send the entities with a tag which is not `unsubscribed`.
+    fn subscribe<I>(
+        &mut self,
+        unsubscribed: I,
+    ) where
+        I: IntoIterator<Item = u16>,
+    {
+        let unsubscribed = unsubscribed.into_iter().collect::<Vec<u16>>();
+        self.subscription = if unsubscribed.is_empty() {
+            None
+        } else {
+            Some(Subscription {
+                tags: TagSet::Except(unsubscribed),
+                ids:  None,
+            })
+        };
+
+        if let Some(frontend) = self.frontend.as_ref() {
+            frontend.send(Message::Subscription(self.subscription.clone()));
+        }
+    }
+
+    fn on_command_result(
+        &mut self,
+        result: CommandResult,
+    ) {
+        match result.outcome {
+            Ok(reply) => debug!("command {} succeeded: {:?}", result.id, reply),
+            Err(err) => warn!("command {} failed: {}", result.id, err.reason),
+        }
+    }
 }
 
 
@@ -290,15 +394,25 @@ impl State for PlayerGameClient {
         let point = self.graphics.camera().eye();
 
 
-        let mut last_state = None;
+        self.world.on_tick();
+        // deltas only make sense applied in order, so every queued update is processed
         while let Some(msg) = self.frontend.as_mut().and_then(|fe| fe.recv()) {
-            last_state = Some(msg);
+            match msg {
+                Message::CommandResult(result) => self.on_command_result(result),
+                msg @ Message::Welcome(_) => {
+                    // a new connection starts out sending the whole world
+                    self.reported = None;
+                    self.world.process(msg, window);
+                    if let (Some(frontend), Some(subscription)) = (&self.frontend, &self.subscription) {
+                        frontend.send(Message::Subscription(Some(subscription.clone())));
+                    }
+                }
+                msg => self.world.process(msg, window),
+            }
         }
+        self.report_interest();
 
-        self.world.on_tick();
-        if let Some(state) = last_state {
-            self.world.process(state, window);
-        } else {
+        if !self.world.updated {
             self.world.tick += 1;
         }
         self.world.update_position_and_direction(point, direction);
@@ -331,6 +445,10 @@ impl State for PlayerGameClient {
 
 
             hud.draw(&mut event);
+
+            if let Some(unsubscribed) = hud.tags_mut().unsubscribed().cloned() {
+                self.subscribe(unsubscribed);
+            }
         }
     }
 }
@@ -21,6 +21,8 @@ pub struct Config {
     pub field_of_view:        f32,
     pub znear:                f32,
     pub zfar:                 f32,
+    /// the server only sends the entities within this distance of the camera, `None` for all
+    pub view_radius:          Option<f32>,
 }
 
 
@@ -34,6 +36,10 @@ impl Config {
         2048.0
     }
 
+    pub fn default_view_radius() -> Option<f32> {
+        Some(750.0)
+    }
+
     pub fn default_znear() -> f32 {
         0.5
     }
@@ -80,6 +86,7 @@ impl Default for Config {
             field_of_view:        Self::default_field_of_view(),
             znear:                Self::default_znear(),
             zfar:                 Self::default_zfar(),
+            view_radius:          Self::default_view_radius(),
         }
     }
 }
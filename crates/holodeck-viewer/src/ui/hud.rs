@@ -12,6 +12,7 @@ use crate::ui::{
     DrawEvent,
     InfoPane,
     Minimap,
+    TagPane,
 };
 
 
@@ -27,6 +28,7 @@ pub struct HeadsUpDisplay {
     ids:       HudIds,
     minimap:   Minimap,
     info_pane: InfoPane,
+    tags:      TagPane,
 }
 
 impl HeadsUpDisplay {
@@ -40,6 +42,7 @@ impl HeadsUpDisplay {
             ids,
             minimap: Minimap::new(parent_id, window),
             info_pane: InfoPane::new(parent_id, window),
+            tags: TagPane::new(window),
         }
     }
 
@@ -51,6 +54,10 @@ impl HeadsUpDisplay {
         &mut self.minimap
     }
 
+    pub fn tags_mut(&mut self) -> &mut TagPane {
+        &mut self.tags
+    }
+
     pub fn theme() -> conrod::Theme {
         use conrod::position::{
             Align,
@@ -96,6 +103,7 @@ impl Draw for HeadsUpDisplay {
         //
 
         self.minimap.draw(event);
+        self.tags.draw(event);
         // self.info_pane.draw(event);
     }
 }
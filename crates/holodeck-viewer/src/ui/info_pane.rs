@@ -56,8 +56,8 @@ impl InfoPaneMode {
 
 pub struct InfoPaneApp {
     InfoPane_mode: InfoPaneMode,
-    /*    bitmap: RgbaImage,
-     *    bitmap: Bitmap, */
+    //    bitmap: RgbaImage,
+    //    bitmap: Bitmap,
 }
 
 
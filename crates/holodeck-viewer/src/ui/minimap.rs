@@ -73,8 +73,8 @@ impl MinimapMode {
 
 pub struct MinimapApp {
     minimap_mode: MinimapMode,
-    /*    bitmap: RgbaImage,
-     *    bitmap: Bitmap, */
+    //    bitmap: RgbaImage,
+    //    bitmap: Bitmap,
 }
 
 
@@ -2,6 +2,7 @@ mod draw;
 mod hud;
 mod info_pane;
 mod minimap;
+mod tag_pane;
 
 pub use self::{
     draw::{
@@ -21,4 +22,5 @@ pub use self::{
         InfoPaneApp,
         InfoPaneMode,
     },
+    tag_pane::TagPane,
 };
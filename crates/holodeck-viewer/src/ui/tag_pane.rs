@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeSet,
+    mem,
+};
+
+use crate::deps::kiss3d::conrod;
+
+use crate::deps::kiss3d::conrod::{
+    widget_ids,
+    Borderable,
+};
+
+use crate::deps::kiss3d::window::Window;
+
+use crate::{
+    theme::Color,
+    ui::{
+        Draw,
+        DrawEvent,
+    },
+    world::Tag,
+};
+
+
+
+// Generate a unique `WidgetId` for each widget.
+widget_ids! {
+    pub struct TagPaneIds {
+        bordered_background,
+        toggles[],
+        overflow,
+    }
+}
+
+
+/// A toggle for every tag seen so far, the server stops sending the entities of the tags which
+/// are toggled off rather than the viewer hiding them. Tags stay listed after their entities are
+/// no longer sent, so they can be toggled back on.
+pub struct TagPane {
+    ids:          TagPaneIds,
+    hidden:       bool,
+    unsubscribed: BTreeSet<Tag>,
+    changed:      bool,
+}
+
+impl TagPane {
+    const MARGIN: conrod::Scalar = 30.0;
+    const MAX_TAGS: usize = 32;
+    const PADDING: conrod::Scalar = 4.0;
+    const ROW_HEIGHT: conrod::Scalar = 20.0;
+    const ROW_WIDTH: conrod::Scalar = 120.0;
+
+    pub fn new(window: &mut Window) -> Self {
+        TagPane {
+            ids:          TagPaneIds::new(window.conrod_ui_mut().widget_id_generator()),
+            hidden:       true,
+            unsubscribed: BTreeSet::new(),
+            changed:      false,
+        }
+    }
+
+    pub fn toggle_hidden(&mut self) {
+        self.hidden = !self.hidden;
+    }
+
+    /// The tags toggled off, only once after they changed.
+    pub fn unsubscribed(&mut self) -> Option<&BTreeSet<Tag>> {
+        if mem::replace(&mut self.changed, false) {
+            Some(&self.unsubscribed)
+        } else {
+            None
+        }
+    }
+}
+
+impl Draw for TagPane {
+    fn draw(
+        &mut self,
+        event: &mut DrawEvent,
+    ) {
+        let DrawEvent { world, ui, .. } = event;
+
+        use conrod::{
+            widget,
+            Colorable,
+            Labelable,
+            Positionable,
+            Sizeable,
+            Widget,
+        };
+
+        if self.hidden {
+            return;
+        }
+
+        let tags = world.tags();
+        let count = tags.len().min(Self::MAX_TAGS);
+        let overflow = tags.len() - count;
+        if self.ids.toggles.len() < count {
+            self.ids.toggles.resize(count, &mut ui.widget_id_generator());
+        }
+
+        // the tags which do not fit are said to be there at least, below the ones which do
+        let rows = count + if overflow > 0 { 1 } else { 0 };
+        let row = Self::ROW_HEIGHT + Self::PADDING;
+        let size = [
+            Self::ROW_WIDTH + 2.0 * Self::PADDING,
+            rows as f64 * row + Self::PADDING,
+        ];
+        widget::BorderedRectangle::new(size)
+            .border(3.0)
+            .color(Color::holodeck_space_grey().with_a(0.3).into())
+            .border_color(Color::holodeck_plasma().with_a(0.3).into())
+            .top_left_with_margin(Self::MARGIN)
+            .set(self.ids.bordered_background, ui);
+
+        if overflow > 0 {
+            widget::Text::new(&format!("{} more tags", overflow))
+                .color(conrod::color::WHITE)
+                .font_size(12)
+                .top_left_with_margins_on(
+                    self.ids.bordered_background,
+                    Self::PADDING + count as f64 * row,
+                    Self::PADDING,
+                )
+                .set(self.ids.overflow, ui);
+        }
+
+        let rows = tags.iter().zip(self.ids.toggles.iter()).take(count);
+        for (i, (&tag, &id)) in rows.enumerate() {
+            let subscribed = !self.unsubscribed.contains(&tag);
+            let color = if subscribed {
+                world.tag_colors.get(&tag).copied().unwrap_or(Color::white())
+            } else {
+                Color::holodeck_space_grey()
+            };
+
+            let toggled = widget::Toggle::new(subscribed)
+                .label(&format!("tag {}", tag))
+                .label_color(conrod::color::WHITE)
+                .label_font_size(12)
+                .color(color.with_a(0.6).into())
+                .w_h(Self::ROW_WIDTH, Self::ROW_HEIGHT)
+                .top_left_with_margins_on(
+                    self.ids.bordered_background,
+                    Self::PADDING + i as f64 * row,
+                    Self::PADDING,
+                )
+                .set(id, ui);
+
+            for subscribe in toggled {
+                if subscribe {
+                    self.unsubscribed.remove(&tag);
+                } else {
+                    self.unsubscribed.insert(tag);
+                }
+                self.changed = true;
+            }
+        }
+    }
+}
@@ -4,11 +4,14 @@ use crate::deps::arraydeque::{
     ArrayDeque,
 };
 use crate::{
-    deps::na::{
-        Point3,
-        Translation3,
-        UnitQuaternion,
-        Vector3,
+    deps::{
+        holodeck_core::messages,
+        na::{
+            Point3,
+            Translation3,
+            UnitQuaternion,
+            Vector3,
+        },
     },
     engine::GraphicsNode,
     theme::Color,
@@ -44,6 +47,9 @@ pub(crate) struct VelocityInterpolation {
     pub repeated_calls: u32,
     pub velocity:       Option<Vector3<f32>>,
     pub positions:      Positions,
+    /// the velocity and acceleration sent by the simulation since the last position, preferred
+    /// over the estimate from `positions`
+    pub reported:       Option<(Vector3<f32>, Vector3<f32>)>,
 }
 
 
@@ -59,12 +65,26 @@ impl VelocityInterpolation {
     ) {
         self.positions.push_back((tick, pos));
         self.velocity = None;
+        self.reported = None;
         self.repeated_calls = 0;
     }
 
+    pub fn report(
+        &mut self,
+        velocity: Vector3<f32>,
+        acceleration: Vector3<f32>,
+    ) {
+        self.reported = Some((velocity, acceleration));
+    }
+
     pub fn velocity(&mut self) -> Vector3<f32> {
         self.repeated_calls += 1;
 
+        if let Some((velocity, acceleration)) = self.reported {
+            // dead reckoning, the velocity keeps changing for every step since the last state
+            return velocity + acceleration * (self.repeated_calls - 1) as f32;
+        }
+
         let samples = self.positions.len();
         if samples < Self::MIN_SAMPLES {
             return Vector3::default();
@@ -98,8 +118,11 @@ impl VelocityInterpolation {
 
 pub struct DynamicEntity {
     pub(super) object:        Object,
+    pub(super) kind:          Kind,
     pub(super) tick:          u64,
     pub(super) tag:           u16,
+    /// the rotation the object was built with, entity rotations are applied on top of it
+    pub(super) base_rotation: UnitQuaternion<f32>,
     #[cfg(feature = "interpolation")]
     pub(super) interpolation: VelocityInterpolation,
 }
@@ -107,13 +130,17 @@ pub struct DynamicEntity {
 impl DynamicEntity {
     pub fn new(
         object: Object,
+        kind: Kind,
         tick: u64,
         tag: u16,
     ) -> Self {
+        let base_rotation = object.scene_node().data().local_rotation();
         DynamicEntity {
             object,
+            kind,
             tick,
             tag,
+            base_rotation,
             #[cfg(feature = "interpolation")]
             interpolation: Default::default(),
         }
@@ -154,6 +181,44 @@ impl DynamicEntity {
 
         self.apply_delta(&Delta::Position { id: 0, position });
     }
+
+    /// Use the simulation's velocity and acceleration to extrapolate the position until the next
+    /// update instead of estimating them from past positions. Must follow `update()`.
+    pub fn set_motion(
+        &mut self,
+        velocity: Vector3<f32>,
+        acceleration: Vector3<f32>,
+    ) {
+        #[cfg(feature = "interpolation")]
+        {
+            self.interpolation.report(velocity, acceleration);
+        }
+        #[cfg(not(feature = "interpolation"))]
+        {
+            let _ = (velocity, acceleration);
+        }
+    }
+
+    pub fn set_rotation(
+        &mut self,
+        rotation: UnitQuaternion<f32>,
+    ) {
+        let rotation = rotation * self.base_rotation;
+        self.apply_delta(&Delta::Rotation { id: 0, rotation });
+    }
+
+    /// `scale` is along the scene axes the entity is rotated from. The node scales its own axes
+    /// before any rotation, which for objects built turned (quads are laid down on the ground)
+    /// are not the same ones, so the scale is turned back by the base rotation first.
+    pub fn set_scale(
+        &mut self,
+        scale: Vector3<f32>,
+    ) {
+        let scale = (self.base_rotation.inverse() * scale).abs();
+        self.object
+            .scene_node_mut()
+            .set_local_scale(scale.x, scale.y, scale.z);
+    }
 }
 
 impl Entity for DynamicEntity {
@@ -171,6 +236,7 @@ impl Entity for DynamicEntity {
                 // self.object.scene_node().data().local_translation().vector;
                 self.object.apply_delta(delta);
             }
+            Delta::Rotation { .. } | Delta::Delete { .. } => self.object.apply_delta(delta),
             _ => {}
         }
     }
@@ -213,6 +279,19 @@ pub enum Kind {
 }
 
 
+impl From<messages::Kind> for Kind {
+    fn from(kind: messages::Kind) -> Self {
+        match kind {
+            messages::Kind::Block => Kind::Block,
+            messages::Kind::Player => Kind::Player,
+            messages::Kind::Plane => Kind::Plane,
+            messages::Kind::Sphere => Kind::Sphere,
+            messages::Kind::Quad => Kind::Quad,
+        }
+    }
+}
+
+
 impl Delta {
     pub fn position(
         id: Id,
@@ -3,6 +3,7 @@ mod border2;
 mod entity;
 mod grid;
 mod object;
+mod placement;
 mod plane;
 mod player;
 pub mod procedural;
@@ -28,12 +29,16 @@ pub use self::{
     },
     grid::Gridlines,
     object::Object,
+    placement::Placement,
     plane::Plane,
     player::Player,
     quad::Quad,
     shell::Shell,
     skybox::Skybox,
     sphere::Sphere,
-    world::World,
+    world::{
+        Tag,
+        World,
+    },
     zones::Zones,
 };
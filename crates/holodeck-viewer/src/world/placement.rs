@@ -0,0 +1,125 @@
+use crate::{
+    deps::{
+        holodeck_core::messages::{
+            Bounds,
+            UpAxis,
+            WorldDescription,
+        },
+        na::{
+            Point3,
+            Quaternion,
+            UnitQuaternion,
+            Vector3,
+        },
+    },
+    geometry::AABB3,
+};
+
+
+/// Maps simulation coordinates into the scene, which is y up with the ground drawn at y = 0,
+/// following the conventions announced in the server's [`WorldDescription`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placement {
+    pub up_axis:       UpAxis,
+    /// added to simulation positions, in simulation coordinates
+    pub origin:        [f32; 3],
+    pub ground_height: f32,
+}
+
+
+impl Placement {
+    /// Map a position in simulation coordinates into the scene.
+    pub fn point(
+        &self,
+        position: [f32; 3],
+    ) -> Point3<f32> {
+        let up = self.up_axis.index();
+        let mut shifted = [0.0f32; 3];
+        for (axis, value) in shifted.iter_mut().enumerate() {
+            *value = position[axis] + self.origin[axis];
+        }
+        // the ground is always at y = 0 in the scene, no matter where the origin puts it
+        shifted[up] = position[up] - self.ground_height;
+
+        Point3::from(self.vector(shifted))
+    }
+
+    /// Map a point in the scene back into simulation coordinates, the inverse of
+    /// [`Placement::point`].
+    pub fn position(
+        &self,
+        point: &Point3<f32>,
+    ) -> [f32; 3] {
+        let up = self.up_axis.index();
+        // swapping the axes back is the same swap
+        let shifted = self.vector([point.x, point.y, point.z]);
+        let mut position = [0.0f32; 3];
+        for (axis, value) in position.iter_mut().enumerate() {
+            *value = shifted[axis] - self.origin[axis];
+        }
+        position[up] = shifted[up] + self.ground_height;
+
+        position
+    }
+
+    /// Map a direction in simulation coordinates (velocity, scale, ..) into the scene.
+    pub fn vector(
+        &self,
+        [x, y, z]: [f32; 3],
+    ) -> Vector3<f32> {
+        match self.up_axis {
+            UpAxis::Y => Vector3::new(x, y, z),
+            UpAxis::Z => Vector3::new(x, z, y),
+        }
+    }
+
+    /// Z up simulations are right handed, swapping the y and z axes to make them y up mirrors
+    /// the space so the rotation's axis flips as well.
+    pub fn rotation(
+        &self,
+        [i, j, k, w]: [f32; 4],
+    ) -> UnitQuaternion<f32> {
+        match self.up_axis {
+            UpAxis::Y => UnitQuaternion::from_quaternion(Quaternion::new(w, i, j, k)),
+            UpAxis::Z => UnitQuaternion::from_quaternion(Quaternion::new(w, -i, -k, -j)),
+        }
+    }
+
+    /// The scene space box covering the bounds.
+    pub fn bounds(
+        &self,
+        bounds: &Bounds,
+    ) -> AABB3<f32> {
+        let a = self.point(bounds.min);
+        let b = self.point(bounds.max);
+
+        AABB3::with_min_max(
+            (a.x.min(b.x), a.x.max(b.x)),
+            (a.y.min(b.y), a.y.max(b.y)),
+            (a.z.min(b.z), a.z.max(b.z)),
+        )
+    }
+}
+
+
+impl Default for Placement {
+    /// z up, centered on the origin
+    fn default() -> Self {
+        Placement {
+            up_axis:       UpAxis::Z,
+            origin:        [0.0; 3],
+            ground_height: 0.0,
+        }
+    }
+}
+
+
+impl From<&WorldDescription> for Placement {
+    fn from(world: &WorldDescription) -> Self {
+        Placement {
+            up_axis:       world.up_axis,
+            origin:        world.origin,
+            ground_height: world.ground_height,
+        }
+    }
+}
@@ -1,9 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{
+    BTreeSet,
+    HashMap,
+    HashSet,
+};
 
 use crate::{
     config::Config,
     deps::{
-        holodeck_core::messages::SimulationState,
+        holodeck_core::messages::{
+            self,
+            Message,
+            SimulationDelta,
+            SimulationState,
+            WorldDescription,
+        },
         kiss3d::{
             light::Light,
             nalgebra::{
@@ -15,6 +25,10 @@ use crate::{
             scene::SceneNode,
             window::Window,
         },
+        log::{
+            debug,
+            warn,
+        },
         na::{
             Unit,
             UnitQuaternion,
@@ -28,12 +42,19 @@ use crate::{
     world::{
         Block,
         Border2,
+        Delta,
         DynamicEntity,
+        Entity,
         Gridlines,
         Id,
+        Kind,
         Object,
+        Placement,
+        Plane,
+        Player,
         Quad,
         Shell,
+        Sphere,
         Zones,
     },
 };
@@ -75,11 +96,50 @@ impl Environment {
     }
 
     fn toggle_zones(&mut self) {
-        let toggle = !self.zones[0].scene_node().is_visible();
+        let toggle = !self.zones_visible();
         self.zones
             .iter_mut()
             .for_each(|z| z.scene_node_mut().set_visible(toggle))
     }
+
+    fn zones_visible(&self) -> bool {
+        self.zones
+            .first()
+            .map(|z| z.scene_node().is_visible())
+            .unwrap_or(false)
+    }
+
+    /// Replace the zones, keeping them shown or hidden like the ones they replace.
+    fn replace_zones(
+        &mut self,
+        mut zones: Vec<Quad>,
+    ) {
+        let visible = self.zones_visible();
+        zones
+            .iter_mut()
+            .for_each(|z| z.scene_node_mut().set_visible(visible));
+
+        for mut zone in std::mem::replace(&mut self.zones, zones) {
+            zone.scene_node_mut().unlink();
+        }
+    }
+
+    /// The border drawn around the world's bounds.
+    fn boundary(bounds: &AABB3<f32>) -> Border2 {
+        let x_len = bounds.width() / 2.0f32;
+        let z_len = bounds.depth() / 2.0f32;
+
+        let mut boundary = Border2::new(
+            None,
+            Vector3::new(bounds.min.x + x_len, 2.0f32, bounds.min.z + z_len),
+            Vector3::x_axis().scale(-x_len),
+            Vector3::z_axis().scale(z_len),
+            Color::holodeck_boundary(),
+        );
+        boundary.set_layers(5);
+        boundary.set_spacing(5.0f32);
+        boundary
+    }
 }
 
 
@@ -87,16 +147,25 @@ pub struct World {
     // pub player: Player,
     pub updated:     bool,
     pub tick:        Tick,
+    /// the tick of the last simulation state applied, deltas must be based on it
+    pub state_tick:  Option<Tick>,
     pub bounds:      AABB3<f32>,
+    /// where simulation coordinates end up in the scene
+    pub placement:   Placement,
     pub position:    Point3<f32>,
     pub direction:   Vector3<f32>,
     pub environment: Environment,
     pub objects:     HashMap<Id, DynamicEntity>,
     pub tag_colors:  HashMap<Tag, Color>,
+    /// every tag an entity was reported with, whether or not it is still around
+    tags:            BTreeSet<Tag>,
 }
 
 
 impl World {
+    /// The size along each axis of an entity which does not specify its scale.
+    const ENTITY_SIZE: f32 = 2.25;
+
     pub fn generate(
         config: &Config,
         window: &mut Window,
@@ -123,32 +192,18 @@ impl World {
         skyshell.scene_node_mut().set_texture(texture::Skybox::load());
 
 
-        let x_len = config.world_bounds.width() / 2.0f32;
-        let z_len = config.world_bounds.depth() / 2.0f32;
-
-        let mut boundary = Border2::new(
-            None,
-            Vector3::new(0.0f32, 2.0f32, 0.0f32),
-            Vector3::x_axis().scale(-x_len),
-            Vector3::z_axis().scale(z_len),
-            Color::holodeck_boundary(),
-        );
-        boundary.set_layers(5);
-        boundary.set_spacing(5.0f32);
-        let mut zones = Zones::generate(&config.world_bounds, window);
-
-        let mut tag_colors = HashMap::new();
-
-        for (i, color) in Color::holodeck_zones().iter().copied().enumerate() {
-            let id = i as u16;
-            tag_colors.insert(id, color);
-        }
+        // placeholders until the server describes the world
+        let boundary = Environment::boundary(&config.world_bounds);
+        let zones = Zones::generate(&config.world_bounds, window);
+        let tag_colors = Self::default_tag_colors();
 
         let mut world = Self {
             // player,
             updated: false,
             tick: 0,
+            state_tick: None,
             bounds: config.world_bounds,
+            placement: Placement::default(),
             //  skybox,
             position: Point3::new(0.0f32, 0.0f32, 0.0f32),
             direction: Vector3::default(),
@@ -168,58 +223,247 @@ impl World {
             },
             objects: Default::default(),
             tag_colors,
+            tags: BTreeSet::new(),
         };
         world.toggle_zones();
 
         world
     }
 
+    fn default_tag_colors() -> HashMap<Tag, Color> {
+        Color::holodeck_zones()
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(i, color)| (i as Tag, color))
+            .collect()
+    }
+
+    /// Every tag an entity was reported with, tags stay after their entities are gone.
+    pub fn tags(&self) -> &BTreeSet<Tag> {
+        &self.tags
+    }
+
     pub fn on_tick(&mut self) {
         self.updated = false;
     }
 
     pub fn process(
         &mut self,
-        state: Box<SimulationState>,
+        message: Message,
         window: &mut Window,
     ) {
-        let tick = state.tick;
+        match message {
+            Message::Welcome(welcome) => self.describe(&welcome.world, window),
+            Message::State(state) => self.process_state(state, window),
+            Message::Delta(delta) => self.process_delta(delta, window),
+            other => debug!("ignoring message: {:?}", other),
+        }
+    }
 
-        let tag_colors = &self.tag_colors;
-        for crate::deps::holodeck_core::messages::Entity { id, x, y, z, tag } in
-            state.entities.iter().copied()
-        {
-            let pos = Point3::new(x - 500.0, 1.2f32, y - 500.0).into();
+    /// Lay the world out as announced by the server, replacing whatever was there before.
+    fn describe(
+        &mut self,
+        world: &WorldDescription,
+        window: &mut Window,
+    ) {
+        debug!("world described by the server: {:?}", world);
+        self.placement = Placement::from(world);
+        self.bounds = self.placement.bounds(&world.bounds);
+        self.state_tick = None;
+
+        // entities are only meaningful in the world they were reported in
+        let ids = self.objects.keys().copied().collect::<Vec<Id>>();
+        for id in ids {
+            self.despawn(id);
+        }
 
-            let entry = self.objects.entry(id).or_insert_with(|| {
-                let color = tag_colors.get(&tag).copied().unwrap_or(Color::white());
-                let object = Object::Block(Block::new_cube(Some(id), pos, color.into(), 2.25f32, window));
+        self.environment.grid.bounds = self.bounds;
+        self.environment.boundary = Environment::boundary(&self.bounds);
+        self.environment
+            .replace_zones(Zones::describe(&world.zones, &self.placement, window));
 
-                DynamicEntity::new(object, tick, tag)
-            });
+        self.tag_colors = Self::default_tag_colors();
+        for tag_color in world.tag_colors.iter() {
+            self.tag_colors
+                .insert(tag_color.tag, Color::from(tag_color.color));
+        }
+    }
 
+    fn process_state(
+        &mut self,
+        state: SimulationState,
+        window: &mut Window,
+    ) {
+        let tick = state.tick;
+        // states over UDP and over the connection overtake each other, an older one would undo
+        // what a newer one did
+        if let Some(last) = self.state_tick.filter(|&last| tick <= last) {
+            debug!(
+                "dropping state for tick {}, the last state applied was {}",
+                tick, last
+            );
+            return;
+        }
 
-            entry.update(tick, pos);
-            if tag != entry.tag {
-                let color = tag_colors.get(&tag).copied().unwrap_or(Color::white());
-                entry.set_color(color);
+        if state.authoritative {
+            let live = state.entities.iter().map(|e| e.id).collect::<HashSet<Id>>();
+            let despawned = self
+                .objects
+                .keys()
+                .filter(|id| !live.contains(id))
+                .copied()
+                .collect::<Vec<Id>>();
+
+            for id in despawned {
+                self.despawn(id);
             }
-            entry.tick = tick;
         }
 
+        for id in state.removed.iter().copied() {
+            self.despawn(id);
+        }
+
+        for entity in state.entities.iter() {
+            self.upsert(tick, entity, window);
+        }
+
+        self.state_tick = Some(tick);
+        self.tick = tick;
+        self.updated = true;
+    }
+
+    fn process_delta(
+        &mut self,
+        delta: SimulationDelta,
+        window: &mut Window,
+    ) {
+        if self.state_tick != Some(delta.base_tick) {
+            warn!(
+                "dropping delta for tick {} based on {}, the last state applied was {:?}",
+                delta.tick, delta.base_tick, self.state_tick
+            );
+            return;
+        }
+
+        let tick = delta.tick;
+
+        for entity in delta.created.iter().chain(delta.moved.iter()) {
+            self.upsert(tick, entity, window);
+        }
+
+        for id in delta.removed.iter().copied() {
+            self.despawn(id);
+        }
+
+        self.state_tick = Some(tick);
         self.tick = tick;
         self.updated = true;
     }
 
+    /// Remove the entity from the world and unlink its node from the scene.
+    fn despawn(
+        &mut self,
+        id: Id,
+    ) {
+        if let Some(mut entity) = self.objects.remove(&id) {
+            entity.apply_delta(&Delta::delete(id));
+        }
+    }
+
+    fn upsert(
+        &mut self,
+        tick: Tick,
+        entity: &messages::Entity,
+        window: &mut Window,
+    ) {
+        let id = entity.id;
+        let tag = entity.tag;
+        self.tags.insert(tag);
+        let kind = Kind::from(entity.kind());
+        let placement = self.placement;
+        let pos = placement.point([entity.x, entity.y, entity.z]);
+
+        // the shape of a node cannot be changed, rebuild the object when the kind changes
+        if self.objects.get(&id).map(|e| e.kind != kind).unwrap_or(false) {
+            self.despawn(id);
+        }
+
+        let tag_colors = &self.tag_colors;
+        let entry = self.objects.entry(id).or_insert_with(|| {
+            let color = tag_colors.get(&tag).copied().unwrap_or(Color::white());
+            let object = Self::build_object(id, kind, pos, color, window);
+
+            DynamicEntity::new(object, kind, tick, tag)
+        });
+
+
+        entry.update(tick, pos);
+        if let Some(velocity) = entity.velocity {
+            let acceleration = entity.acceleration.unwrap_or_default();
+            entry.set_motion(placement.vector(velocity), placement.vector(acceleration));
+        }
+        entry.set_rotation(
+            entity
+                .rotation
+                .map(|rotation| placement.rotation(rotation))
+                .unwrap_or_else(UnitQuaternion::identity),
+        );
+        entry.set_scale(
+            entity
+                .scale
+                .map(|scale| placement.vector(scale))
+                .unwrap_or_else(|| Vector3::repeat(Self::ENTITY_SIZE)),
+        );
+        if tag != entry.tag {
+            let color = tag_colors.get(&tag).copied().unwrap_or(Color::white());
+            entry.set_color(color);
+            entry.tag = tag;
+        }
+        entry.tick = tick;
+    }
+
+    /// Build a unit sized object of the kind, the entity's scale is applied to the node.
+    fn build_object(
+        id: Id,
+        kind: Kind,
+        pos: Point3<f32>,
+        color: Color,
+        window: &mut Window,
+    ) -> Object {
+        match kind {
+            Kind::Block => Object::Block(Block::new_cube(Some(id), pos, color, 1.0, window)),
+            Kind::Player => {
+                let mut capsule = window.add_capsule(0.25, 0.5);
+                capsule.set_local_translation(Translation3 { vector: pos.coords });
+                Object::Player(Player {
+                    id: Some(id),
+                    gfx: capsule,
+                    color,
+                    is_client: false,
+                })
+            }
+            Kind::Plane => Object::Plane(Plane::new_xz(Some(id), color, 1.0, 1.0, window)),
+            Kind::Sphere => {
+                let mut sphere = Sphere::new(0.5, color, pos, window);
+                sphere.id = Some(id);
+                Object::Sphere(sphere)
+            }
+            Kind::Quad => Object::Quad(Quad::square(Some(id), pos, color, 1.0, window)),
+        }
+    }
+
     pub fn draw(
         &mut self,
         window: &mut Window,
     ) {
         self.environment.draw(window);
 
-        if self.updated == false {
+        // entities left out of the last update, the server sends far away ones less often, keep
+        // moving until they are updated again
+        #[cfg(feature = "interpolation")]
+        {
             let tick = self.tick;
-            #[cfg(feature = "interpolation")]
             for entity in self.objects.values_mut().filter(|e| e.tick < tick) {
                 entity.interpolate();
             }
@@ -1,5 +1,6 @@
 use crate::{
     deps::{
+        holodeck_core::messages,
         kiss3d::window::Window,
         na::{
             Point3,
@@ -15,6 +16,7 @@ use crate::{
         Delta,
         Entity,
         Id,
+        Placement,
         Quad,
     },
 };
@@ -56,4 +58,30 @@ impl Zones {
 
         zones
     }
+
+    /// Build the zones announced by the server, zones without a color of their own cycle
+    /// through the palette.
+    pub fn describe(
+        zones: &[messages::Zone],
+        placement: &Placement,
+        window: &mut Window,
+    ) -> Vec<Quad> {
+        let mut colors = Color::holodeck_zones().into_iter().cycle();
+
+        zones
+            .iter()
+            .map(|zone| {
+                let bounds = placement.bounds(&zone.bounds);
+                let center = Point3::new(
+                    bounds.min.x + bounds.width() / 2.0,
+                    0.1,
+                    bounds.min.z + bounds.depth() / 2.0,
+                );
+                let palette = colors.next().unwrap();
+                let color = zone.color.map(Color::from).unwrap_or(palette);
+
+                Quad::new_xz(None, color, center, bounds.width(), bounds.depth(), window)
+            })
+            .collect()
+    }
 }